@@ -1,397 +1,10359 @@
-use std::collections::{HashMap, HashSet};
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::hash::Hash;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
 use std::path::{Component, Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc;
+use std::process::{self, ExitStatus, Stdio};
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(all(feature = "native", unix))]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(all(feature = "native", unix))]
+use std::os::unix::net::{UnixListener, UnixStream};
 
-use notify::event::ModifyKind;
+#[cfg(feature = "native")]
+use notify::event::{CreateKind, ModifyKind, RemoveKind};
+#[cfg(feature = "native")]
 use notify::{EventKind, RecursiveMode, Watcher};
 
+/// Set once shutdown is requested. Prefer [`request_shutdown`], which also
+/// wakes a watcher blocked waiting for events.
 pub static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
+/// Set by SIGUSR1 (unix) to make the poller rescan without waiting out the
+/// rest of `--poll-interval`. The watcher needs no equivalent: it already
+/// reports changes as they happen.
+pub static RESCAN: AtomicBool = AtomicBool::new(false);
+
+/// Set by [`request_dump`] (and SIGUSR1 on unix) to make a `--buffer-all`
+/// run write out everything it has collected.
+pub static DUMP: AtomicBool = AtomicBool::new(false);
+
+/// Asks a `--buffer-all` run to write every change collected since the last
+/// dump, as one batch.
+pub fn request_dump() {
+    DUMP.store(true, Ordering::SeqCst);
+}
+
+/// The [`LogLevel`] in effect, as a `u8`; set from [`Config::log_level`]
+/// when a run starts.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Warn as u8);
+
+fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Whether diagnostics at `level` are printed.
+fn logs(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Writes `message` to stderr if `level` is enabled.
+fn log(level: LogLevel, message: fmt::Arguments<'_>) {
+    if logs(level) {
+        eprintln!("{}", message);
+    }
+}
+
+/// Watcher loops waiting for events; each is sent `WatchMsg::Shutdown`.
+#[cfg(feature = "native")]
+static SHUTDOWN_WAITERS: Mutex<Vec<mpsc::Sender<WatchMsg>>> = Mutex::new(Vec::new());
+
+/// Sets [`SHUTDOWN`] and wakes any running watcher so it exits immediately.
+pub fn request_shutdown() {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+    #[cfg(feature = "native")]
+    if let Ok(mut waiters) = SHUTDOWN_WAITERS.lock() {
+        waiters.retain(|tx| tx.send(WatchMsg::Shutdown).is_ok());
+    }
+}
+
+/// Registers a watcher loop to be woken by [`request_shutdown`].
+#[cfg(feature = "native")]
+fn wake_on_shutdown(tx: mpsc::Sender<WatchMsg>) {
+    if let Ok(mut waiters) = SHUTDOWN_WAITERS.lock() {
+        waiters.push(tx);
+    }
+}
+
+/// The shortest [`Config::shutdown_check`], so a polled flag can't turn the
+/// watch loop into a busy loop.
+const MIN_SHUTDOWN_CHECK: Duration = Duration::from_millis(10);
+
+/// The shutdown flag a run loop watches and sets: the process-wide
+/// [`SHUTDOWN`], or one owned by the caller so several runs in one process
+/// can be stopped independently.
+#[derive(Clone, Debug, Default)]
+pub(crate) enum Shutdown {
+    #[default]
+    Global,
+    Flag(Arc<AtomicBool>),
+}
+
+impl Shutdown {
+    fn requested(&self) -> bool {
+        match self {
+            Shutdown::Global => SHUTDOWN.load(Ordering::Relaxed),
+            Shutdown::Flag(flag) => flag.load(Ordering::Relaxed),
+        }
+    }
+
+    fn request(&self) {
+        match self {
+            Shutdown::Global => request_shutdown(),
+            Shutdown::Flag(flag) => flag.store(true, Ordering::SeqCst),
+        }
+    }
+
+    /// How long a loop blocked on events may go before re-checking the flag.
+    /// [`request_shutdown`] wakes loops on the global one; a caller's flag
+    /// has no such hook, so it is polled every `every` (at least
+    /// [`MIN_SHUTDOWN_CHECK`]).
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    fn check_every(&self, every: Duration) -> Option<Duration> {
+        match self {
+            Shutdown::Global => None,
+            Shutdown::Flag(_) => Some(every.max(MIN_SHUTDOWN_CHECK)),
+        }
+    }
+}
+
+/// Write end of the self-pipe the signal handler wakes the relay thread with.
+#[cfg(unix)]
+static SIGNAL_PIPE: AtomicI32 = AtomicI32::new(-1);
+
 #[cfg(unix)]
 pub fn install_signal_handlers() {
     unsafe extern "C" {
         fn signal(sig: i32, handler: extern "C" fn(i32)) -> usize;
+        fn pipe(fds: *mut i32) -> i32;
+        fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+        fn write(fd: i32, buf: *const u8, count: usize) -> isize;
     }
 
+    // Only async-signal-safe work here; the relay thread does the rest.
     extern "C" fn handle(_: i32) {
         SHUTDOWN.store(true, Ordering::SeqCst);
+        let fd = SIGNAL_PIPE.load(Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe {
+                write(fd, [0u8].as_ptr(), 1);
+            }
+        }
+    }
+
+    let mut fds = [-1i32; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } == 0 {
+        SIGNAL_PIPE.store(fds[1], Ordering::SeqCst);
+        let read_fd = fds[0];
+        thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            loop {
+                match unsafe { read(read_fd, buf.as_mut_ptr(), 1) } {
+                    1 => request_shutdown(),
+                    0 => break,
+                    // Interrupted by the signal itself; try again.
+                    _ => continue,
+                }
+            }
+        });
     }
 
+    extern "C" fn handle_rescan(_: i32) {
+        RESCAN.store(true, Ordering::SeqCst);
+        DUMP.store(true, Ordering::SeqCst);
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: i32 = 10;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: i32 = 30;
+
     unsafe {
         signal(2, handle); // SIGINT
         signal(15, handle); // SIGTERM
+        signal(SIGUSR1, handle_rescan);
     }
 }
 
-#[cfg(not(unix))]
-pub fn install_signal_handlers() {}
+#[cfg(windows)]
+pub fn install_signal_handlers() {
+    unsafe extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+    }
 
-pub struct Config {
-    pub extensions: Vec<String>,
-    pub poll: bool,
-    pub poll_interval: Duration,
-    pub debounce: Duration,
-    pub paths: Vec<PathBuf>,
-}
+    // Ctrl+C, Ctrl+Break and console close all request shutdown. The handler
+    // runs on its own thread, so it can wake the watcher directly.
+    unsafe extern "system" fn handle(_: u32) -> i32 {
+        request_shutdown();
+        1
+    }
 
-pub fn parse_args() -> Result<Config, String> {
-    let args: Vec<String> = env::args().skip(1).collect();
-    parse_args_from(&args)
+    unsafe {
+        SetConsoleCtrlHandler(Some(handle), 1);
+    }
 }
 
-fn parse_args_from(args: &[String]) -> Result<Config, String> {
-    let mut ext_raw = String::from("php");
-    let mut poll = false;
-    let mut poll_interval = Duration::from_millis(500);
-    let mut debounce = Duration::from_millis(300);
-    let mut paths: Vec<PathBuf> = Vec::new();
+#[cfg(not(any(unix, windows)))]
+pub fn install_signal_handlers() {}
 
-    let mut i = 0;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--ext" => {
-                i += 1;
-                if i >= args.len() {
-                    return Err("--ext requires a value".into());
-                }
-                ext_raw = args[i].clone();
-            }
-            "--poll" => {
-                poll = true;
-            }
-            "--poll-interval" => {
-                i += 1;
-                if i >= args.len() {
-                    return Err("--poll-interval requires a value".into());
-                }
-                poll_interval = parse_duration_str(&args[i])?;
+#[derive(Debug)]
+pub enum WatchError {
+    /// A command-line flag or value was malformed.
+    InvalidArg(String),
+    /// No paths were given to watch.
+    NoPaths,
+    NotADirectory(PathBuf),
+    /// An I/O error tied to a specific path.
+    Path(PathBuf, io::Error),
+    /// The event backend could not be created (`path` is `None`) or could
+    /// not watch `path`.
+    #[cfg(feature = "native")]
+    WatchSetupFailed {
+        path: Option<PathBuf>,
+        source: notify::Error,
+    },
+    /// Writing output failed.
+    Io(io::Error),
+    /// `--metrics-addr` could not be listened on.
+    Metrics(SocketAddr, io::Error),
+    /// `--control-socket` could not be listened on.
+    ControlSocket(PathBuf, io::Error),
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchError::InvalidArg(msg) => write!(f, "{}", msg),
+            WatchError::NoPaths => write!(f, "at least one path argument is required"),
+            WatchError::NotADirectory(p) => write!(f, "{} is not a directory", p.display()),
+            WatchError::Path(p, e) => write!(f, "{}: {}", p.display(), e),
+            #[cfg(feature = "native")]
+            WatchError::WatchSetupFailed { path: None, source } => {
+                write!(f, "failed to create watcher: {}", source)
             }
-            "--debounce" => {
-                i += 1;
-                if i >= args.len() {
-                    return Err("--debounce requires a value".into());
+            #[cfg(feature = "native")]
+            WatchError::WatchSetupFailed {
+                path: Some(p),
+                source,
+            } => {
+                write!(f, "failed to watch {}: {}", p.display(), source)?;
+                if is_watch_limit(source) {
+                    write!(
+                        f,
+                        " (the inotify watch limit was reached; raise it with \
+                         `sudo sysctl fs.inotify.max_user_watches=524288` or use --poll)"
+                    )?;
                 }
-                debounce = parse_duration_str(&args[i])?;
-            }
-            arg if arg.starts_with("--") => {
-                return Err(format!("unknown flag: {}", arg));
-            }
-            _ => {
-                paths.push(PathBuf::from(&args[i]));
+                Ok(())
             }
+            WatchError::Io(e) => write!(f, "write failed: {}", e),
+            WatchError::Metrics(addr, e) => write!(f, "failed to serve metrics on {}: {}", addr, e),
+            WatchError::ControlSocket(path, e) => write!(
+                f,
+                "failed to listen on control socket {}: {}",
+                path.display(),
+                e
+            ),
         }
-        i += 1;
     }
+}
 
-    if paths.is_empty() {
-        return Err("at least one path argument is required".into());
+/// Whether a notify error means the OS ran out of watch descriptors. inotify
+/// reports this as `ENOSPC` ("No space left on device").
+#[cfg(feature = "native")]
+fn is_watch_limit(err: &notify::Error) -> bool {
+    match &err.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(e) => cfg!(target_os = "linux") && e.raw_os_error() == Some(28),
+        _ => false,
     }
+}
 
-    for p in &paths {
-        let meta = fs::metadata(p).map_err(|e| format!("{}: {}", p.display(), e))?;
-        if !meta.is_dir() {
-            return Err(format!("{} is not a directory", p.display()));
+impl std::error::Error for WatchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WatchError::Path(_, e) | WatchError::Io(e) => Some(e),
+            #[cfg(feature = "native")]
+            WatchError::WatchSetupFailed { source, .. } => Some(source),
+            _ => None,
         }
     }
+}
 
-    Ok(Config {
-        extensions: parse_extensions(&ext_raw),
-        poll,
-        poll_interval,
-        debounce,
-        paths,
-    })
+impl From<io::Error> for WatchError {
+    fn from(e: io::Error) -> Self {
+        WatchError::Io(e)
+    }
 }
 
-fn parse_duration_str(s: &str) -> Result<Duration, String> {
-    if let Some(ms) = s.strip_suffix("ms") {
-        ms.parse::<u64>()
-            .map(Duration::from_millis)
-            .map_err(|e| format!("invalid duration '{}': {}", s, e))
-    } else if let Some(secs) = s.strip_suffix('s') {
-        secs.parse::<f64>()
-            .map(Duration::from_secs_f64)
-            .map_err(|e| format!("invalid duration '{}': {}", s, e))
+/// Shape of the lines written for each flushed batch.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// One `tag: path` line per change.
+    #[default]
+    Text,
+    /// One `{"event":"batch","paths":[...],"count":N}` object per flush.
+    JsonBatch,
+    /// Bare paths, each terminated by a NUL byte, for `xargs -0`.
+    Print0,
+    /// A `kind,path,timestamp` row per change, quoted per RFC 4180, after a
+    /// header row written at startup.
+    Csv,
+}
+
+impl OutputFormat {
+    /// `text` (or `human`), `json-batch` (or `json`) and `csv`.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" | "human" => Some(OutputFormat::Text),
+            "json-batch" | "json" => Some(OutputFormat::JsonBatch),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// An additional output from a repeated `--output FORMAT:DEST`, written in
+/// its own format alongside the main one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sink {
+    pub format: OutputFormat,
+    /// `None` means stdout.
+    pub output: Option<PathBuf>,
+}
+
+/// Whether text output colors the `{kind}` prefix with ANSI escapes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Color when writing to a terminal. The binary resolves this before
+    /// running; the library treats an unresolved `Auto` as `Never`.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// ANSI color for a tag: green for creations, red for removals, yellow for
+/// everything else.
+fn tag_color(tag: &str) -> &'static str {
+    if tag.ends_with("created") {
+        "\x1b[32m"
+    } else if tag.ends_with("removed") {
+        "\x1b[31m"
     } else {
-        s.parse::<u64>()
-            .map(Duration::from_millis)
-            .map_err(|e| format!("invalid duration '{}': {}", s, e))
+        "\x1b[33m"
     }
 }
 
-pub fn parse_extensions(raw: &str) -> Vec<String> {
-    raw.split(',')
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .map(|s| {
-            if s.starts_with('.') {
-                s.to_string()
-            } else {
-                format!(".{}", s)
-            }
-        })
-        .collect()
+/// A parsed `--template` for text output lines.
+///
+/// Supported placeholders are `{path}` (followed by ` -> target` for a
+/// repointed symlink), `{kind}` (the tag, e.g. `changed`), `{ext}` (the
+/// `--ext` entry the path matched, e.g. `.blade.php`, or else the file's
+/// extension including the dot) and `{time}` (RFC 3339, UTC). `{{` and `}}`
+/// produce literal braces.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<TemplatePart>,
 }
 
-pub fn matches_extension(path: &str, exts: &[String]) -> bool {
-    exts.iter().any(|ext| path.ends_with(ext.as_str()))
+#[derive(Clone, Debug, PartialEq)]
+enum TemplatePart {
+    Literal(String),
+    Path,
+    Kind,
+    Ext,
+    Time,
 }
 
-pub fn is_ignored(path: &Path) -> bool {
-    match path.file_name().and_then(|n| n.to_str()) {
-        Some(name) => name.starts_with('.') || name == "vendor" || name == "node_modules",
-        None => false,
+impl Default for Template {
+    fn default() -> Self {
+        Template {
+            parts: vec![
+                TemplatePart::Kind,
+                TemplatePart::Literal(": ".into()),
+                TemplatePart::Path,
+            ],
+        }
     }
 }
 
-pub fn is_ignored_path(path: &Path) -> bool {
-    for component in path.components() {
-        if let Component::Normal(name) = component {
-            if let Some(s) = name.to_str() {
-                if s.starts_with('.') || s == "vendor" || s == "node_modules" {
-                    return true;
+impl Template {
+    pub fn parse(raw: &str) -> Result<Template, WatchError> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = raw.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                return Err(WatchError::InvalidArg(format!(
+                                    "unclosed placeholder in template '{}'",
+                                    raw
+                                )));
+                            }
+                        }
+                    }
+                    let part = match name.as_str() {
+                        "path" => TemplatePart::Path,
+                        "kind" => TemplatePart::Kind,
+                        "ext" => TemplatePart::Ext,
+                        "time" => TemplatePart::Time,
+                        other => {
+                            return Err(WatchError::InvalidArg(format!(
+                                "unknown placeholder '{{{}}}' in template '{}'",
+                                other, raw
+                            )));
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
                 }
+                '}' => {
+                    return Err(WatchError::InvalidArg(format!(
+                        "unmatched '}}' in template '{}'",
+                        raw
+                    )));
+                }
+                c => literal.push(c),
             }
         }
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(Template { parts })
+    }
+
+    fn render(&self, change: &Change, now: SystemTime, color: bool) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                TemplatePart::Literal(s) => out.push_str(s),
+                TemplatePart::Path => {
+                    out.push_str(&display_path(&change.path));
+                    if let Some(target) = &change.target {
+                        out.push_str(" -> ");
+                        out.push_str(&display_path(target));
+                    }
+                }
+                TemplatePart::Kind if color => {
+                    out.push_str(tag_color(&change.tag));
+                    out.push_str(&change.tag);
+                    out.push_str("\x1b[0m");
+                }
+                TemplatePart::Kind => out.push_str(&change.tag),
+                TemplatePart::Ext => {
+                    if let Some(ext) = &change.ext {
+                        out.push_str(ext);
+                    } else if let Some(ext) = change.path.extension() {
+                        out.push('.');
+                        out.push_str(&display_path(Path::new(ext)));
+                    }
+                }
+                TemplatePart::Time => out.push_str(&format_rfc3339(now)),
+            }
+        }
+        out
     }
-    false
 }
 
-struct Debouncer {
-    tx: Option<mpsc::Sender<String>>,
-    handle: Option<thread::JoinHandle<()>>,
+/// Formats a timestamp as `YYYY-MM-DDTHH:MM:SSZ` (UTC, second precision).
+fn format_rfc3339(t: SystemTime) -> String {
+    let secs = t
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil-from-days (Howard Hinnant), valid for dates after 1970.
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
 }
 
-impl Debouncer {
-    fn new(debounce: Duration, mut writer: Box<dyn Write + Send>) -> Self {
-        let (tx, rx) = mpsc::channel::<String>();
+/// Parses `YYYY-MM-DDTHH:MM:SS`, with an optional fraction and a `Z` or
+/// `+HH:MM`/`-HH:MM` offset. Times before 1970 are rejected.
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    let number = |part: Option<&str>| -> Option<i64> {
+        let part = part?;
+        if part.is_empty() || !part.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        part.parse().ok()
+    };
+    let b = s.as_bytes();
+    if b.len() < 20
+        || b[4] != b'-'
+        || b[7] != b'-'
+        || !matches!(b[10], b'T' | b't')
+        || b[13] != b':'
+        || b[16] != b':'
+    {
+        return None;
+    }
+    let (year, month, day) = (
+        number(s.get(0..4))?,
+        number(s.get(5..7))?,
+        number(s.get(8..10))?,
+    );
+    let (hour, minute, second) = (
+        number(s.get(11..13))?,
+        number(s.get(14..16))?,
+        number(s.get(17..19))?,
+    );
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=month_days).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
 
-        let handle = thread::spawn(move || {
-            let mut pending: HashSet<String> = HashSet::new();
+    let mut rest = s.get(19..)?;
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        let kept = &fraction[..digits.min(9)];
+        nanos = number(Some(kept))? as u32 * 10u32.pow(9 - kept.len() as u32);
+        rest = &fraction[digits..];
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let (hours, minutes) = (number(rest.get(1..3))?, number(rest.get(4..6))?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let secs = hours * 3600 + minutes * 60;
+            if *sign == b'+' { secs } else { -secs }
+        }
+        _ => return None,
+    };
 
-            loop {
-                let msg = if pending.is_empty() {
-                    match rx.recv() {
-                        Ok(path) => Some(path),
-                        Err(_) => break,
-                    }
-                } else {
-                    match rx.recv_timeout(debounce) {
-                        Ok(path) => Some(path),
-                        Err(mpsc::RecvTimeoutError::Timeout) => {
-                            Self::flush(&mut pending, &mut writer);
-                            None
-                        }
-                        Err(mpsc::RecvTimeoutError::Disconnected) => {
-                            Self::flush(&mut pending, &mut writer);
-                            break;
-                        }
-                    }
-                };
+    // Days-from-civil (Howard Hinnant), the inverse of `format_rfc3339`.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset;
+    Some(SystemTime::UNIX_EPOCH + Duration::new(u64::try_from(secs).ok()?, nanos))
+}
 
-                if let Some(path) = msg {
-                    pending.insert(path);
-                }
+/// How the poller decides whether a file changed between scans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PollCompare {
+    /// Compare modification times.
+    #[default]
+    Mtime,
+    /// Compare a hash of the file contents. Every matching file is read in
+    /// full on each poll, so this trades I/O for fewer false positives.
+    Hash,
+}
+
+/// Which notify watcher the event-driven mode uses. `--poll` bypasses both
+/// for the crate's own scanner.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// The platform's native API (inotify, FSEvents, ReadDirectoryChangesW).
+    #[default]
+    Native,
+    /// notify's `PollWatcher`, scanning every `--poll-interval`.
+    Poll,
+}
+
+/// How much goes to stderr, each level including those before it. Stdout
+/// carries only changes, whatever the level.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Failures only: watcher errors and failed batch commands.
+    Error,
+    /// Also anything that may need attention, such as dropped changes.
+    #[default]
+    Warn,
+    /// Also watch setup and readiness.
+    Info,
+    /// Also every raw event, with why each dropped path was dropped.
+    Debug,
+}
+
+/// Which changes share a debounce timer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DebounceMode {
+    /// One timer for everything (apart from `--debounce ext=...` entries).
+    #[default]
+    Global,
+    /// One timer per matched extension, so a busy extension never holds
+    /// back the others.
+    PerExt,
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub extensions: Vec<String>,
+    /// Exact filenames (`Dockerfile`, `Makefile`) reported in addition to
+    /// `extensions`, under the default tag.
+    pub names: Vec<String>,
+    pub rules: Vec<Rule>,
+    /// Extensions removed after `extensions`/`rules` are applied.
+    pub ext_exclude: Vec<String>,
+    /// Match `extensions`, rules and `ext_exclude` against a file's whole
+    /// extension rather than its suffix (see [`matched_extension_strict`]).
+    pub strict_ext: bool,
+    /// Path globs relative to a root (e.g. `src/**`); when non-empty a file
+    /// must also match one of them. Excludes still win.
+    pub only: Vec<String>,
+    /// `.filewatchignore`-style patterns applied under every root, on top of
+    /// the roots' own files.
+    pub ignore: Vec<String>,
+    /// Directories under a root (e.g. `vendor/mypackage`) watched even though
+    /// they are inside an always-ignored `vendor`, `node_modules` or dot
+    /// directory.
+    pub watch_also: Vec<PathBuf>,
+    /// Only report files git tracks in each root's repository.
+    pub tracked_only: bool,
+    /// Canonical paths of the tracked files, listed at startup with
+    /// `git ls-files` when `tracked_only` is set.
+    pub tracked_files: HashSet<PathBuf>,
+    /// Report every non-ignored file not otherwise matched, under the
+    /// default tag.
+    pub match_all: bool,
+    /// Keep `match_all` from reporting [`BINARY_EXTENSIONS`]; listing one in
+    /// `extensions` or a rule still reports it.
+    pub binary_filter: bool,
+    /// Files smaller than this many bytes are not reported yet.
+    pub min_size: u64,
+    /// Files larger than this many bytes are never reported; zero means no
+    /// limit.
+    pub max_size: u64,
+    /// Files modified more recently than this are not reported yet (watcher
+    /// mode).
+    pub min_age: Duration,
+    /// Read each changed file to the end before reporting it, retrying on
+    /// the next cycle while that fails (watcher mode).
+    pub verify_writes: bool,
+    pub poll: bool,
+    pub poll_interval: Duration,
+    pub poll_compare: PollCompare,
+    /// The notify watcher to use when not `poll`ing.
+    pub backend: Backend,
+    /// Switch to polling if the native event backend cannot be set up.
+    pub poll_fallback: bool,
+    /// Times to retry watching a root, with exponential backoff, before
+    /// giving up (watcher mode).
+    pub watch_retries: u32,
+    /// Reuse directory listings between polls while a directory's mtime is
+    /// unchanged.
+    pub poll_fast: bool,
+    /// Warn on stderr when a directory can't be listed, once until it can be
+    /// again (poll mode).
+    pub warn_unreadable: bool,
+    pub debounce: Duration,
+    /// Per-extension debounce windows from `--debounce php=300ms,css=50ms`;
+    /// extensions not listed use `debounce`. The first matching entry wins.
+    pub debounce_per_ext: Vec<(String, Duration)>,
+    pub debounce_mode: DebounceMode,
+    /// Each flush deadline is pushed back by a random offset below this, so
+    /// watchers started together don't flush in step; zero disables.
+    pub debounce_jitter: Duration,
+    /// Events in this long after startup are discarded.
+    pub initial_delay: Duration,
+    pub debounce_leading: bool,
+    /// Also flush everything pending on this fixed schedule, whatever the
+    /// debounce windows are doing; with a zero `debounce`, only on it. Zero
+    /// disables.
+    pub flush_every: Duration,
+    /// Hold each batch until this long after the previous one, collecting
+    /// changes meanwhile; zero disables.
+    pub flush_cooldown: Duration,
+    pub flush_interval: Duration,
+    /// At most this many lines per second, excess queued; zero disables.
+    pub throttle: u32,
+    /// Stop after writing this many changes; zero means no limit.
+    pub max_events: u64,
+    /// Warn on stderr when more than this many events arrive within one
+    /// debounce window (watcher mode); zero disables.
+    pub alert_rate: u64,
+    /// With `alert_rate`, also stop reporting until a window stays under it.
+    pub alert_pause: bool,
+    /// Drop a change whose file is the same size as when it was last sent,
+    /// within one debounce window (watcher mode).
+    pub coalesce_by_size: bool,
+    /// Drop a change whose file has the same contents as when it was last
+    /// seen, such as a bare `touch`. Every matching file is read once at
+    /// startup and every changed file again as it changes.
+    pub content_only: bool,
+    pub summary: bool,
+    /// Collect changes without writing them until [`request_dump`] (or
+    /// SIGUSR1) asks for everything since the last dump.
+    pub buffer_all: bool,
+    /// Changes that may wait for the output writer before new ones are dropped.
+    pub queue_size: usize,
+    /// How long shutdown waits for pending output to drain before giving up
+    /// on it; zero waits indefinitely.
+    pub shutdown_timeout: Duration,
+    /// How often a watcher stopped by the caller's flag (see
+    /// [`run_watcher_with_shutdown`]) checks it; raised to 10ms if lower.
+    /// Signals and [`request_shutdown`] wake the watcher directly instead.
+    pub shutdown_check: Duration,
+    /// Suppress repeat reports of a path within this window; zero disables.
+    pub dedup_window: Duration,
+    /// Report nothing for a file created and then removed within one
+    /// debounce window, such as an editor's swap file.
+    pub coalesce_transient: bool,
+    /// Treat paths differing only in case as the same change when
+    /// debouncing; the first spelling seen is reported.
+    pub ignore_case: bool,
+    /// Write each batch sorted by path; otherwise in no particular order.
+    pub sort: bool,
+    pub follow_symlinks: bool,
+    /// Descend into subdirectories of each root; otherwise only the root's
+    /// own files are watched.
+    pub recursive: bool,
+    /// Accept paths that do not exist yet and wait for them to appear before
+    /// watching.
+    pub wait_for_paths: bool,
+    /// Drop roots inside (or equal to) another root instead of warning that
+    /// their changes may be reported twice.
+    pub dedup_roots: bool,
+    /// Report every matching file once at startup before watching.
+    pub process_existing: bool,
+    /// Seed the poller with a scan at startup so existing files are not
+    /// reported; without it the first poll reports every matching file.
+    pub initial_scan: bool,
+    /// Have the poller's initial scan report existing files modified after
+    /// this time instead of seeding them silently.
+    pub since: Option<SystemTime>,
+    /// Poller state saved across restarts: loaded at startup to report what
+    /// changed while stopped, and rewritten as changes are seen.
+    pub state_file: Option<PathBuf>,
+    pub list: bool,
+    pub watch_dirs: bool,
+    /// Output file; `None` (or `-` on the command line) means stdout.
+    pub output: Option<PathBuf>,
+    /// Further outputs, each also receiving every batch.
+    pub sinks: Vec<Sink>,
+    pub output_append: bool,
+    /// Append every written change to this file as a JSON line, timed from
+    /// startup, for `replay`.
+    pub record: Option<PathBuf>,
+    /// Re-emit the changes in this `--record` file with their original
+    /// timing instead of watching anything.
+    pub replay: Option<PathBuf>,
+    pub format: OutputFormat,
+    /// Line shape for `OutputFormat::Text`.
+    pub template: Template,
+    /// Print paths relative to the watched root they fall under.
+    pub relative: bool,
+    /// Print paths relative to this absolute base whichever root they fall
+    /// under; paths outside it stay absolute.
+    pub relative_to: Option<PathBuf>,
+    /// Resolve every reported path with `fs::canonicalize`, so both backends
+    /// print the same symlink-free shape.
+    pub canonical: bool,
+    /// Show each file's size in bytes, read when its batch is flushed.
+    pub with_size: bool,
+    /// Show a modified file's previous and new mtime (poll mode, comparing
+    /// by mtime).
+    pub with_mtime: bool,
+    /// Name for every output line, to tell several watchers apart in one
+    /// log: a `[label] ` prefix, or a `watcher` field in JSON.
+    pub label: Option<String>,
+    /// Print each batch grouped under its directories.
+    pub group_by_dir: bool,
+    /// Print one `changed-dir` line in place of a batch's files in any
+    /// directory with more than this many of them; zero disables.
+    pub collapse_threshold: usize,
+    /// Report notify's exact `EventKind` (e.g. `Modify(Data(Content))`) in
+    /// place of the tag (watcher mode).
+    pub verbose_events: bool,
+    /// Which stderr diagnostics are printed. [`LogLevel::Debug`] traces
+    /// every raw event, with why each path was dropped (watcher mode).
+    pub log_level: LogLevel,
+    /// Shell command run once per flush with the NUL-separated changed
+    /// paths on stdin.
+    pub batch_command: Option<String>,
+    /// The words after a trailing `--`: a program and its arguments, run
+    /// without a shell in place of `batch_command`.
+    pub batch_argv: Vec<String>,
+    /// Also run `batch_command` once at startup, with nothing on stdin,
+    /// before the first change.
+    pub exec_on_start: bool,
+    /// `(tag, command)` pairs: each command runs once per flush, like
+    /// `batch_command`, with the changed paths that `--rule` tagged `tag`.
+    pub rule_commands: Vec<(String, String)>,
+    /// Give every rule with a command its own debounce timer, so a busy rule
+    /// doesn't hold back the others' commands.
+    pub debounce_group_by_command: bool,
+    /// Hold `batch_command` and `rule_commands` until no batch has arrived
+    /// for this long, running them once for everything gathered; zero runs
+    /// them per flush.
+    pub exec_debounce: Duration,
+    /// Working directory for `batch_command`; `None` inherits ours.
+    pub chdir: Option<PathBuf>,
+    /// Shut down cleanly once the output's reader has gone away (broken
+    /// pipe) instead of writing into the void.
+    pub exit_on_broken_pipe: bool,
+    pub color: ColorChoice,
+    /// Print `heartbeat` to stderr after this long without changes; zero
+    /// disables.
+    pub heartbeat: Duration,
+    /// Write a `ready` line to the output once every root is watched (or,
+    /// polling, once the initial scan is done).
+    pub ready_marker: bool,
+    /// Print event counters to stderr on exit (watcher mode).
+    pub stats: bool,
+    /// Accept JSON-line `watch`/`unwatch` commands on stdin (watcher mode).
+    pub control: bool,
+    /// Listen on this unix socket for `status`, `add PATH`, `remove PATH`,
+    /// `reload` and `shutdown` line commands (watcher mode, unix only). The
+    /// socket file is removed on exit.
+    pub control_socket: Option<PathBuf>,
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Patterns loaded from the roots' `.filewatchignore` files.
+    pub ignore_files: Vec<IgnoreFile>,
+    /// Skip editor save-time scratch files (see [`EDITOR_TEMP_PATTERNS`]).
+    pub smart_editor_filter: bool,
+    /// Watch dot-named files such as `.env.local`; dot-named directories are
+    /// still ignored.
+    pub hidden_files: bool,
+    /// Report metadata-only changes (chmod, touch) in watcher mode.
+    pub watch_metadata: bool,
+    /// Report access events in watcher mode.
+    pub watch_access: bool,
+    /// Report notify's catch-all `Any`/`Other` events in watcher mode, which
+    /// don't say what happened to the path.
+    pub include_ambiguous: bool,
+    /// Report removed files (and, with `watch_dirs`, directories).
+    pub removals: bool,
+    /// Print the resolved configuration to stderr before watching.
+    pub show_config: bool,
+    /// Write watcher errors into the change output instead of stderr.
+    pub errors_to_output: bool,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            extensions: parse_extensions("php"),
+            rules: Vec::new(),
+            ext_exclude: Vec::new(),
+            strict_ext: false,
+            only: Vec::new(),
+            ignore: Vec::new(),
+            watch_also: Vec::new(),
+            tracked_only: false,
+            tracked_files: HashSet::new(),
+            names: Vec::new(),
+            match_all: false,
+            binary_filter: true,
+            min_size: 0,
+            max_size: 0,
+            min_age: Duration::ZERO,
+            verify_writes: false,
+            poll: false,
+            poll_interval: Duration::from_millis(500),
+            poll_compare: PollCompare::Mtime,
+            backend: Backend::Native,
+            poll_fallback: false,
+            watch_retries: 0,
+            poll_fast: false,
+            warn_unreadable: false,
+            debounce: Duration::from_millis(300),
+            debounce_per_ext: Vec::new(),
+            debounce_mode: DebounceMode::Global,
+            debounce_jitter: Duration::ZERO,
+            initial_delay: Duration::ZERO,
+            debounce_leading: false,
+            flush_every: Duration::ZERO,
+            flush_cooldown: Duration::ZERO,
+            flush_interval: Duration::ZERO,
+            throttle: 0,
+            max_events: 0,
+            alert_rate: 0,
+            alert_pause: false,
+            coalesce_by_size: false,
+            content_only: false,
+            summary: false,
+            buffer_all: false,
+            queue_size: 10_000,
+            shutdown_timeout: Duration::from_secs(2),
+            shutdown_check: Duration::from_millis(50),
+            dedup_window: Duration::ZERO,
+            coalesce_transient: false,
+            ignore_case: false,
+            sort: true,
+            follow_symlinks: false,
+            recursive: true,
+            initial_scan: true,
+            since: None,
+            state_file: None,
+            wait_for_paths: false,
+            dedup_roots: false,
+            process_existing: false,
+            list: false,
+            watch_dirs: false,
+            output: None,
+            sinks: Vec::new(),
+            record: None,
+            replay: None,
+            output_append: false,
+            format: OutputFormat::Text,
+            template: Template::default(),
+            relative: false,
+            relative_to: None,
+            canonical: false,
+            with_size: false,
+            with_mtime: false,
+            label: None,
+            group_by_dir: false,
+            collapse_threshold: 0,
+            verbose_events: false,
+            log_level: LogLevel::Warn,
+            batch_command: None,
+            batch_argv: Vec::new(),
+            exec_on_start: false,
+            rule_commands: Vec::new(),
+            debounce_group_by_command: false,
+            exec_debounce: Duration::ZERO,
+            chdir: None,
+            exit_on_broken_pipe: true,
+            color: ColorChoice::Auto,
+            heartbeat: Duration::ZERO,
+            ready_marker: false,
+            stats: false,
+            control: false,
+            control_socket: None,
+            metrics_addr: None,
+            ignore_files: Vec::new(),
+            smart_editor_filter: false,
+            hidden_files: false,
+            watch_metadata: false,
+            watch_access: false,
+            include_ambiguous: false,
+            removals: true,
+            show_config: false,
+            errors_to_output: false,
+            paths: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// The resolved settings as `key: value` lines, for `--show-config`.
+    /// Roots are canonicalized where they exist. The `--batch-command` text
+    /// is not included, since it may carry credentials.
+    pub fn describe(&self) -> String {
+        let list = |items: &[String]| {
+            if items.is_empty() {
+                "-".to_string()
+            } else {
+                items.join(",")
             }
-        });
+        };
+        let mode = if self.replay.is_some() {
+            "replay"
+        } else if self.list {
+            "list"
+        } else if self.poll || cfg!(not(feature = "native")) {
+            "poll"
+        } else {
+            "watcher"
+        };
+        let mut lines = vec![format!("mode: {}", mode)];
+        for root in &self.paths {
+            let shown = fs::canonicalize(root).unwrap_or_else(|_| root.clone());
+            lines.push(format!("path: {}", display_path(&shown)));
+        }
+        if self.rules.is_empty() {
+            lines.push(format!("extensions: {}", list(&self.extensions)));
+        }
+        if !self.names.is_empty() {
+            lines.push(format!("names: {}", list(&self.names)));
+        }
+        for rule in &self.rules {
+            lines.push(format!("rule: {}:{}", list(&rule.extensions), rule.tag));
+        }
+        lines.push(format!("exclude: {}", list(&self.ext_exclude)));
+        if !self.only.is_empty() {
+            lines.push(format!("only: {}", list(&self.only)));
+        }
+        if self.tracked_only {
+            lines.push(format!("tracked-only: {} files", self.tracked_files.len()));
+        }
+        lines.push(format!("all: {}", self.match_all));
+        if self.match_all {
+            lines.push(format!("binary-filter: {}", self.binary_filter));
+        }
+        lines.push(format!("recursive: {}", self.recursive));
+        lines.push(format!("log-level: {:?}", self.log_level));
+        lines.push(format!("follow-symlinks: {}", self.follow_symlinks));
+        let mut debounce = format!("debounce: {:?}", self.debounce);
+        for (ext, window) in &self.debounce_per_ext {
+            debounce.push_str(&format!(", {}={:?}", ext, window));
+        }
+        if self.debounce_mode == DebounceMode::PerExt {
+            debounce.push_str(" (per extension)");
+        }
+        if self.debounce_group_by_command {
+            debounce.push_str(" (per rule command)");
+        }
+        if !self.debounce_jitter.is_zero() {
+            debounce.push_str(&format!(", jitter {:?}", self.debounce_jitter));
+        }
+        if !self.flush_cooldown.is_zero() {
+            debounce.push_str(&format!(", cooldown {:?}", self.flush_cooldown));
+        }
+        lines.push(debounce);
+        if self.poll || self.poll_fallback {
+            lines.push(format!(
+                "poll: interval={:?} compare={:?} fast={}",
+                self.poll_interval, self.poll_compare, self.poll_fast
+            ));
+        }
+        let mut ignore = vec!["vendor".to_string(), "node_modules".into(), ".*".into()];
+        if self.smart_editor_filter {
+            ignore.push("editor-temp".into());
+        }
+        ignore.extend(self.ignore.iter().cloned());
+        lines.push(format!("ignore: {}", ignore.join(",")));
+        for file in &self.ignore_files {
+            lines.push(format!(
+                "ignore-file: {} ({})",
+                display_path(&file.root.join(IGNORE_FILE)),
+                list(&file.patterns)
+            ));
+        }
+        lines.push(format!(
+            "output: {}",
+            self.output.as_deref().map_or("-".to_string(), display_path)
+        ));
+        if let Some(file) = &self.record {
+            lines.push(format!("record: {}", display_path(file)));
+        }
+        if let Some(file) = &self.replay {
+            lines.push(format!("replay: {}", display_path(file)));
+        }
+        lines.push(format!("format: {:?}", self.format));
+        for sink in &self.sinks {
+            lines.push(format!(
+                "sink: {:?} -> {}",
+                sink.format,
+                sink.output.as_deref().map_or("-".to_string(), display_path)
+            ));
+        }
+        if self.batch_command.is_some() || !self.batch_argv.is_empty() {
+            lines.push("batch-command: (set)".into());
+        }
+        if !self.exec_debounce.is_zero() {
+            lines.push(format!("exec-debounce: {:?}", self.exec_debounce));
+        }
+        for (tag, _) in &self.rule_commands {
+            lines.push(format!("rule-command: {} (set)", tag));
+        }
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
 
-        Debouncer {
-            tx: Some(tx),
-            handle: Some(handle),
+    /// Reads `.filewatchignore` from each root that has one.
+    fn load_ignore_files(&mut self) -> Result<(), WatchError> {
+        self.ignore_files.clear();
+        for root in &self.paths {
+            let Ok(abs) = fs::canonicalize(root) else {
+                continue; // not created yet (`--wait-for-paths`)
+            };
+            let file = abs.join(IGNORE_FILE);
+            let contents = match fs::read_to_string(&file) {
+                Ok(c) => c,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(WatchError::Path(file, e)),
+            };
+            self.ignore_files.push(IgnoreFile {
+                root: abs,
+                patterns: parse_ignore_patterns(&contents),
+            });
         }
+        Ok(())
     }
 
-    fn send(&self, path: String) {
-        if let Some(tx) = &self.tx {
-            let _ = tx.send(path);
+    fn validate(&self) -> Result<(), WatchError> {
+        if self.paths.is_empty() && self.replay.is_none() {
+            return Err(WatchError::NoPaths);
+        }
+
+        if self.queue_size == 0 {
+            return Err(WatchError::InvalidArg(
+                "--queue-size must be at least 1".into(),
+            ));
+        }
+
+        for p in &self.paths {
+            let meta = match fs::metadata(p) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && self.wait_for_paths => continue,
+                Err(e) => return Err(WatchError::Path(p.clone(), e)),
+            };
+            if !meta.is_dir() {
+                return Err(WatchError::NotADirectory(p.clone()));
+            }
+        }
+
+        if let Some(dir) = &self.chdir {
+            let meta = fs::metadata(dir).map_err(|e| WatchError::Path(dir.clone(), e))?;
+            if !meta.is_dir() {
+                return Err(WatchError::NotADirectory(dir.clone()));
+            }
+        }
+
+        if let Some(file) = &self.record {
+            open_record(file).map_err(|e| WatchError::Path(file.clone(), e))?;
+        }
+
+        if cfg!(not(unix)) && self.control_socket.is_some() {
+            return Err(WatchError::InvalidArg(
+                "--control-socket is only supported on unix".into(),
+            ));
+        }
+
+        for (tag, _) in &self.rule_commands {
+            if !self.rules.iter().any(|rule| rule.tag == *tag) {
+                return Err(WatchError::InvalidArg(format!(
+                    "--rule-command: no --rule is tagged '{}'",
+                    tag
+                )));
+            }
         }
+        if !self.rule_commands.is_empty() && self.verbose_events {
+            return Err(WatchError::InvalidArg(
+                "--rule-command can't be combined with --verbose-events".into(),
+            ));
+        }
+        if let Some(base) = &self.relative_to
+            && !base.is_absolute()
+        {
+            return Err(WatchError::InvalidArg(format!(
+                "--relative-to must be an absolute path: {}",
+                base.display()
+            )));
+        }
+        if self.since.is_some() && !self.poll {
+            return Err(WatchError::InvalidArg("--since requires --poll".into()));
+        }
+        if self.since.is_some() && !self.initial_scan {
+            return Err(WatchError::InvalidArg(
+                "--since can't be combined with --no-initial-scan".into(),
+            ));
+        }
+        if self.relative && self.relative_to.is_some() {
+            return Err(WatchError::InvalidArg(
+                "--relative can't be combined with --relative-to".into(),
+            ));
+        }
+        if self.batch_command.is_some() && !self.batch_argv.is_empty() {
+            return Err(WatchError::InvalidArg(
+                "--batch-command can't be combined with a command after --".into(),
+            ));
+        }
+        if self.exec_on_start && self.batch_command.is_none() && self.batch_argv.is_empty() {
+            return Err(WatchError::InvalidArg(
+                "--exec-on-start requires --batch-command or a command after --".into(),
+            ));
+        }
+        Ok(())
     }
 
-    fn shutdown(&mut self) {
-        self.tx.take();
-        if let Some(handle) = self.handle.take() {
-            let _ = handle.join();
+    /// Finds roots inside (or the same as) an earlier or enclosing root, whose
+    /// changes both watches would see. With `dedup_roots` they are dropped;
+    /// otherwise each is warned about. Missing roots are left alone.
+    fn check_nested_roots(&mut self) {
+        let canonical: Vec<Option<PathBuf>> = self
+            .paths
+            .iter()
+            .map(|p| fs::canonicalize(p).ok())
+            .collect();
+        let mut keep = vec![true; self.paths.len()];
+        for (i, inner) in canonical.iter().enumerate() {
+            let Some(inner) = inner else { continue };
+            // An identical root counts as nested only in the later copy.
+            let outer = canonical.iter().enumerate().find(|&(j, outer)| {
+                j != i
+                    && keep[j]
+                    && outer
+                        .as_ref()
+                        .is_some_and(|outer| inner.starts_with(outer) && (inner != outer || j < i))
+            });
+            let Some((j, _)) = outer else { continue };
+            if self.dedup_roots {
+                keep[i] = false;
+            } else if self.log_level >= LogLevel::Warn {
+                // Runs while parsing, before the level is in effect.
+                eprintln!(
+                    "warning: {} is inside {}; its changes may be reported twice (--dedup-roots drops it)",
+                    self.paths[i].display(),
+                    self.paths[j].display()
+                );
+            }
+        }
+        let mut keep = keep.into_iter();
+        self.paths.retain(|_| keep.next().unwrap_or(true));
+    }
+
+    /// Lists each root's tracked files with `git ls-files` if `tracked_only`
+    /// is set. A root outside a git repository is an error.
+    fn load_tracked_files(&mut self) -> Result<(), WatchError> {
+        self.tracked_files.clear();
+        if !self.tracked_only {
+            return Ok(());
+        }
+        for root in &self.paths {
+            let Ok(abs) = fs::canonicalize(root) else {
+                continue; // not created yet (`--wait-for-paths`)
+            };
+            let output = process::Command::new("git")
+                .arg("-C")
+                .arg(&abs)
+                .args(["ls-files", "-z"])
+                .stdin(Stdio::null())
+                .output()
+                .map_err(|e| {
+                    WatchError::Path(
+                        root.clone(),
+                        io::Error::new(e.kind(), format!("git: {}", e)),
+                    )
+                })?;
+            if !output.status.success() {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                return Err(WatchError::Path(
+                    root.clone(),
+                    io::Error::other(format!("--tracked-only: {}", stderr.trim())),
+                ));
+            }
+            for name in output.stdout.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+                self.tracked_files.insert(abs.join(path_from_bytes(name)));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A path from the bytes of a name another program printed: as they are on
+/// unix, where a path is any bytes, and decoded as UTF-8 elsewhere.
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        PathBuf::from(OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Fluent construction of a [`Config`] with the same defaults as the CLI.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Extensions to watch, with or without the leading dot.
+    pub fn extensions<I, S>(mut self, exts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.extensions = exts
+            .into_iter()
+            .flat_map(|e| parse_extensions(e.as_ref()))
+            .collect();
+        self
+    }
+
+    /// Exact filenames to watch alongside the extensions.
+    pub fn names<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.names = names
+            .into_iter()
+            .flat_map(|n| parse_names(n.as_ref()))
+            .collect();
+        self
+    }
+
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.config.rules.push(rule);
+        self
+    }
+
+    /// Extensions to drop even if they match `extensions` or a rule.
+    pub fn ext_exclude<I, S>(mut self, exts: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.ext_exclude = exts
+            .into_iter()
+            .flat_map(|e| parse_extensions(e.as_ref()))
+            .collect();
+        self
+    }
+
+    pub fn strict_ext(mut self, strict: bool) -> Self {
+        self.config.strict_ext = strict;
+        self
+    }
+
+    /// Path globs relative to a root that a file must also match.
+    pub fn only<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.only = patterns
+            .into_iter()
+            .flat_map(|p| parse_only_patterns(p.as_ref()))
+            .collect();
+        self
+    }
+
+    pub fn watch_also<I, P>(mut self, dirs: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.config.watch_also = dirs.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn ignore<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.config.ignore = patterns
+            .into_iter()
+            .flat_map(|p| parse_ignore_list(p.as_ref()))
+            .collect();
+        self
+    }
+
+    pub fn tracked_only(mut self, tracked_only: bool) -> Self {
+        self.config.tracked_only = tracked_only;
+        self
+    }
+
+    pub fn match_all(mut self, match_all: bool) -> Self {
+        self.config.match_all = match_all;
+        self
+    }
+
+    pub fn binary_filter(mut self, enabled: bool) -> Self {
+        self.config.binary_filter = enabled;
+        self
+    }
+
+    pub fn smart_editor_filter(mut self, enabled: bool) -> Self {
+        self.config.smart_editor_filter = enabled;
+        self
+    }
+
+    pub fn hidden_files(mut self, enabled: bool) -> Self {
+        self.config.hidden_files = enabled;
+        self
+    }
+
+    pub fn watch_metadata(mut self, watch: bool) -> Self {
+        self.config.watch_metadata = watch;
+        self
+    }
+
+    pub fn watch_access(mut self, watch: bool) -> Self {
+        self.config.watch_access = watch;
+        self
+    }
+
+    pub fn include_ambiguous(mut self, include: bool) -> Self {
+        self.config.include_ambiguous = include;
+        self
+    }
+
+    pub fn removals(mut self, removals: bool) -> Self {
+        self.config.removals = removals;
+        self
+    }
+
+    pub fn show_config(mut self, show: bool) -> Self {
+        self.config.show_config = show;
+        self
+    }
+
+    pub fn errors_to_output(mut self, enabled: bool) -> Self {
+        self.config.errors_to_output = enabled;
+        self
+    }
+
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.config.min_size = bytes;
+        self
+    }
+
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.config.max_size = bytes;
+        self
+    }
+
+    pub fn min_age(mut self, age: Duration) -> Self {
+        self.config.min_age = age;
+        self
+    }
+
+    pub fn verify_writes(mut self, verify: bool) -> Self {
+        self.config.verify_writes = verify;
+        self
+    }
+
+    pub fn poll(mut self, poll: bool) -> Self {
+        self.config.poll = poll;
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.config.poll_interval = interval;
+        self
+    }
+
+    pub fn poll_compare(mut self, compare: PollCompare) -> Self {
+        self.config.poll_compare = compare;
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    pub fn poll_fallback(mut self, fallback: bool) -> Self {
+        self.config.poll_fallback = fallback;
+        self
+    }
+
+    pub fn watch_retries(mut self, retries: u32) -> Self {
+        self.config.watch_retries = retries;
+        self
+    }
+
+    pub fn poll_fast(mut self, fast: bool) -> Self {
+        self.config.poll_fast = fast;
+        self
+    }
+
+    pub fn warn_unreadable(mut self, warn: bool) -> Self {
+        self.config.warn_unreadable = warn;
+        self
+    }
+
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.config.debounce = debounce;
+        self
+    }
+
+    /// Debounce changes to files with extension `ext` over `window` instead
+    /// of the global debounce.
+    pub fn debounce_for(mut self, ext: &str, window: Duration) -> Self {
+        for ext in parse_extensions(ext) {
+            self.config.debounce_per_ext.push((ext, window));
+        }
+        self
+    }
+
+    pub fn debounce_mode(mut self, mode: DebounceMode) -> Self {
+        self.config.debounce_mode = mode;
+        self
+    }
+
+    pub fn debounce_jitter(mut self, jitter: Duration) -> Self {
+        self.config.debounce_jitter = jitter;
+        self
+    }
+
+    pub fn initial_delay(mut self, delay: Duration) -> Self {
+        self.config.initial_delay = delay;
+        self
+    }
+
+    pub fn debounce_leading(mut self, leading: bool) -> Self {
+        self.config.debounce_leading = leading;
+        self
+    }
+
+    pub fn flush_every(mut self, every: Duration) -> Self {
+        self.config.flush_every = every;
+        self
+    }
+
+    pub fn flush_cooldown(mut self, cooldown: Duration) -> Self {
+        self.config.flush_cooldown = cooldown;
+        self
+    }
+
+    pub fn flush_interval(mut self, interval: Duration) -> Self {
+        self.config.flush_interval = interval;
+        self
+    }
+
+    pub fn throttle(mut self, lines_per_sec: u32) -> Self {
+        self.config.throttle = lines_per_sec;
+        self
+    }
+
+    pub fn max_events(mut self, max: u64) -> Self {
+        self.config.max_events = max;
+        self
+    }
+
+    pub fn alert_rate(mut self, events: u64) -> Self {
+        self.config.alert_rate = events;
+        self
+    }
+
+    pub fn alert_pause(mut self, pause: bool) -> Self {
+        self.config.alert_pause = pause;
+        self
+    }
+
+    pub fn coalesce_by_size(mut self, coalesce: bool) -> Self {
+        self.config.coalesce_by_size = coalesce;
+        self
+    }
+
+    pub fn content_only(mut self, content_only: bool) -> Self {
+        self.config.content_only = content_only;
+        self
+    }
+
+    pub fn summary(mut self, summary: bool) -> Self {
+        self.config.summary = summary;
+        self
+    }
+
+    pub fn buffer_all(mut self, buffer_all: bool) -> Self {
+        self.config.buffer_all = buffer_all;
+        self
+    }
+
+    pub fn queue_size(mut self, size: usize) -> Self {
+        self.config.queue_size = size;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn shutdown_check(mut self, every: Duration) -> Self {
+        self.config.shutdown_check = every;
+        self
+    }
+
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.config.dedup_window = window;
+        self
+    }
+
+    pub fn coalesce_transient(mut self, coalesce: bool) -> Self {
+        self.config.coalesce_transient = coalesce;
+        self
+    }
+
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.config.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn sort(mut self, sort: bool) -> Self {
+        self.config.sort = sort;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow: bool) -> Self {
+        self.config.follow_symlinks = follow;
+        self
+    }
+
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.config.recursive = recursive;
+        self
+    }
+
+    pub fn wait_for_paths(mut self, wait: bool) -> Self {
+        self.config.wait_for_paths = wait;
+        self
+    }
+
+    pub fn dedup_roots(mut self, dedup: bool) -> Self {
+        self.config.dedup_roots = dedup;
+        self
+    }
+
+    pub fn process_existing(mut self, process: bool) -> Self {
+        self.config.process_existing = process;
+        self
+    }
+
+    pub fn initial_scan(mut self, scan: bool) -> Self {
+        self.config.initial_scan = scan;
+        self
+    }
+
+    pub fn since(mut self, since: SystemTime) -> Self {
+        self.config.since = Some(since);
+        self
+    }
+
+    pub fn state_file(mut self, file: Option<PathBuf>) -> Self {
+        self.config.state_file = file;
+        self
+    }
+
+    pub fn list(mut self, list: bool) -> Self {
+        self.config.list = list;
+        self
+    }
+
+    pub fn watch_dirs(mut self, watch_dirs: bool) -> Self {
+        self.config.watch_dirs = watch_dirs;
+        self
+    }
+
+    /// Output file; `None` writes to stdout.
+    pub fn output(mut self, output: Option<PathBuf>) -> Self {
+        self.config.output = output;
+        self
+    }
+
+    pub fn sink(mut self, sink: Sink) -> Self {
+        self.config.sinks.push(sink);
+        self
+    }
+
+    pub fn output_append(mut self, append: bool) -> Self {
+        self.config.output_append = append;
+        self
+    }
+
+    pub fn record(mut self, file: Option<PathBuf>) -> Self {
+        self.config.record = file;
+        self
+    }
+
+    pub fn replay(mut self, file: Option<PathBuf>) -> Self {
+        self.config.replay = file;
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    pub fn template(mut self, template: Template) -> Self {
+        self.config.template = template;
+        self
+    }
+
+    pub fn relative(mut self, relative: bool) -> Self {
+        self.config.relative = relative;
+        self
+    }
+
+    pub fn relative_to(mut self, base: impl Into<PathBuf>) -> Self {
+        self.config.relative_to = Some(base.into());
+        self
+    }
+
+    pub fn canonical(mut self, canonical: bool) -> Self {
+        self.config.canonical = canonical;
+        self
+    }
+
+    pub fn with_size(mut self, with_size: bool) -> Self {
+        self.config.with_size = with_size;
+        self
+    }
+
+    pub fn with_mtime(mut self, with_mtime: bool) -> Self {
+        self.config.with_mtime = with_mtime;
+        self
+    }
+
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.config.label = Some(label.into());
+        self
+    }
+
+    pub fn group_by_dir(mut self, group: bool) -> Self {
+        self.config.group_by_dir = group;
+        self
+    }
+
+    pub fn collapse_threshold(mut self, files: usize) -> Self {
+        self.config.collapse_threshold = files;
+        self
+    }
+
+    pub fn verbose_events(mut self, verbose: bool) -> Self {
+        self.config.verbose_events = verbose;
+        self
+    }
+
+    pub fn log_level(mut self, level: LogLevel) -> Self {
+        self.config.log_level = level;
+        self
+    }
+
+    /// Shorthand for `log_level(LogLevel::Debug)`; `false` restores the
+    /// default level.
+    pub fn debug(mut self, debug: bool) -> Self {
+        self.config.log_level = if debug {
+            LogLevel::Debug
+        } else {
+            LogLevel::default()
+        };
+        self
+    }
+
+    pub fn batch_command(mut self, command: Option<String>) -> Self {
+        self.config.batch_command = command;
+        self
+    }
+
+    /// Runs `command` once per flush with the changed paths tagged `tag`.
+    pub fn rule_command(mut self, tag: &str, command: &str) -> Self {
+        self.config
+            .rule_commands
+            .push((tag.to_string(), command.to_string()));
+        self
+    }
+
+    pub fn debounce_group_by_command(mut self, group: bool) -> Self {
+        self.config.debounce_group_by_command = group;
+        self
+    }
+
+    pub fn batch_argv(mut self, argv: Vec<String>) -> Self {
+        self.config.batch_argv = argv;
+        self
+    }
+
+    pub fn exec_on_start(mut self, exec: bool) -> Self {
+        self.config.exec_on_start = exec;
+        self
+    }
+
+    pub fn exec_debounce(mut self, delay: Duration) -> Self {
+        self.config.exec_debounce = delay;
+        self
+    }
+
+    pub fn chdir(mut self, dir: Option<PathBuf>) -> Self {
+        self.config.chdir = dir;
+        self
+    }
+
+    pub fn exit_on_broken_pipe(mut self, exit: bool) -> Self {
+        self.config.exit_on_broken_pipe = exit;
+        self
+    }
+
+    pub fn color(mut self, color: ColorChoice) -> Self {
+        self.config.color = color;
+        self
+    }
+
+    pub fn heartbeat(mut self, interval: Duration) -> Self {
+        self.config.heartbeat = interval;
+        self
+    }
+
+    pub fn ready_marker(mut self, ready_marker: bool) -> Self {
+        self.config.ready_marker = ready_marker;
+        self
+    }
+
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.config.stats = stats;
+        self
+    }
+
+    pub fn control(mut self, control: bool) -> Self {
+        self.config.control = control;
+        self
+    }
+
+    pub fn metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.metrics_addr = Some(addr);
+        self
+    }
+
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.control_socket = Some(path.into());
+        self
+    }
+
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.paths.push(path.into());
+        self
+    }
+
+    pub fn paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.config.paths.extend(paths.into_iter().map(Into::into));
+        self
+    }
+
+    /// Validates that at least one path was given and that every path is an
+    /// existing directory, then handles nested roots (see `dedup_roots`),
+    /// loads each root's `.filewatchignore` and, with `tracked_only`, its
+    /// tracked files.
+    pub fn build(mut self) -> Result<Config, WatchError> {
+        self.config.validate()?;
+        self.config.check_nested_roots();
+        self.config.load_ignore_files()?;
+        self.config.load_tracked_files()?;
+        Ok(self.config)
+    }
+}
+
+/// What the command line asked for.
+#[derive(Debug)]
+pub enum Command {
+    Run(Box<Config>),
+    /// `snapshot [flags] <path>...`: print the matching files' state as JSON.
+    Snapshot(Box<Config>),
+    /// `diff [flags] <path>... <snapshot>`: compare against a saved snapshot.
+    Diff(Box<Config>, PathBuf),
+    /// `--help`/`-h`: print [`USAGE`] and exit.
+    Help,
+    /// `--version`/`-V`: print [`VERSION`] and exit.
+    Version,
+}
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const USAGE: &str = "\
+Usage: filewatcher [flags] <path> [<path>...] [-- <program> [<arg>...]]
+       filewatcher snapshot [flags] <path>... > state.json
+       filewatcher diff [flags] <path>... state.json
+
+Matching:
+  --ext EXTS             Comma-separated extensions or filename globs (repeatable;
+                         default: php; * for all)
+  --name NAMES           Also watch files with these exact names, e.g.
+                         Dockerfile,Makefile (repeatable)
+  --all                  Watch every non-ignored file regardless of extension
+  --no-binary-filter     With --all, also report images, archives and media
+  --ext-exclude EXTS     Extensions to drop; on its own, watch everything else
+  --strict-ext           Match a file's whole extension, so php skips a.blade.php
+  --only GLOBS           Also require a path glob relative to a root, e.g.
+                         src/**,tests/** (repeatable)
+  --watch-also DIR       Watch DIR under a root even inside vendor,
+                         node_modules or a dot directory (repeatable)
+  --ignore PATTERNS      Also ignore these .filewatchignore patterns, e.g.
+                         *.log,build/ (repeatable)
+  --tracked-only         Only report files tracked by git
+  --rule EXTS:TAG        Report EXTS under TAG instead of `changed` (repeatable)
+  --smart-editor-filter  Skip editor temp files (*~, #*#, *.swp, 4913, ...)
+  --hidden-files         Watch dot files such as .env; dot directories stay ignored
+  --watch-metadata       Report metadata-only changes such as chmod
+  --watch-access         Report file access events
+  --include-ambiguous    Report events the backend can't classify
+  --no-removals          Don't report removed files
+  --min-size SIZE        Hold back files smaller than this until they grow
+  --max-size SIZE        Skip files larger than this (e.g. 512K, 1.5M, 2G)
+  --min-age DURATION     Hold back files modified more recently than this
+  --verify-writes        Hold back files until they can be read to the end
+
+Polling:
+  --poll                 Poll instead of using OS-native events
+  --poll-interval DUR    Polling interval (default: 500ms)
+  --poll-compare MODE    mtime or hash (default: mtime)
+  --backend NAME         native or poll: notify's watcher to use without --poll
+  --poll-fast            Skip re-reading directories whose mtime is unchanged
+  --warn-unreadable      Warn about directories that can't be read
+  --poll-fallback        Poll if OS-native events cannot be set up
+  --watch-retries N      Retry a root that fails to watch N times (default: 0)
+  --watch-dirs           Also report dir-created/dir-removed when polling
+  --no-initial-scan      Report every matching file on the first poll
+  --since TIME           Report files modified after TIME at startup, e.g.
+                         2024-01-01T00:00:00Z, or 1h for an hour ago (poll mode)
+  --state-file FILE      Keep poller state in FILE to report changes made
+                         while stopped
+
+Debouncing:
+  --debounce DURATION    Debounce window (default: 300ms); `php=300ms,css=50ms`
+                         sets per-extension windows
+  --debounce-mode MODE   global, or per-ext for one timer per extension
+                         (default: global)
+  --debounce-group-by-command
+                         One timer per --rule-command, so each rule's
+                         command runs on its own schedule
+  --debounce-jitter DUR  Delay each flush by a random amount up to DUR
+  --initial-delay DUR    Discard changes for this long after startup
+  --debounce-leading     Report the first change after a quiet period immediately
+  --flush-every DUR      Also flush pending changes on a fixed schedule
+  --flush-cooldown DUR   Leave at least DUR between batches
+  --flush-interval DUR   Minimum time between output flushes (default: 0)
+  --throttle N           Write at most N lines per second, queueing the rest
+  --max-events N         Exit after N changes have been written
+  --alert-rate N         Warn when over N events arrive in one debounce window
+  --alert-pause          With --alert-rate, stop reporting while over the rate
+  --coalesce-by-size     Drop a change when the file's size is unchanged since
+                         it was last reported within the debounce window
+  --content-only         Drop a change when the file's contents are unchanged,
+                         e.g. a bare touch; reads every matching file once at
+                         startup and every changed file in full, which costs
+                         I/O on large trees
+  --summary              Print a `--- N files changed ---` line after each batch
+  --buffer-all           Hold every change until SIGUSR1, then print them all
+  --queue-size N         Changes that may wait on a slow output (default: 10000)
+  --shutdown-timeout DUR Longest wait for output to drain on exit (default: 2s)
+  --dedup-window DUR     Suppress repeat reports of a path for this long
+  --coalesce-transient   Report nothing for files created and removed within
+                         one debounce window
+  --ignore-case          Treat paths differing only in case as one change
+  --no-sort              Don't sort the paths within each batch
+
+Scanning:
+  --follow-symlinks      Descend into symlinked directories
+  --recursive            Watch subdirectories too (default)
+  --no-recursive         Only watch files directly inside each path
+  --wait-for-paths       Accept missing paths and wait for them to appear
+  --dedup-roots          Drop paths nested inside another given path
+  --process-existing     Report every matching file once at startup
+  --list                 Print the matching files and exit
+  --control              Accept watch/unwatch JSON commands on stdin
+  --control-socket PATH  Accept status/add/remove/reload/shutdown commands on
+                         a unix socket
+  --metrics-addr ADDR    Serve Prometheus metrics on ADDR, e.g. 127.0.0.1:9100
+  --paths-from FILE      Read newline-separated paths from FILE (- for stdin)
+  --config FILE          Read flags from a TOML file; command-line flags win
+
+Output:
+  --output [FMT:]FILE    Write changes to FILE instead of stdout (- for stdout);
+                         repeat to add outputs, each in its own format
+  --output-append        Append to the --output file instead of truncating it
+  --record FILE          Also append each change to FILE as timed JSON lines
+  --replay FILE          Re-emit a --record file with its original timing
+                         instead of watching; no paths needed
+  --format FORMAT        text, json-batch or csv (default: text)
+  --print0               Print bare paths ending in NUL instead of lines
+  --template TEMPLATE    Text line shape (default: {kind}: {path})
+  --relative             Print paths relative to the watched root
+  --relative-to DIR      Print paths relative to DIR, an absolute path
+  --canonical            Resolve symlinks in every printed path
+  --with-size            Add each file's size, e.g. `changed: a.php (1432 bytes)`
+  --with-mtime           Add a modified file's old and new mtime (poll mode)
+  --label NAME           Label every line, e.g. `[backend] changed: app.php`
+  --group-by-dir         Print each batch as directories with indented files
+  --collapse-threshold N Print `changed-dir: DIR/ (M files)` for directories
+                         with over N files in a batch
+  --verbose-events       Print notify's exact event kind instead of the tag
+  --log-level LEVEL      error, warn, info or debug: which diagnostics go to
+                         stderr (default: warn)
+  --debug                Same as --log-level debug: trace raw events and why
+                         paths were dropped
+  --batch-command CMD    Run CMD per batch with NUL-separated paths on stdin
+  --rule-command TAG=CMD Like --batch-command, with only the paths tagged
+                         TAG by --rule (repeatable)
+  --exec-on-start        Also run --batch-command once before watching
+  -- PROGRAM ARGS...     Like --batch-command, run directly without a shell;
+                         everything after -- is taken literally
+  --exec-debounce DUR    Run the batch and rule commands once batches have
+                         stopped arriving for DUR
+  --chdir DIR            Working directory for --batch-command
+  --color WHEN           auto, always or never (default: auto)
+  --heartbeat DURATION   Print `heartbeat` to stderr after this long idle
+  --ready-marker         Print `ready` once watching has started
+  --stats                Print event counters to stderr on exit
+  --errors-to-output     Write watcher errors to the output instead of stderr
+  --no-exit-on-broken-pipe
+                         Keep running after the output's reader goes away
+  --show-config          Print the resolved configuration to stderr at startup
+
+  -h, --help             Print this help and exit
+  -V, --version          Print the version and exit
+";
+
+/// Environment variables that set a flag's default, and the flag.
+const ENV_FLAGS: [(&str, &str); 4] = [
+    ("FILEWATCHER_EXT", "--ext"),
+    ("FILEWATCHER_DEBOUNCE", "--debounce"),
+    ("FILEWATCHER_POLL_INTERVAL", "--poll-interval"),
+    ("FILEWATCHER_IGNORE", "--ignore"),
+];
+
+/// Looks up an environment variable; a closure so tests needn't touch the
+/// process environment.
+type EnvLookup<'a> = &'a dyn Fn(&str) -> Option<String>;
+
+pub fn parse_args() -> Result<Command, WatchError> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    parse_args_with_env(&args, &|var| env::var(var).ok())
+}
+
+fn parse_args_with_env(args: &[String], env: EnvLookup) -> Result<Command, WatchError> {
+    match args.first().map(String::as_str) {
+        Some("snapshot") => Ok(match parse_run_args(&args[1..], env)? {
+            Command::Run(config) => Command::Snapshot(config),
+            other => other,
+        }),
+        Some("diff") => {
+            let (snapshot, rest) = match args[1..].split_last() {
+                Some((last, rest)) if !last.starts_with('-') => (PathBuf::from(last), rest),
+                _ => {
+                    return Err(WatchError::InvalidArg(
+                        "diff requires a snapshot file after the paths".into(),
+                    ));
+                }
+            };
+            Ok(match parse_run_args(rest, env)? {
+                Command::Run(config) => Command::Diff(config, snapshot),
+                other => other,
+            })
+        }
+        _ => parse_run_args(args, env),
+    }
+}
+
+fn parse_run_args(args: &[String], env: EnvLookup) -> Result<Command, WatchError> {
+    // Lowest to highest precedence: the environment, the `--config` file,
+    // then the command line. Each layer leaves out flags a later one passes.
+    // Neither looks past a `--`, so a batch program's own flags don't count.
+    let flags = args.split(|a| a == "--").next().unwrap_or(args);
+    let file = config_file_args(flags)?;
+    let file_args = file.as_ref().map_or(&[][..], |f| f.args.as_slice());
+    let env_args: Vec<String> = ENV_FLAGS
+        .iter()
+        .filter(|(_, flag)| !flags.iter().chain(file_args).any(|a| a == flag))
+        .filter_map(|(var, flag)| {
+            let value = env(var).filter(|v| !v.trim().is_empty())?;
+            Some([flag.to_string(), value])
+        })
+        .flatten()
+        .collect();
+    let from_file = env_args.len()..env_args.len() + file_args.len();
+    let args: Vec<String> = env_args
+        .iter()
+        .chain(file_args)
+        .chain(args)
+        .cloned()
+        .collect();
+    let args = args.as_slice();
+
+    let mut config = Config::default();
+    let mut ext_given = false;
+    let mut print0 = false;
+    // The first --output is the main one; later ones become sinks, in the
+    // main format unless they name their own.
+    let mut output_given = false;
+    let mut sinks: Vec<(Option<OutputFormat>, Option<PathBuf>)> = Vec::new();
+
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--help" | "-h" => return Ok(Command::Help),
+            "--version" | "-V" => return Ok(Command::Version),
+            "--ext" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--ext requires a value".into()));
+                }
+                // The first --ext replaces the default; later ones add to it.
+                if !ext_given {
+                    config.extensions.clear();
+                }
+                if args[i].trim() == "*" {
+                    config.match_all = true;
+                } else {
+                    for ext in parse_extensions(&args[i]) {
+                        if !config.extensions.contains(&ext) {
+                            config.extensions.push(ext);
+                        }
+                    }
+                }
+                ext_given = true;
+            }
+            "--name" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--name requires a value".into()));
+                }
+                config.names.extend(parse_names(&args[i]));
+            }
+            "--all" => {
+                config.match_all = true;
+                ext_given = true;
+            }
+            "--no-binary-filter" => {
+                config.binary_filter = false;
+            }
+            "--ext-exclude" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--ext-exclude requires a value".into(),
+                    ));
+                }
+                config.ext_exclude = parse_extensions(&args[i]);
+            }
+            "--strict-ext" => {
+                config.strict_ext = true;
+            }
+            "--only" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--only requires a value".into()));
+                }
+                config.only.extend(parse_only_patterns(&args[i]));
+            }
+            "--watch-also" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--watch-also requires a value".into(),
+                    ));
+                }
+                config.watch_also.push(PathBuf::from(&args[i]));
+            }
+            "--ignore" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--ignore requires a value".into()));
+                }
+                config.ignore.extend(parse_ignore_list(&args[i]));
+            }
+            "--tracked-only" => {
+                config.tracked_only = true;
+            }
+            "--rule" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--rule requires a value".into()));
+                }
+                config.rules.push(parse_rule(&args[i])?);
+            }
+            "--smart-editor-filter" => {
+                config.smart_editor_filter = true;
+            }
+            "--hidden-files" => {
+                config.hidden_files = true;
+            }
+            "--watch-metadata" => {
+                config.watch_metadata = true;
+            }
+            "--watch-access" => {
+                config.watch_access = true;
+            }
+            "--include-ambiguous" => {
+                config.include_ambiguous = true;
+            }
+            "--no-removals" => {
+                config.removals = false;
+            }
+            "--min-size" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--min-size requires a value".into()));
+                }
+                config.min_size = parse_size_str(&args[i])?;
+            }
+            "--max-size" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--max-size requires a value".into()));
+                }
+                config.max_size = parse_size_str(&args[i])?;
+            }
+            "--min-age" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--min-age requires a value".into()));
+                }
+                config.min_age = parse_duration_str(&args[i])?;
+            }
+            "--verify-writes" => {
+                config.verify_writes = true;
+            }
+            "--poll" => {
+                config.poll = true;
+            }
+            "--poll-interval" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--poll-interval requires a value".into(),
+                    ));
+                }
+                config.poll_interval = parse_duration_str(&args[i])?;
+            }
+            "--backend" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--backend requires a value".into()));
+                }
+                config.backend = match args[i].as_str() {
+                    "native" => Backend::Native,
+                    "poll" => Backend::Poll,
+                    other => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid --backend '{}': expected native or poll",
+                            other
+                        )));
+                    }
+                };
+            }
+            "--poll-compare" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--poll-compare requires a value".into(),
+                    ));
+                }
+                config.poll_compare = match args[i].as_str() {
+                    "mtime" => PollCompare::Mtime,
+                    "hash" => PollCompare::Hash,
+                    other => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid --poll-compare '{}': expected mtime or hash",
+                            other
+                        )));
+                    }
+                };
+            }
+            "--poll-fast" => {
+                config.poll_fast = true;
+            }
+            "--warn-unreadable" => {
+                config.warn_unreadable = true;
+            }
+            "--poll-fallback" => {
+                config.poll_fallback = true;
+            }
+            "--watch-retries" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--watch-retries requires a value".into(),
+                    ));
+                }
+                config.watch_retries = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid watch retries '{}': {}", args[i], e))
+                })?;
+            }
+            "--debounce" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--debounce requires a value".into()));
+                }
+                parse_debounce(&args[i], &mut config)?;
+            }
+            "--debounce-mode" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--debounce-mode requires a value".into(),
+                    ));
+                }
+                config.debounce_mode = match args[i].as_str() {
+                    "global" => DebounceMode::Global,
+                    "per-ext" => DebounceMode::PerExt,
+                    other => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid --debounce-mode '{}': expected global or per-ext",
+                            other
+                        )));
+                    }
+                };
+            }
+            "--debounce-jitter" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--debounce-jitter requires a value".into(),
+                    ));
+                }
+                config.debounce_jitter = parse_duration_str(&args[i])?;
+            }
+            "--initial-delay" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--initial-delay requires a value".into(),
+                    ));
+                }
+                config.initial_delay = parse_duration_str(&args[i])?;
+            }
+            "--debounce-leading" => {
+                config.debounce_leading = true;
+            }
+            "--flush-every" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--flush-every requires a value".into(),
+                    ));
+                }
+                config.flush_every = parse_duration_str(&args[i])?;
+            }
+            "--flush-cooldown" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--flush-cooldown requires a value".into(),
+                    ));
+                }
+                config.flush_cooldown = parse_duration_str(&args[i])?;
+            }
+            "--flush-interval" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--flush-interval requires a value".into(),
+                    ));
+                }
+                config.flush_interval = parse_duration_str(&args[i])?;
+            }
+            "--throttle" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--throttle requires a value".into()));
+                }
+                config.throttle = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid throttle '{}': {}", args[i], e))
+                })?;
+            }
+            "--max-events" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--max-events requires a value".into(),
+                    ));
+                }
+                config.max_events = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid max events '{}': {}", args[i], e))
+                })?;
+            }
+            "--alert-rate" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--alert-rate requires a value".into(),
+                    ));
+                }
+                config.alert_rate = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid alert rate '{}': {}", args[i], e))
+                })?;
+            }
+            "--alert-pause" => {
+                config.alert_pause = true;
+            }
+            "--coalesce-by-size" => {
+                config.coalesce_by_size = true;
+            }
+            "--content-only" => {
+                config.content_only = true;
+            }
+            "--summary" => {
+                config.summary = true;
+            }
+            "--buffer-all" => {
+                config.buffer_all = true;
+            }
+            "--queue-size" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--queue-size requires a value".into(),
+                    ));
+                }
+                config.queue_size = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid queue size '{}': {}", args[i], e))
+                })?;
+            }
+            "--shutdown-timeout" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--shutdown-timeout requires a value".into(),
+                    ));
+                }
+                config.shutdown_timeout = parse_duration_str(&args[i])?;
+            }
+            "--coalesce-transient" => {
+                config.coalesce_transient = true;
+            }
+            "--dedup-window" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--dedup-window requires a value".into(),
+                    ));
+                }
+                config.dedup_window = parse_duration_str(&args[i])?;
+            }
+            "--ignore-case" => {
+                config.ignore_case = true;
+            }
+            "--no-sort" => {
+                config.sort = false;
+            }
+            "--exit-on-broken-pipe" => {
+                config.exit_on_broken_pipe = true;
+            }
+            "--no-exit-on-broken-pipe" => {
+                config.exit_on_broken_pipe = false;
+            }
+            "--follow-symlinks" => {
+                config.follow_symlinks = true;
+            }
+            "--no-initial-scan" => {
+                config.initial_scan = false;
+            }
+            "--since" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--since requires a value".into()));
+                }
+                config.since = Some(parse_since(&args[i])?);
+            }
+            "--state-file" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--state-file requires a value".into(),
+                    ));
+                }
+                config.state_file = Some(PathBuf::from(&args[i]));
+            }
+            "--show-config" => {
+                config.show_config = true;
+            }
+            "--errors-to-output" => {
+                config.errors_to_output = true;
+            }
+            "--recursive" => {
+                config.recursive = true;
+            }
+            "--no-recursive" => {
+                config.recursive = false;
+            }
+            "--wait-for-paths" => {
+                config.wait_for_paths = true;
+            }
+            "--dedup-roots" => {
+                config.dedup_roots = true;
+            }
+            "--process-existing" => {
+                config.process_existing = true;
+            }
+            "--list" => {
+                config.list = true;
+            }
+            "--watch-dirs" => {
+                config.watch_dirs = true;
+            }
+            "--output" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--output requires a value".into()));
+                }
+                let (format, output) = parse_output_spec(&args[i]);
+                if output_given {
+                    sinks.push((format, output));
+                } else {
+                    config.output = output;
+                    config.format = format.unwrap_or(config.format);
+                    output_given = true;
+                }
+            }
+            "--record" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--record requires a value".into()));
+                }
+                config.record = Some(PathBuf::from(&args[i]));
+            }
+            "--replay" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--replay requires a value".into()));
+                }
+                config.replay = Some(PathBuf::from(&args[i]));
+            }
+            "--output-append" => {
+                config.output_append = true;
+            }
+            "--format" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--format requires a value".into()));
+                }
+                config.format = OutputFormat::from_name(&args[i]).ok_or_else(|| {
+                    WatchError::InvalidArg(format!(
+                        "invalid --format '{}': expected text, json-batch or csv",
+                        args[i]
+                    ))
+                })?;
+            }
+            "--template" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--template requires a value".into()));
+                }
+                config.template = Template::parse(&args[i])?;
+            }
+            "--relative" => {
+                config.relative = true;
+            }
+            "--relative-to" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--relative-to requires a value".into(),
+                    ));
+                }
+                config.relative_to = Some(PathBuf::from(&args[i]));
+            }
+            "--canonical" => {
+                config.canonical = true;
+            }
+            "--with-size" => {
+                config.with_size = true;
+            }
+            "--with-mtime" => {
+                config.with_mtime = true;
+            }
+            "--label" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--label requires a value".into()));
+                }
+                config.label = Some(args[i].clone());
+            }
+            "--group-by-dir" => {
+                config.group_by_dir = true;
+            }
+            "--collapse-threshold" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--collapse-threshold requires a value".into(),
+                    ));
+                }
+                config.collapse_threshold = args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!(
+                        "invalid collapse threshold '{}': {}",
+                        args[i], e
+                    ))
+                })?;
+            }
+            "--print0" => {
+                print0 = true;
+            }
+            "--verbose-events" => {
+                config.verbose_events = true;
+            }
+            "--debug" => {
+                config.log_level = LogLevel::Debug;
+            }
+            "--log-level" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--log-level requires a value".into(),
+                    ));
+                }
+                config.log_level = match args[i].as_str() {
+                    "error" => LogLevel::Error,
+                    "warn" => LogLevel::Warn,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    other => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid --log-level '{}': expected error, warn, info or debug",
+                            other
+                        )));
+                    }
+                };
+            }
+            "--batch-command" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--batch-command requires a value".into(),
+                    ));
+                }
+                config.batch_command = Some(args[i].clone());
+            }
+            "--exec-on-start" => {
+                config.exec_on_start = true;
+            }
+            "--rule-command" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--rule-command requires a value".into(),
+                    ));
+                }
+                match args[i].split_once('=') {
+                    Some((tag, command)) if !tag.trim().is_empty() && !command.is_empty() => {
+                        config
+                            .rule_commands
+                            .push((tag.trim().to_string(), command.to_string()));
+                    }
+                    _ => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid rule command '{}': expected TAG=CMD",
+                            args[i]
+                        )));
+                    }
+                }
+            }
+            "--exec-debounce" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--exec-debounce requires a value".into(),
+                    ));
+                }
+                config.exec_debounce = parse_duration_str(&args[i])?;
+            }
+            "--debounce-group-by-command" => {
+                config.debounce_group_by_command = true;
+            }
+            "--chdir" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--chdir requires a value".into()));
+                }
+                config.chdir = Some(PathBuf::from(&args[i]));
+            }
+            "--color" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg("--color requires a value".into()));
+                }
+                config.color = match args[i].as_str() {
+                    "auto" => ColorChoice::Auto,
+                    "always" => ColorChoice::Always,
+                    "never" => ColorChoice::Never,
+                    other => {
+                        return Err(WatchError::InvalidArg(format!(
+                            "invalid --color '{}': expected auto, always or never",
+                            other
+                        )));
+                    }
+                };
+            }
+            "--heartbeat" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--heartbeat requires a value".into(),
+                    ));
+                }
+                config.heartbeat = parse_duration_str(&args[i])?;
+            }
+            "--ready-marker" => {
+                config.ready_marker = true;
+            }
+            "--stats" => {
+                config.stats = true;
+            }
+            "--control" => {
+                config.control = true;
+            }
+            "--control-socket" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--control-socket requires a value".into(),
+                    ));
+                }
+                config.control_socket = Some(PathBuf::from(&args[i]));
+            }
+            "--metrics-addr" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--metrics-addr requires a value".into(),
+                    ));
+                }
+                config.metrics_addr = Some(args[i].parse().map_err(|e| {
+                    WatchError::InvalidArg(format!("invalid metrics address '{}': {}", args[i], e))
+                })?);
+            }
+            "--paths-from" => {
+                i += 1;
+                if i >= args.len() {
+                    return Err(WatchError::InvalidArg(
+                        "--paths-from requires a value".into(),
+                    ));
+                }
+                config.paths.extend(read_paths_from(&args[i])?);
+            }
+            // Already read by `config_file_args`.
+            "--config" => {
+                i += 1;
+            }
+            // Only here, where a flag is expected, does `--` end the flags:
+            // the rest is the batch program and its arguments, verbatim.
+            "--" => {
+                config.batch_argv = args[i + 1..].to_vec();
+                break;
+            }
+            arg if arg.starts_with("--") && from_file.contains(&i) => {
+                return Err(WatchError::InvalidArg(format!(
+                    "{}: unknown key '{}'",
+                    file.as_ref()
+                        .map_or(String::new(), |f| display_path(&f.path)),
+                    &arg[2..]
+                )));
+            }
+            arg if arg.starts_with("--") => {
+                return Err(WatchError::InvalidArg(format!("unknown flag: {}", arg)));
+            }
+            _ => {
+                config.paths.push(PathBuf::from(&args[i]));
+            }
+        }
+        i += 1;
+    }
+    if config.paths.is_empty()
+        && let Some(file) = file
+    {
+        config.paths = file.paths;
+    }
+
+    // Print0 and CSV lines have no room for decoration.
+    let bare = if print0 {
+        Some("--print0")
+    } else if config.format == OutputFormat::Csv {
+        Some("--format csv")
+    } else {
+        None
+    };
+    if let Some(bare) = bare {
+        let clash = if print0 && config.format != OutputFormat::Text {
+            Some("--format")
+        } else if config.template != Template::default() {
+            Some("--template")
+        } else if config.group_by_dir {
+            Some("--group-by-dir")
+        } else if config.summary {
+            Some("--summary")
+        } else if config.label.is_some() {
+            Some("--label")
+        } else if config.collapse_threshold > 0 {
+            Some("--collapse-threshold")
+        } else {
+            None
+        };
+        if let Some(flag) = clash {
+            return Err(WatchError::InvalidArg(format!(
+                "{} can't be combined with {}",
+                bare, flag
+            )));
+        }
+        if print0 {
+            config.format = OutputFormat::Print0;
+        }
+    }
+    // CSV rows have fixed columns, in a sink as much as the main output.
+    let csv = config.format == OutputFormat::Csv
+        || sinks
+            .iter()
+            .any(|(format, _)| *format == Some(OutputFormat::Csv));
+    let extra_column = if config.with_size {
+        Some("--with-size")
+    } else if config.with_mtime {
+        Some("--with-mtime")
+    } else {
+        None
+    };
+    if let Some(flag) = extra_column.filter(|_| csv) {
+        return Err(WatchError::InvalidArg(format!(
+            "--format csv can't be combined with {}",
+            flag
+        )));
+    }
+
+    config.sinks = sinks
+        .into_iter()
+        .map(|(format, output)| Sink {
+            format: format.unwrap_or(config.format),
+            output,
+        })
+        .collect();
+
+    // An exclude list on its own means "everything except".
+    if !ext_given && !config.ext_exclude.is_empty() {
+        config.extensions.clear();
+        config.match_all = true;
+    }
+
+    config.validate()?;
+    config.check_nested_roots();
+    config.load_ignore_files()?;
+    config.load_tracked_files()?;
+    Ok(Command::Run(Box::new(config)))
+}
+
+/// Opens the configured output destination. Files are truncated unless
+/// `output_append` is set.
+pub fn open_output(config: &Config) -> Result<Box<dyn Write + Send>, WatchError> {
+    match &config.output {
+        None => Ok(Box::new(io::BufWriter::new(io::stdout()))),
+        Some(path) => {
+            let file = open_output_file(path, config.output_append)
+                .map_err(|e| WatchError::Path(path.clone(), e))?;
+            Ok(Box::new(io::BufWriter::new(file)))
+        }
+    }
+}
+
+/// Splits an `--output` value into an optional `FORMAT:` prefix and the
+/// destination, where `-` is stdout. A prefix that isn't a format name is
+/// part of the path.
+fn parse_output_spec(raw: &str) -> (Option<OutputFormat>, Option<PathBuf>) {
+    let (format, dest) = match raw.split_once(':') {
+        Some((name, dest)) => match OutputFormat::from_name(name) {
+            Some(format) => (Some(format), dest),
+            None => (None, raw),
+        },
+        None => (None, raw),
+    };
+    let output = (dest != "-").then(|| PathBuf::from(dest));
+    (format, output)
+}
+
+/// Opens `path` for writing, truncating it unless `append`.
+fn open_output_file(path: &Path, append: bool) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+}
+
+fn read_paths_from(source: &str) -> Result<Vec<PathBuf>, WatchError> {
+    let mut contents = String::new();
+    if source == "-" {
+        io::stdin()
+            .read_to_string(&mut contents)
+            .map_err(|e| WatchError::Path(PathBuf::from("stdin"), e))?;
+    } else {
+        contents =
+            fs::read_to_string(source).map_err(|e| WatchError::Path(PathBuf::from(source), e))?;
+    }
+    Ok(parse_path_list(&contents))
+}
+
+pub fn parse_path_list(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// The flags a `--config` file contributes, ahead of the command line's.
+struct ConfigFile {
+    path: PathBuf,
+    args: Vec<String>,
+    /// Used only when the command line names no paths.
+    paths: Vec<PathBuf>,
+}
+
+/// Reads the `--config` file named in `args`, if any. Keys the command line
+/// also passes as flags are left out, so the command line wins even for
+/// repeatable flags.
+fn config_file_args(args: &[String]) -> Result<Option<ConfigFile>, WatchError> {
+    let Some(at) = args.iter().position(|a| a == "--config") else {
+        return Ok(None);
+    };
+    let Some(path) = args.get(at + 1).map(PathBuf::from) else {
+        return Err(WatchError::InvalidArg("--config requires a value".into()));
+    };
+    let contents = fs::read_to_string(&path).map_err(|e| WatchError::Path(path.clone(), e))?;
+    let entries = parse_config_file(&contents)
+        .map_err(|e| WatchError::InvalidArg(format!("{}: {}", display_path(&path), e)))?;
+
+    let mut file = ConfigFile {
+        path,
+        args: Vec::new(),
+        paths: Vec::new(),
+    };
+    for (key, values) in entries {
+        if key == "paths" {
+            file.paths
+                .extend(values.into_iter().flatten().map(PathBuf::from));
+            continue;
+        }
+        let flag = format!("--{}", key);
+        if args.contains(&flag) {
+            continue;
+        }
+        match values {
+            // `key = true` is a bare flag.
+            None => file.args.push(flag),
+            Some(values) => {
+                for value in values {
+                    file.args.push(flag.clone());
+                    file.args.push(value);
+                }
+            }
+        }
+    }
+    Ok(Some(file))
+}
+
+/// A `--config` key and its values; `None` for a bare flag.
+type ConfigEntry = (String, Option<Vec<String>>);
+
+/// Parses the TOML subset `--config` accepts: `key = value` lines where the
+/// key is a long flag name (`_` may stand in for `-`) or `paths`, and the
+/// value is a string, a number, a boolean, or a one-line array of strings
+/// and numbers. `true` gives `None` (a bare flag); `false` entries are left
+/// out. Errors name the line.
+fn parse_config_file(contents: &str) -> Result<Vec<ConfigEntry>, String> {
+    let mut entries = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        if line.starts_with('[') {
+            return Err(err("tables are not supported"));
+        }
+        let (key, raw) = line
+            .split_once('=')
+            .ok_or_else(|| err("expected `key = value`"))?;
+        let key = key.trim().replace('_', "-");
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        {
+            return Err(err(&format!("invalid key '{}'", key)));
+        }
+        if key == "config" {
+            return Err(err("a config file can't name another"));
+        }
+
+        let mut rest = raw.trim();
+        let value = if rest.starts_with('[') {
+            rest = &rest[1..];
+            let mut items = Vec::new();
+            loop {
+                rest = rest.trim_start();
+                if let Some(after) = rest.strip_prefix(']') {
+                    rest = after;
+                    break;
+                }
+                let (item, after) = parse_toml_scalar(rest).map_err(|e| err(&e))?;
+                items.push(item.ok_or_else(|| err("arrays can't hold booleans"))?);
+                rest = after.trim_start();
+                rest = rest.strip_prefix(',').unwrap_or(rest);
+                if rest.is_empty() {
+                    return Err(err("unterminated array"));
+                }
+            }
+            Some(Some(items))
+        } else {
+            let (value, after) = parse_toml_scalar(rest).map_err(|e| err(&e))?;
+            rest = after;
+            match value {
+                Some(v) => Some(Some(vec![v])),
+                None if raw.trim().starts_with("true") => Some(None),
+                None => None,
+            }
+        };
+        let rest = rest.trim();
+        if !rest.is_empty() && !rest.starts_with('#') {
+            return Err(err(&format!("unexpected '{}' after the value", rest)));
+        }
+        if let Some(value) = value {
+            entries.push((key, value));
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads one value from the start of `s`: a string or number as `Some`, or a
+/// boolean as `None` (the caller checks which). Returns the rest of `s`.
+fn parse_toml_scalar(s: &str) -> Result<(Option<String>, &str), String> {
+    if let Some(body) = s.strip_prefix('\'') {
+        let end = body.find('\'').ok_or("unterminated string")?;
+        return Ok((Some(body[..end].to_string()), &body[end + 1..]));
+    }
+    if let Some(body) = s.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = body.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Some(value), &body[i + 1..])),
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(c @ ('"' | '\\')) => value.push(c),
+                    _ => return Err("invalid escape in string".into()),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".into());
+    }
+    for word in ["true", "false"] {
+        if let Some(rest) = s.strip_prefix(word) {
+            return Ok((None, rest));
+        }
+    }
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    if end == 0 {
+        let word = s.split([',', ']', ' ']).next().unwrap_or(s);
+        return Err(format!("invalid value '{}' (quote strings)", word));
+    }
+    Ok((Some(s[..end].to_string()), &s[end..]))
+}
+
+fn parse_duration_str(s: &str) -> Result<Duration, WatchError> {
+    let invalid =
+        |e: &dyn fmt::Display| WatchError::InvalidArg(format!("invalid duration '{}': {}", s, e));
+    // Negative, NaN and overflowing values are errors, not panics.
+    let scaled = |n: &str, secs_per_unit: f64| {
+        let n = n.parse::<f64>().map_err(|e| invalid(&e))?;
+        Duration::try_from_secs_f64(n * secs_per_unit).map_err(|e| invalid(&e))
+    };
+    if let Some(ms) = s.strip_suffix("ms") {
+        ms.parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|e| invalid(&e))
+    } else if let Some(secs) = s.strip_suffix('s') {
+        scaled(secs, 1.0)
+    } else if let Some(mins) = s.strip_suffix('m') {
+        scaled(mins, 60.0)
+    } else if let Some(hours) = s.strip_suffix('h') {
+        scaled(hours, 3600.0)
+    } else {
+        s.parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|e| invalid(&e))
+    }
+}
+
+/// Parses `--since`: an RFC 3339 time, or a duration meaning that long
+/// before now.
+fn parse_since(s: &str) -> Result<SystemTime, WatchError> {
+    if let Some(t) = parse_rfc3339(s) {
+        return Ok(t);
+    }
+    let ago = parse_duration_str(s).map_err(|_| {
+        WatchError::InvalidArg(format!(
+            "invalid --since '{}': expected a time such as 2024-01-01T00:00:00Z or a duration such as 1h",
+            s
+        ))
+    })?;
+    Ok(SystemTime::now()
+        .checked_sub(ago)
+        .unwrap_or(SystemTime::UNIX_EPOCH))
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix (powers of
+/// 1024) that also allows a fraction, e.g. `1.5M`.
+fn parse_size_str(s: &str) -> Result<u64, WatchError> {
+    let invalid =
+        |detail: &str| WatchError::InvalidArg(format!("invalid size '{}': {}", s, detail));
+    let (number, unit) = match s.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+        _ => {
+            return s
+                .parse()
+                .map_err(|e: std::num::ParseIntError| invalid(&e.to_string()));
+        }
+    };
+    let multiplier: u64 = match unit {
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => return Err(invalid("expected a K, M or G suffix")),
+    };
+    let n: f64 = number
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| invalid(&e.to_string()))?;
+    if !n.is_finite() || n < 0.0 {
+        return Err(invalid("must be a non-negative number"));
+    }
+    Ok((n * multiplier as f64) as u64)
+}
+
+/// Applies a `--debounce` value: a plain duration, or comma-separated
+/// `ext=DURATION` entries for per-extension windows. A bare duration among
+/// the entries sets the global window.
+fn parse_debounce(raw: &str, config: &mut Config) -> Result<(), WatchError> {
+    let mut any = false;
+    for entry in raw.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        any = true;
+        match entry.split_once('=') {
+            Some((ext, window)) => {
+                let window = parse_duration_str(window.trim())?;
+                let exts = parse_extensions(ext);
+                if exts.is_empty() {
+                    return Err(WatchError::InvalidArg(format!(
+                        "invalid debounce entry '{}': missing extension",
+                        entry
+                    )));
+                }
+                config
+                    .debounce_per_ext
+                    .extend(exts.into_iter().map(|ext| (ext, window)));
+            }
+            None => config.debounce = parse_duration_str(entry)?,
+        }
+    }
+    if !any {
+        return Err(WatchError::InvalidArg(format!(
+            "invalid duration '{}'",
+            raw
+        )));
+    }
+    Ok(())
+}
+
+/// Splits a comma-separated extension list, adding the leading dot where it
+/// is missing. Entries containing `*` or `?` are kept verbatim as filename
+/// globs (see [`matches_extension`]).
+pub fn parse_extensions(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            if s.starts_with('.') || is_glob(s) {
+                s.to_string()
+            } else {
+                format!(".{}", s)
+            }
+        })
+        .collect()
+}
+
+/// Splits a comma-separated `--name` list of exact filenames.
+pub fn parse_names(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Splits a comma-separated `--only` list, dropping leading `./` and `/` so
+/// patterns match paths relative to a root.
+pub fn parse_only_patterns(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(|s| s.trim().trim_start_matches("./").trim_start_matches('/'))
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Splits a comma-separated `--ignore` list.
+pub fn parse_ignore_list(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Tag printed for changes when no `--rule` is given.
+pub const DEFAULT_TAG: &str = "changed";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rule {
+    pub extensions: Vec<String>,
+    pub tag: String,
+}
+
+/// Parses a `--rule` value of the form `ext[,ext...]:tag`.
+pub fn parse_rule(raw: &str) -> Result<Rule, WatchError> {
+    let invalid = || WatchError::InvalidArg(format!("invalid rule '{}': expected EXTS:TAG", raw));
+    let (exts, tag) = raw.rsplit_once(':').ok_or_else(invalid)?;
+    let extensions = parse_extensions(exts);
+    let tag = tag.trim();
+    if extensions.is_empty() || tag.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Rule {
+        extensions,
+        tag: tag.to_string(),
+    })
+}
+
+/// Returns the first rule whose extensions match `path`.
+pub fn match_rule<'a, P: AsRef<OsStr> + ?Sized>(path: &P, rules: &'a [Rule]) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .find(|rule| matches_extension(path, &rule.extensions))
+}
+
+/// Decides which files are reported, and under which tag.
+#[derive(Clone, Debug, Default)]
+struct Filter {
+    rules: Vec<Rule>,
+    names: Vec<String>,
+    /// `--max-size`; zero for no limit.
+    max_size: u64,
+    match_all: bool,
+    skip_binary: bool,
+    exclude: Vec<String>,
+    strict_ext: bool,
+    ignore_files: Vec<IgnoreFile>,
+    skip_editor_temp: bool,
+    hidden_files: bool,
+    /// `--watch-also` directories joined to each root, both as given and
+    /// canonical, since the poller reports the one and the watcher the other.
+    watch_also: Vec<PathBuf>,
+    /// `--only` path globs, matched relative to `roots`.
+    only: Vec<String>,
+    /// The roots, both as given (made absolute) and canonical, for `only`
+    /// and for checking only the components below a root for ignored names.
+    roots: Vec<PathBuf>,
+    /// `--tracked-only`: the files git tracks.
+    tracked: Option<HashSet<PathBuf>>,
+}
+
+impl Filter {
+    /// Uses the `--rule` entries if any were given, otherwise `--ext` under
+    /// the default tag.
+    fn from_config(config: &Config) -> Self {
+        let rules = if config.rules.is_empty() {
+            vec![Rule {
+                extensions: config.extensions.clone(),
+                tag: DEFAULT_TAG.to_string(),
+            }]
+        } else {
+            config.rules.clone()
+        };
+        Filter {
+            rules,
+            names: config.names.clone(),
+            max_size: config.max_size,
+            match_all: config.match_all,
+            skip_binary: config.binary_filter,
+            exclude: config.ext_exclude.clone(),
+            strict_ext: config.strict_ext,
+            ignore_files: config
+                .ignore_files
+                .iter()
+                .cloned()
+                .chain(
+                    config
+                        .paths
+                        .iter()
+                        .filter(|_| !config.ignore.is_empty())
+                        .filter_map(|p| fs::canonicalize(p).ok())
+                        .map(|root| IgnoreFile {
+                            root,
+                            patterns: config.ignore.clone(),
+                        }),
+                )
+                .collect(),
+            skip_editor_temp: config.smart_editor_filter,
+            hidden_files: config.hidden_files,
+            watch_also: {
+                let mut dirs: Vec<PathBuf> = Vec::new();
+                for root in &config.paths {
+                    let bases = [std::path::absolute(root).ok(), fs::canonicalize(root).ok()];
+                    for base in bases.into_iter().flatten() {
+                        for dir in &config.watch_also {
+                            let dir = base.join(dir);
+                            if !dirs.contains(&dir) {
+                                dirs.push(dir);
+                            }
+                        }
+                    }
+                }
+                dirs
+            },
+            only: config.only.clone(),
+            roots: {
+                let mut roots: Vec<PathBuf> = Vec::new();
+                for root in &config.paths {
+                    let bases = [std::path::absolute(root).ok(), fs::canonicalize(root).ok()];
+                    for base in bases.into_iter().flatten() {
+                        if !roots.contains(&base) {
+                            roots.push(base);
+                        }
+                    }
+                }
+                roots
+            },
+            tracked: config.tracked_only.then(|| config.tracked_files.clone()),
+        }
+    }
+
+    /// The tag to report `path` under, or `None` if it is filtered out.
+    /// Excludes are applied after the include rules.
+    fn tag(&self, path: &Path) -> Option<&str> {
+        self.classify(path).map(|(tag, _)| tag)
+    }
+
+    /// Like [`Filter::tag`], also returning the extension or `--name` entry
+    /// that matched (`None` when only `--all` let the path through).
+    fn classify(&self, path: &Path) -> Option<(&str, Option<&str>)> {
+        if self.matched_extension(path, &self.exclude).is_some()
+            || self.too_large(path)
+            || !self.only_allows(path)
+            || self.tracked.as_ref().is_some_and(|t| !t.contains(path))
+        {
+            return None;
+        }
+        for rule in &self.rules {
+            if let Some(ext) = self.matched_extension(path, &rule.extensions) {
+                return Some((&rule.tag, Some(ext)));
+            }
+        }
+        let name = path.file_name();
+        if let Some(n) = self.names.iter().find(|n| name == Some(OsStr::new(n))) {
+            return Some((DEFAULT_TAG, Some(n)));
+        }
+        let binary = self.skip_binary && is_binary(path);
+        (self.match_all && !binary).then_some((DEFAULT_TAG, None))
+    }
+
+    /// [`matched_extension`], or its `--strict-ext` form.
+    fn matched_extension<'a>(&self, path: &Path, exts: &'a [String]) -> Option<&'a str> {
+        if self.strict_ext {
+            matched_extension_strict(path, exts)
+        } else {
+            matched_extension(path, exts)
+        }
+    }
+
+    /// Whether `path` is over `--max-size`. A file that can't be read, e.g.
+    /// because it was removed, is not.
+    fn too_large(&self, path: &Path) -> bool {
+        self.max_size > 0 && fs::metadata(path).is_ok_and(|m| m.len() > self.max_size)
+    }
+
+    /// Whether `path`, relative to a root, matches an `--only` glob (always
+    /// true without `--only`).
+    fn only_allows(&self, path: &Path) -> bool {
+        if self.only.is_empty() {
+            return true;
+        }
+        self.roots.iter().any(|root| {
+            path.strip_prefix(root).is_ok_and(|rel| {
+                let rel = rel.as_os_str().as_encoded_bytes();
+                self.only.iter().any(|p| glob_match(p.as_bytes(), rel))
+            })
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        self.tag(path).is_some()
+    }
+
+    /// Whether `path` is under an always-ignored directory, or is one; see
+    /// [`is_ignored_path`]. A dot-named file is too, unless `--hidden-files`.
+    /// Whether it is a file can only be guessed from what is on disk now: a
+    /// removed hidden directory passes for one. Only the components below
+    /// the root count, so a root inside `~/.config` is still watched.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    fn always_ignored(&self, path: &Path) -> bool {
+        if self.hidden_files && is_hidden(path) && !path.is_dir() {
+            return path
+                .parent()
+                .is_some_and(|dir| self.ignored_below_root(dir));
+        }
+        self.ignored_below_root(path)
+    }
+
+    /// [`is_ignored_path`] on the part of `path` below its root (the
+    /// closest one when roots nest); inside a `--watch-also` directory, on
+    /// the part below that instead.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    fn ignored_below_root(&self, path: &Path) -> bool {
+        if self.watch_also.iter().any(|dir| path.starts_with(dir)) {
+            return is_ignored_below(path, &self.watch_also);
+        }
+        let below = self
+            .roots
+            .iter()
+            .filter_map(|root| path.strip_prefix(root).ok())
+            .min_by_key(|rel| rel.as_os_str().len())
+            .unwrap_or(path);
+        is_ignored_path(below)
+    }
+
+    /// Whether a root's `.filewatchignore`, `--ignore` or
+    /// `--smart-editor-filter` excludes `path`.
+    fn ignored(&self, path: &Path) -> bool {
+        (self.skip_editor_temp && is_editor_temp(path))
+            || self.ignore_files.iter().any(|f| f.matches(path))
+    }
+}
+
+/// Whether `path` ends with one of `exts`. Glob entries (`*.blade.php`,
+/// `config.*`) are instead matched against the whole filename.
+pub fn matches_extension<P: AsRef<OsStr> + ?Sized>(path: &P, exts: &[String]) -> bool {
+    matched_extension(path, exts).is_some()
+}
+
+/// The first entry of `exts` that `path` matches (see [`matches_extension`]).
+pub fn matched_extension<'a, P: AsRef<OsStr> + ?Sized>(
+    path: &P,
+    exts: &'a [String],
+) -> Option<&'a str> {
+    let path = path.as_ref();
+    let bytes = path.as_encoded_bytes();
+    exts.iter()
+        .find(|ext| {
+            if is_glob(ext) {
+                Path::new(path)
+                    .file_name()
+                    .is_some_and(|name| glob_match(ext.as_bytes(), name.as_encoded_bytes()))
+            } else {
+                bytes.ends_with(ext.as_bytes())
+            }
+        })
+        .map(String::as_str)
+}
+
+/// Like [`matched_extension`], but a plain entry must be the filename's
+/// whole extension: everything from its first dot, not counting leading
+/// ones. So `.php` matches `app.php` but not `home.blade.php`, which only
+/// `.blade.php` matches, and `.env.php` has the extension `.php`.
+pub fn matched_extension_strict<'a, P: AsRef<OsStr> + ?Sized>(
+    path: &P,
+    exts: &'a [String],
+) -> Option<&'a str> {
+    let name = Path::new(path.as_ref()).file_name()?.as_encoded_bytes();
+    let stem = name.iter().position(|&b| b != b'.').unwrap_or(name.len());
+    let ext = name[stem..]
+        .iter()
+        .position(|&b| b == b'.')
+        .map(|dot| &name[stem + dot..]);
+    exts.iter()
+        .find(|e| {
+            if is_glob(e) {
+                glob_match(e.as_bytes(), name)
+            } else {
+                ext == Some(e.as_bytes())
+            }
+        })
+        .map(String::as_str)
+}
+
+fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Matches `name` against a pattern where `*` is any run of bytes and `?` is
+/// any single byte.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name offset it is currently covering.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Parses a flat JSON object whose values are all strings, e.g. a `--control`
+/// command or a snapshot. Returns `None` for anything else.
+fn parse_json_object(s: &str) -> Option<HashMap<String, String>> {
+    parse_json_fields(s, false)
+}
+
+/// [`parse_json_object`], also accepting numbers (kept as their text) if
+/// `numbers` is set, as in a `--record` line.
+fn parse_json_fields(s: &str, numbers: bool) -> Option<HashMap<String, String>> {
+    fn skip_ws(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+    }
+
+    fn string(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut out = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(out),
+                '\\' => match chars.next()? {
+                    '"' => out.push('"'),
+                    '\\' => out.push('\\'),
+                    '/' => out.push('/'),
+                    'n' => out.push('\n'),
+                    'r' => out.push('\r'),
+                    't' => out.push('\t'),
+                    'b' => out.push('\u{8}'),
+                    'f' => out.push('\u{c}'),
+                    'u' => {
+                        let hex: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                        out.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                    }
+                    _ => return None,
+                },
+                c => out.push(c),
+            }
+        }
+    }
+
+    let mut chars = s.chars().peekable();
+    let mut fields = HashMap::new();
+    skip_ws(&mut chars);
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_ws(&mut chars);
+    if chars.peek() == Some(&'}') {
+        chars.next();
+    } else {
+        loop {
+            skip_ws(&mut chars);
+            let key = string(&mut chars)?;
+            skip_ws(&mut chars);
+            if chars.next()? != ':' {
+                return None;
+            }
+            skip_ws(&mut chars);
+            let value = if numbers
+                && chars
+                    .peek()
+                    .is_some_and(|c| c.is_ascii_digit() || *c == '-')
+            {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || "+-.eE".contains(*c)) {
+                    number.push(c);
+                }
+                number.parse::<f64>().ok()?;
+                number
+            } else {
+                string(&mut chars)?
+            };
+            fields.insert(key, value);
+            skip_ws(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+    skip_ws(&mut chars);
+    chars.next().is_none().then_some(fields)
+}
+
+/// The first line of `--format csv` output.
+const CSV_HEADER: &str = "kind,path,timestamp";
+
+/// One CSV row. Fields holding a comma, quote or line break are quoted, with
+/// quotes doubled (RFC 4180).
+fn csv_row(fields: &[&str]) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect();
+    fields.join(",")
+}
+
+/// Quotes `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Renders a path for output. Bytes that are not valid UTF-8 are written as
+/// `\xNN` escapes instead of being replaced with `\u{FFFD}`.
+pub fn display_path(path: &Path) -> String {
+    if let Some(s) = path.to_str() {
+        return s.to_string();
+    }
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        out.push_str(chunk.valid());
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+/// Per-root ignore file, read once at startup.
+pub const IGNORE_FILE: &str = ".filewatchignore";
+
+/// The patterns from one root's [`IGNORE_FILE`].
+///
+/// A pattern without a `/` is matched against every path component below the
+/// root (`*.log`, `build`); one with a `/` is matched against the path
+/// relative to the root and its ancestors (`storage/framework/*`). `*` and
+/// `?` are wildcards.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IgnoreFile {
+    /// Canonical root the patterns are relative to.
+    pub root: PathBuf,
+    pub patterns: Vec<String>,
+}
+
+impl IgnoreFile {
+    pub fn matches(&self, path: &Path) -> bool {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        self.patterns.iter().any(|pattern| {
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.contains('/') {
+                let pattern = pattern.trim_start_matches('/').as_bytes();
+                rel.ancestors()
+                    .any(|a| glob_match(pattern, a.as_os_str().as_encoded_bytes()))
+            } else {
+                rel.components().any(|c| match c {
+                    Component::Normal(name) => {
+                        glob_match(pattern.as_bytes(), name.as_encoded_bytes())
+                    }
+                    _ => false,
+                })
+            }
+        })
+    }
+}
+
+/// Parses ignore-file contents: one pattern per line, skipping blank lines
+/// and `#` comments.
+pub fn parse_ignore_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Filename globs for the scratch files editors write while saving, skipped
+/// with `--smart-editor-filter`.
+pub const EDITOR_TEMP_PATTERNS: &[&str] = &[
+    "*~",         // backup copies (Emacs, gedit, Kate)
+    "#*#",        // Emacs auto-save
+    ".#*",        // Emacs lock files
+    "*.swp",      // Vim swap files
+    "*.swx",      // Vim swap files
+    "*.tmp",      // generic atomic-save temporaries
+    "*.kate-swp", // Kate swap files
+];
+
+/// Whether `path` is an editor's save-time scratch file: a name matching
+/// [`EDITOR_TEMP_PATTERNS`], or an all-digit name like the `4913` Vim uses to
+/// probe directory permissions.
+pub fn is_editor_temp(path: &Path) -> bool {
+    let Some(name) = path.file_name() else {
+        return false;
+    };
+    let name = name.as_encoded_bytes();
+    name.iter().all(u8::is_ascii_digit)
+        || EDITOR_TEMP_PATTERNS
+            .iter()
+            .any(|p| glob_match(p.as_bytes(), name))
+}
+
+/// Extensions of files that are probably not source (images, archives,
+/// media, dumps), which `--all` skips unless `--no-binary-filter` is given.
+pub const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "ico", "bmp", "tiff", "psd", "zip", "tar", "gz", "tgz",
+    "bz2", "xz", "7z", "rar", "jar", "phar", "mp3", "mp4", "m4a", "wav", "ogg", "webm", "mov",
+    "avi", "mkv", "woff", "woff2", "ttf", "otf", "eot", "pdf", "sql", "sqlite", "db", "iso", "dmg",
+    "exe", "dll", "so", "dylib", "bin",
+];
+
+/// Whether `path`'s extension is in [`BINARY_EXTENSIONS`], ignoring case.
+pub fn is_binary(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        BINARY_EXTENSIONS
+            .iter()
+            .any(|b| b.eq_ignore_ascii_case(ext))
+    })
+}
+
+fn is_ignored_name(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    name.starts_with(b".") || name == b"vendor" || name == b"node_modules"
+}
+
+/// Whether `path`'s name starts with a dot.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+}
+
+pub fn is_ignored(path: &Path) -> bool {
+    path.file_name().is_some_and(is_ignored_name)
+}
+
+/// Whether any component of `path` is always ignored (see [`is_ignored`]).
+pub fn is_ignored_path(path: &Path) -> bool {
+    for component in path.components() {
+        if let Component::Normal(name) = component
+            && is_ignored_name(name)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// [`is_ignored_path`], except that inside one of the `--watch-also`
+/// directories only the components below it count.
+fn is_ignored_below(path: &Path, watch_also: &[PathBuf]) -> bool {
+    let path = watch_also
+        .iter()
+        .find_map(|dir| path.strip_prefix(dir).ok())
+        .unwrap_or(path);
+    is_ignored_path(path)
+}
+
+struct Output {
+    writer: Box<dyn Write + Send>,
+    flush_interval: Duration,
+    last_flush: Instant,
+    dirty: bool,
+    throttle: Option<Throttle>,
+    /// Requested on the first broken-pipe error, then cleared.
+    exit_on_broken_pipe: Option<Shutdown>,
+    /// Ends each line: `\n`, or NUL for [`OutputFormat::Print0`].
+    terminator: char,
+}
+
+/// How long shutdown keeps trickling out a `--throttle` backlog before
+/// writing the rest at once. A shorter `--shutdown-timeout` caps it at half
+/// the timeout, leaving the other half for that final write.
+const THROTTLE_DRAIN_LIMIT: Duration = Duration::from_secs(5);
+
+/// Token bucket for `--throttle`: up to `rate` lines per second, with lines
+/// beyond that held in `backlog`.
+struct Throttle {
+    rate: u32,
+    tokens: f64,
+    last_refill: Instant,
+    backlog: VecDeque<String>,
+}
+
+impl Throttle {
+    fn new(rate: u32) -> Self {
+        Throttle {
+            rate,
+            tokens: f64::from(rate),
+            last_refill: Instant::now(),
+            backlog: VecDeque::new(),
+        }
+    }
+
+    fn take(&mut self) -> bool {
+        let rate = f64::from(self.rate);
+        let refill = self.last_refill.elapsed().as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(rate);
+        self.last_refill = Instant::now();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Time until the next backlogged line may be written.
+    fn due_in(&self) -> Option<Duration> {
+        if self.backlog.is_empty() {
+            return None;
+        }
+        let missing = (1.0 - self.tokens).max(0.0) / f64::from(self.rate);
+        Some(Duration::from_secs_f64(missing).saturating_sub(self.last_refill.elapsed()))
+    }
+}
+
+impl Output {
+    fn new(writer: Box<dyn Write + Send>, flush_interval: Duration, throttle: u32) -> Self {
+        Output {
+            writer,
+            flush_interval,
+            last_flush: Instant::now(),
+            dirty: false,
+            throttle: (throttle > 0).then(|| Throttle::new(throttle)),
+            exit_on_broken_pipe: None,
+            terminator: '\n',
+        }
+    }
+
+    /// Failed writes are otherwise ignored, but a broken pipe means nobody
+    /// is reading any more, so with `--exit-on-broken-pipe` we stop.
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result
+            && e.kind() == io::ErrorKind::BrokenPipe
+            && let Some(shutdown) = self.exit_on_broken_pipe.take()
+        {
+            shutdown.request();
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        match &mut self.throttle {
+            Some(t) => {
+                t.backlog.push_back(line.to_string());
+                self.release();
+            }
+            None => self.write(line),
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        let result = write!(self.writer, "{}{}", line, self.terminator);
+        self.check(result);
+        self.dirty = true;
+    }
+
+    /// Writes as much of the throttled backlog as the rate allows.
+    fn release(&mut self) {
+        while let Some(t) = &mut self.throttle
+            && !t.backlog.is_empty()
+            && t.take()
+            && let Some(line) = t.backlog.pop_front()
+        {
+            self.write(&line);
+        }
+    }
+
+    /// Time left until buffered output must be flushed or more throttled
+    /// lines may be written, or `None` if there is nothing to do.
+    fn flush_due_in(&self) -> Option<Duration> {
+        let flush = self.dirty.then(|| {
+            self.flush_interval
+                .saturating_sub(self.last_flush.elapsed())
+        });
+        let release = self.throttle.as_ref().and_then(Throttle::due_in);
+        [flush, release].into_iter().flatten().min()
+    }
+
+    fn flush_if_due(&mut self) {
+        self.release();
+        if self.dirty && self.last_flush.elapsed() >= self.flush_interval {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.dirty {
+            let result = self.writer.flush();
+            self.check(result);
+            self.dirty = false;
+        }
+        self.last_flush = Instant::now();
+    }
+
+    /// Writes out any throttled backlog, still at the throttled rate for up to
+    /// `limit`, then flushes.
+    fn finish(&mut self, limit: Duration) {
+        let deadline = Instant::now() + limit;
+        while let Some(wait) = self.throttle.as_ref().and_then(Throttle::due_in) {
+            if Instant::now() + wait > deadline {
+                break;
+            }
+            self.flush();
+            thread::sleep(wait);
+            self.release();
+        }
+        if let Some(t) = &mut self.throttle {
+            let rest: Vec<String> = t.backlog.drain(..).collect();
+            for line in rest {
+                self.write(&line);
+            }
+        }
+        self.flush();
+    }
+}
+
+#[derive(Clone)]
+struct DebounceOptions {
+    debounce: Duration,
+    /// `--debounce ext=DURATION` windows; each entry debounces as its own
+    /// group with its own timer.
+    per_ext: Vec<(String, Duration)>,
+    mode: DebounceMode,
+    jitter: Duration,
+    leading: bool,
+    flush_every: Duration,
+    flush_cooldown: Duration,
+    flush_interval: Duration,
+    throttle: u32,
+    max_events: u64,
+    summary: bool,
+    buffer_all: bool,
+    dedup_window: Duration,
+    coalesce_transient: bool,
+    ignore_case: bool,
+    sort: bool,
+    format: OutputFormat,
+    template: Template,
+    /// Canonical roots to print paths relative to, longest first, or just
+    /// the `--relative-to` base; empty for absolute paths.
+    roots: Vec<PathBuf>,
+    canonical: bool,
+    with_size: bool,
+    with_mtime: bool,
+    label: Option<String>,
+    group_by_dir: bool,
+    collapse_threshold: usize,
+    batch_command: Option<BatchCommand>,
+    rule_commands: Vec<(String, String)>,
+    group_by_command: bool,
+    exec_debounce: Duration,
+    chdir: Option<PathBuf>,
+    record: Option<PathBuf>,
+    sinks: Vec<Sink>,
+    /// The main output file; `None` for stdout.
+    output: Option<PathBuf>,
+    output_append: bool,
+    exit_on_broken_pipe: bool,
+    shutdown: Shutdown,
+    metrics: Arc<Metrics>,
+    color: bool,
+    queue_size: usize,
+    shutdown_timeout: Duration,
+}
+
+impl Default for DebounceOptions {
+    fn default() -> Self {
+        DebounceOptions::from_config(&Config::default())
+    }
+}
+
+impl DebounceOptions {
+    fn from_config(config: &Config) -> Self {
+        DebounceOptions {
+            debounce: config.debounce,
+            per_ext: config.debounce_per_ext.clone(),
+            mode: config.debounce_mode,
+            jitter: config.debounce_jitter,
+            leading: config.debounce_leading,
+            flush_every: config.flush_every,
+            flush_cooldown: config.flush_cooldown,
+            flush_interval: config.flush_interval,
+            throttle: config.throttle,
+            max_events: config.max_events,
+            summary: config.summary,
+            buffer_all: config.buffer_all,
+            dedup_window: config.dedup_window,
+            coalesce_transient: config.coalesce_transient,
+            ignore_case: config.ignore_case,
+            sort: config.sort,
+            format: config.format,
+            template: config.template.clone(),
+            roots: if let Some(base) = &config.relative_to {
+                vec![fs::canonicalize(base).unwrap_or_else(|_| base.clone())]
+            } else if config.relative {
+                let mut roots: Vec<PathBuf> = config
+                    .paths
+                    .iter()
+                    .filter_map(|p| fs::canonicalize(p).ok())
+                    .collect();
+                roots.sort_by_key(|r| std::cmp::Reverse(r.as_os_str().len()));
+                roots
+            } else {
+                Vec::new()
+            },
+            canonical: config.canonical,
+            with_size: config.with_size,
+            with_mtime: config.with_mtime,
+            label: config.label.clone(),
+            group_by_dir: config.group_by_dir,
+            collapse_threshold: config.collapse_threshold,
+            batch_command: BatchCommand::from_config(config),
+            rule_commands: config.rule_commands.clone(),
+            group_by_command: config.debounce_group_by_command,
+            exec_debounce: config.exec_debounce,
+            chdir: config.chdir.clone(),
+            record: config.record.clone(),
+            sinks: config.sinks.clone(),
+            output: config.output.clone(),
+            output_append: config.output_append,
+            exit_on_broken_pipe: config.exit_on_broken_pipe,
+            shutdown: Shutdown::Global,
+            metrics: Arc::default(),
+            color: config.color == ColorChoice::Always,
+            queue_size: config.queue_size,
+            shutdown_timeout: config.shutdown_timeout,
+        }
+    }
+
+    /// The debounce group for `change`: with `--debounce-group-by-command`
+    /// its rule, if that has a command; else its `per_ext` entry, else with
+    /// `--debounce-mode per-ext` its matched extension, else `None` for the
+    /// global group.
+    fn group(&self, change: &Change) -> Option<String> {
+        if self.group_by_command && self.rule_commands.iter().any(|(tag, _)| *tag == change.tag) {
+            return Some(format!("rule:{}", change.tag));
+        }
+        let window = self
+            .per_ext
+            .iter()
+            .find(|(ext, _)| matches_extension(&change.path, std::slice::from_ref(ext)));
+        match window {
+            Some((ext, _)) => Some(ext.clone()),
+            None if self.mode == DebounceMode::PerExt => change.ext.clone(),
+            None => None,
+        }
+    }
+
+    fn window(&self, group: &Option<String>) -> Duration {
+        self.per_ext
+            .iter()
+            .find(|(ext, _)| group.as_ref() == Some(ext))
+            .map_or(self.debounce, |(_, window)| *window)
+    }
+
+    /// `path` as printed: canonical if `--canonical` is set, then relative
+    /// to the longest matching root if `--relative` is, or to the base if
+    /// `--relative-to` is.
+    fn printed_path(&self, path: PathBuf) -> PathBuf {
+        let path = if self.canonical {
+            canonical_path(path)
+        } else {
+            path
+        };
+        for root in &self.roots {
+            if let Ok(rel) = path.strip_prefix(root) {
+                return if rel.as_os_str().is_empty() {
+                    PathBuf::from(".")
+                } else {
+                    rel.to_path_buf()
+                };
+            }
+        }
+        path
+    }
+}
+
+/// `fs::canonicalize(path)`, or for a path that no longer exists its
+/// canonical parent joined with the file name; unresolvable paths are kept.
+fn canonical_path(path: PathBuf) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(&path) {
+        return canonical;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match fs::canonicalize(parent) {
+            Ok(parent) => parent.join(name),
+            Err(_) => path,
+        },
+        _ => path,
+    }
+}
+
+/// A changed path as the debouncer writes it: everything a batch line can
+/// show. It is also the debouncer's usual entry type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Change {
+    path: PathBuf,
+    tag: String,
+    /// The `--ext`/`--rule`/`--name` entry the path matched; `None` for
+    /// `--all` and directory events.
+    ext: Option<String>,
+    /// The new target of a repointed symlink, printed after the path.
+    target: Option<PathBuf>,
+    /// Size in bytes when flushed, with `--with-size`; `None` once the
+    /// file is gone.
+    size: Option<u64>,
+    /// The previous and new mtime of a modified file, with `--with-mtime`
+    /// (poll mode).
+    mtimes: Option<(SystemTime, SystemTime)>,
+    /// What happened to the path, if known; with `--coalesce-transient`, the
+    /// net result of everything pending for it.
+    kind: Option<ChangeKind>,
+}
+
+/// An entry a [`Debouncer`] batches. Pending entries are deduplicated on
+/// [`key`](Batched::key), so by default equal entries are written once.
+trait Batched: Clone + Eq + Hash + Send + 'static {
+    /// What this entry is deduplicated on.
+    fn key(&self, _opts: &DebounceOptions) -> Self {
+        self.clone()
+    }
+
+    /// Folds `later`, an entry with the same key, into this pending one.
+    /// Returns `false` if the two cancel out and nothing should be written.
+    fn merge(&mut self, _later: Self, _opts: &DebounceOptions) -> bool {
+        true
+    }
+
+    /// The debounce group this entry waits in; `None` for the global one.
+    fn group(&self, _opts: &DebounceOptions) -> Option<String> {
+        None
+    }
+}
+
+impl Batched for Change {
+    /// The change normalized so that, with `--ignore-case`, `App.php` and
+    /// `app.php` collapse into the first seen.
+    fn key(&self, opts: &DebounceOptions) -> Change {
+        let path = if opts.ignore_case {
+            PathBuf::from(self.path.to_string_lossy().to_lowercase())
+        } else {
+            self.path.clone()
+        };
+        Change {
+            path,
+            // Under `--verbose-events` the tag is the event kind, which would
+            // keep a file's create and remove apart.
+            tag: if opts.coalesce_transient {
+                String::new()
+            } else {
+                self.tag.clone()
+            },
+            ext: self.ext.clone(),
+            target: self.target.clone(),
+            size: self.size,
+            mtimes: None,
+            kind: None,
+        }
+    }
+
+    fn merge(&mut self, later: Change, opts: &DebounceOptions) -> bool {
+        if opts.coalesce_transient {
+            self.kind = match (self.kind, later.kind) {
+                // It never outlived the window, so there is nothing to report.
+                (Some(ChangeKind::Created), Some(ChangeKind::Removed)) => return false,
+                (Some(ChangeKind::Created), _) => Some(ChangeKind::Created),
+                (Some(ChangeKind::Removed), Some(ChangeKind::Created)) => {
+                    Some(ChangeKind::Modified)
+                }
+                (_, kind) => kind,
+            };
+        }
+        // Polls coalesced into one entry span from the first mtime to the last.
+        if let (Some((first, _)), Some((_, last))) = (self.mtimes, later.mtimes) {
+            self.mtimes = Some((first, last));
+        }
+        true
+    }
+
+    fn group(&self, opts: &DebounceOptions) -> Option<String> {
+        opts.group(self)
+    }
+}
+
+/// What the debouncer thread receives.
+enum Queued<T> {
+    Entry(T),
+    /// An `--errors-to-output` message, written as soon as it arrives.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    Error(String),
+    /// The `--ready-marker` line.
+    Ready,
+}
+
+struct Debouncer<T: Batched = Change> {
+    /// With a zero debounce (and no throttle), changes are written straight
+    /// from `send` with no thread or queue.
+    direct: Option<Mutex<Batcher<T>>>,
+    tx: Option<mpsc::SyncSender<Queued<T>>>,
+    handle: Option<thread::JoinHandle<()>>,
+    /// The thread's [`Batcher::written`], still readable if it is left
+    /// behind on shutdown.
+    written: Arc<AtomicU64>,
+    /// Changes discarded because the queue was full.
+    dropped: Cell<u64>,
+    metrics: Arc<Metrics>,
+    /// See [`Config::shutdown_timeout`].
+    shutdown_timeout: Duration,
+}
+
+/// State owned by the debouncer thread.
+struct Batcher<T = Change> {
+    opts: DebounceOptions,
+    out: Output,
+    /// `--output FORMAT:DEST` sinks. Their write errors are ignored, so one
+    /// failing doesn't stop the others.
+    sinks: Vec<(OutputFormat, Output)>,
+    /// Keyed by each entry's [`Batched::key`].
+    pending: HashMap<T, T>,
+    /// Turns an entry into the change its line is written from.
+    format: Box<dyn Fn(&T) -> Change + Send>,
+    /// When each path was last written, for `--dedup-window`.
+    reported: HashMap<PathBuf, Instant>,
+    /// Changes written so far.
+    written: Arc<AtomicU64>,
+    /// When the last batch was written, for `--flush-cooldown`.
+    last_report: Option<Instant>,
+    batch: Option<BatchRunner>,
+    /// `--rule-command` runners, by rule tag.
+    rule_batches: Vec<(String, BatchRunner)>,
+    record: Option<Recorder>,
+}
+
+/// Writes `--record` lines: one JSON object per written change, with the
+/// milliseconds since the recording started.
+struct Recorder {
+    file: io::BufWriter<fs::File>,
+    started: Instant,
+}
+
+impl Recorder {
+    fn write(&mut self, changes: &[Change]) -> io::Result<()> {
+        let ms = self.started.elapsed().as_millis();
+        for c in changes {
+            writeln!(self.file, "{}", record_line(ms, c))?;
+        }
+        self.file.flush()
+    }
+}
+
+fn open_record(file: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().create(true).append(true).open(file)
+}
+
+/// One `--record` line, e.g.
+/// `{"ms":1200,"kind":"changed","path":"app/User.php","ext":".php"}`.
+fn record_line(ms: u128, change: &Change) -> String {
+    let mut line = format!(
+        "{{\"ms\":{},\"kind\":{},\"path\":{}",
+        ms,
+        json_string(&change.tag),
+        json_string(&display_path(&change.path))
+    );
+    if let Some(ext) = &change.ext {
+        line.push_str(&format!(",\"ext\":{}", json_string(ext)));
+    }
+    if let Some(target) = &change.target {
+        line.push_str(&format!(
+            ",\"target\":{}",
+            json_string(&display_path(target))
+        ));
+    }
+    line.push('}');
+    line
+}
+
+/// Parses a [`record_line`] back into its time offset and change.
+fn parse_record(line: &str) -> Option<(Duration, Change)> {
+    let mut fields = parse_json_fields(line, true)?;
+    let ms = fields.get("ms")?.parse().ok()?;
+    Some((
+        Duration::from_millis(ms),
+        Change {
+            path: PathBuf::from(fields.remove("path")?),
+            tag: fields.remove("kind")?,
+            ext: fields.remove("ext"),
+            target: fields.remove("target").map(PathBuf::from),
+            size: None,
+            mtimes: None,
+            kind: None,
+        },
+    ))
+}
+
+/// Changed file names keyed by their directory, both sorted, for
+/// `--group-by-dir`.
+fn group_by_dir(changes: &[Change]) -> BTreeMap<String, Vec<String>> {
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for c in changes {
+        let dir = match c.path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => display_path(p),
+            _ => ".".to_string(),
+        };
+        let name = c
+            .path
+            .file_name()
+            .map(|n| display_path(Path::new(n)))
+            .unwrap_or_else(|| display_path(&c.path));
+        groups.entry(dir).or_default().push(name);
+    }
+    for files in groups.values_mut() {
+        files.sort();
+    }
+    groups
+}
+
+/// The directories holding more than `--collapse-threshold` of `changes`,
+/// with how many each holds. Empty when collapsing is off.
+fn dense_dirs<'a>(opts: &DebounceOptions, changes: &'a [Change]) -> BTreeMap<&'a Path, usize> {
+    let mut counts: BTreeMap<&Path, usize> = BTreeMap::new();
+    if opts.collapse_threshold == 0 {
+        return counts;
+    }
+    for c in changes {
+        if let Some(dir) = c.path.parent() {
+            *counts.entry(dir).or_default() += 1;
+        }
+    }
+    counts.retain(|_, n| *n > opts.collapse_threshold);
+    counts
+}
+
+/// How a collapsed directory is printed: with a trailing slash, and `./`
+/// for the root itself under `--relative`.
+fn collapsed_dir(dir: &Path) -> String {
+    if dir.as_os_str().is_empty() {
+        "./".to_string()
+    } else {
+        format!("{}/", display_path(dir))
+    }
+}
+
+/// Runs `--batch-command` once per flush on its own thread so a slow command
+/// never holds up debouncing. Batches run one at a time, in order. With
+/// `--exec-debounce`, batches arriving within `delay` of each other are
+/// merged into one run.
+struct BatchRunner {
+    tx: Option<mpsc::Sender<Vec<PathBuf>>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl BatchRunner {
+    fn new(command: BatchCommand, dir: Option<PathBuf>, delay: Duration) -> Self {
+        let (tx, rx) = mpsc::channel::<Vec<PathBuf>>();
+        let handle = thread::spawn(move || {
+            while let Ok(mut paths) = rx.recv() {
+                if !delay.is_zero() {
+                    // A disconnect means shutdown: run what we have now.
+                    while let Ok(more) = rx.recv_timeout(delay) {
+                        for path in more {
+                            if !paths.contains(&path) {
+                                paths.push(path);
+                            }
+                        }
+                    }
+                }
+                run_batch_command(&command, dir.as_deref(), &paths);
+            }
+        });
+        BatchRunner {
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    fn submit(&self, paths: Vec<PathBuf>) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(paths);
+        }
+    }
+}
+
+impl Drop for BatchRunner {
+    /// Waits for queued batches so the last one runs before exit.
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// What `--batch-command`, a `--rule-command` or the words after `--` run.
+#[derive(Clone, Debug, PartialEq)]
+enum BatchCommand {
+    /// A command line for `sh -c` (`cmd /C` on Windows).
+    Shell(String),
+    /// A program and its arguments, run directly.
+    Argv(Vec<String>),
+}
+
+impl BatchCommand {
+    /// The batch command from `--batch-command` or after `--`, if any.
+    fn from_config(config: &Config) -> Option<Self> {
+        match (&config.batch_command, config.batch_argv.is_empty()) {
+            (Some(command), _) => Some(BatchCommand::Shell(command.clone())),
+            (None, false) => Some(BatchCommand::Argv(config.batch_argv.clone())),
+            (None, true) => None,
+        }
+    }
+
+    fn command(&self) -> process::Command {
+        match self {
+            BatchCommand::Shell(command) => shell(command),
+            BatchCommand::Argv(argv) => {
+                let mut cmd = process::Command::new(&argv[0]);
+                cmd.args(&argv[1..]);
+                cmd
+            }
+        }
+    }
+}
+
+fn shell(command: &str) -> process::Command {
+    if cfg!(windows) {
+        let mut cmd = process::Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = process::Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+/// Spawns `command` (in `dir`, if given), writes `paths` to its stdin
+/// NUL-separated, and waits for it. The command's stdout goes to our stderr to keep change output clean.
+/// Returns its exit status, or `None` if it couldn't be run.
+fn run_batch_command(
+    command: &BatchCommand,
+    dir: Option<&Path>,
+    paths: &[PathBuf],
+) -> Option<ExitStatus> {
+    let mut cmd = command.command();
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+    let mut child = match cmd.stdin(Stdio::piped()).stdout(io::stderr()).spawn() {
+        Ok(c) => c,
+        Err(e) => {
+            log(
+                LogLevel::Error,
+                format_args!("batch command failed to start: {}", e),
+            );
+            return None;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        for p in paths {
+            // A command that exits without reading everything is not an error.
+            if stdin.write_all(p.as_os_str().as_encoded_bytes()).is_err()
+                || stdin.write_all(b"\0").is_err()
+            {
+                break;
+            }
+        }
+    }
+    match child.wait() {
+        Ok(status) => {
+            if !status.success() {
+                log(
+                    LogLevel::Error,
+                    format_args!("batch command exited with {}", status),
+                );
+            }
+            Some(status)
+        }
+        Err(e) => {
+            log(LogLevel::Error, format_args!("batch command failed: {}", e));
+            None
+        }
+    }
+}
+
+/// `--exec-on-start`: runs `--batch-command` once with no paths, after the
+/// watch is in place so nothing it changes is missed. It isn't a batch, so
+/// it doesn't count towards `--max-events`.
+fn run_start_command(config: &Config) {
+    let Some(command) = BatchCommand::from_config(config).filter(|_| config.exec_on_start) else {
+        return;
+    };
+    if let Some(status) = run_batch_command(&command, config.chdir.as_deref(), &[])
+        && status.success()
+    {
+        // A failure is already reported by `run_batch_command`.
+        log(
+            LogLevel::Info,
+            format_args!("info: start command exited with {}", status),
+        );
+    }
+}
+
+/// Whether a CSV output starts with the header: always, unless it is a file
+/// `--output-append` is adding to that already has lines (and so a header).
+fn needs_csv_header(output: Option<&Path>, opts: &DebounceOptions) -> bool {
+    !opts.output_append || output.is_none_or(|path| fs::metadata(path).is_ok_and(|m| m.len() == 0))
+}
+
+impl Batcher {
+    fn new(opts: DebounceOptions, writer: Box<dyn Write + Send>) -> Self {
+        Batcher::with_formatter(opts, writer, Change::clone)
+    }
+}
+
+impl<T: Batched> Batcher<T> {
+    fn with_formatter(
+        opts: DebounceOptions,
+        writer: Box<dyn Write + Send>,
+        format: impl Fn(&T) -> Change + Send + 'static,
+    ) -> Self {
+        let mut out = Output::new(writer, opts.flush_interval, opts.throttle);
+        if opts.format == OutputFormat::Print0 {
+            out.terminator = '\0';
+        }
+        if opts.format == OutputFormat::Csv && needs_csv_header(opts.output.as_deref(), &opts) {
+            out.line(CSV_HEADER);
+        }
+        out.exit_on_broken_pipe = opts.exit_on_broken_pipe.then(|| opts.shutdown.clone());
+        let batch = opts
+            .batch_command
+            .clone()
+            .map(|command| BatchRunner::new(command, opts.chdir.clone(), opts.exec_debounce));
+        let rule_batches = opts
+            .rule_commands
+            .iter()
+            .map(|(tag, command)| {
+                (
+                    tag.clone(),
+                    BatchRunner::new(
+                        BatchCommand::Shell(command.clone()),
+                        opts.chdir.clone(),
+                        opts.exec_debounce,
+                    ),
+                )
+            })
+            .collect();
+        let record = opts
+            .record
+            .as_ref()
+            .and_then(|file| match open_record(file) {
+                Ok(f) => Some(Recorder {
+                    file: io::BufWriter::new(f),
+                    started: Instant::now(),
+                }),
+                Err(e) => {
+                    log(
+                        LogLevel::Warn,
+                        format_args!("warning: not recording to {}: {}", file.display(), e),
+                    );
+                    None
+                }
+            });
+        let sinks = opts
+            .sinks
+            .iter()
+            .filter_map(|sink| {
+                let writer: Box<dyn Write + Send> = match &sink.output {
+                    None => Box::new(io::BufWriter::new(io::stdout())),
+                    Some(path) => match open_output_file(path, opts.output_append) {
+                        Ok(file) => Box::new(io::BufWriter::new(file)),
+                        Err(e) => {
+                            log(
+                                LogLevel::Warn,
+                                format_args!("warning: not writing to {}: {}", path.display(), e),
+                            );
+                            return None;
+                        }
+                    },
+                };
+                let mut out = Output::new(writer, opts.flush_interval, opts.throttle);
+                if sink.format == OutputFormat::Print0 {
+                    out.terminator = '\0';
+                }
+                if sink.format == OutputFormat::Csv
+                    && needs_csv_header(sink.output.as_deref(), &opts)
+                {
+                    out.line(CSV_HEADER);
+                }
+                Some((sink.format, out))
+            })
+            .collect();
+        Batcher {
+            opts,
+            out,
+            sinks,
+            pending: HashMap::new(),
+            format: Box::new(format),
+            reported: HashMap::new(),
+            written: Arc::default(),
+            last_report: None,
+            batch,
+            rule_batches,
+            record,
+        }
+    }
+
+    fn add(&mut self, entry: T) {
+        match self.pending.entry(entry.key(&self.opts)) {
+            Entry::Vacant(pending) => {
+                pending.insert(entry);
+            }
+            Entry::Occupied(mut pending) => {
+                if !pending.get_mut().merge(entry, &self.opts) {
+                    pending.remove();
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        let pending: Vec<(T, T)> = self.pending.drain().collect();
+        self.report(pending);
+    }
+
+    fn error(&mut self, message: &str) {
+        let line = |format| match format {
+            OutputFormat::Text | OutputFormat::Print0 => format!("error: {}", message),
+            OutputFormat::JsonBatch => format!(
+                "{{\"event\":\"error\",\"message\":{}}}",
+                json_string(message)
+            ),
+            OutputFormat::Csv => csv_row(&["error", message, &format_rfc3339(SystemTime::now())]),
+        };
+        self.out.line(&labelled(
+            &self.opts,
+            self.opts.format,
+            line(self.opts.format),
+        ));
+        for (format, out) in &mut self.sinks {
+            out.line(&labelled(&self.opts, *format, line(*format)));
+        }
+        self.flush_outputs();
+    }
+
+    /// Writes the `--ready-marker` line.
+    fn ready(&mut self) {
+        let line = |format| match format {
+            OutputFormat::Text | OutputFormat::Print0 => "ready".to_string(),
+            OutputFormat::JsonBatch => "{\"event\":\"ready\"}".to_string(),
+            OutputFormat::Csv => csv_row(&["ready", "", &format_rfc3339(SystemTime::now())]),
+        };
+        self.out.line(&labelled(
+            &self.opts,
+            self.opts.format,
+            line(self.opts.format),
+        ));
+        for (format, out) in &mut self.sinks {
+            out.line(&labelled(&self.opts, *format, line(*format)));
+        }
+        self.flush_outputs();
+    }
+
+    /// Until when `--flush-cooldown` holds the next batch back; `None` once
+    /// it may be written.
+    fn held_until(&self) -> Option<Instant> {
+        let until = self.last_report? + self.opts.flush_cooldown;
+        (until > Instant::now()).then_some(until)
+    }
+
+    /// The main output and the sinks.
+    fn outputs(&mut self) -> impl Iterator<Item = &mut Output> {
+        std::iter::once(&mut self.out).chain(self.sinks.iter_mut().map(|(_, out)| out))
+    }
+
+    /// See [`Output::flush_due_in`]; the soonest of any output.
+    fn flush_due_in(&self) -> Option<Duration> {
+        std::iter::once(&self.out)
+            .chain(self.sinks.iter().map(|(_, out)| out))
+            .filter_map(Output::flush_due_in)
+            .min()
+    }
+
+    fn flush_if_due(&mut self) {
+        self.outputs().for_each(Output::flush_if_due);
+    }
+
+    fn flush_outputs(&mut self) {
+        self.outputs().for_each(Output::flush);
+    }
+
+    fn finish(&mut self) {
+        let timeout = self.opts.shutdown_timeout;
+        let limit = if timeout.is_zero() {
+            THROTTLE_DRAIN_LIMIT
+        } else {
+            THROTTLE_DRAIN_LIMIT.min(timeout / 2)
+        };
+        self.outputs().for_each(|out| out.finish(limit));
+    }
+
+    /// Flushes only the pending changes in debounce group `group`.
+    fn flush_group(&mut self, group: &Option<String>) {
+        let opts = &self.opts;
+        let pending: Vec<(T, T)> = self
+            .pending
+            .extract_if(|_, entry| entry.group(opts) == *group)
+            .collect();
+        self.report(pending);
+    }
+
+    fn report(&mut self, pending: Vec<(T, T)>) {
+        let window = self.opts.dedup_window;
+        if !window.is_zero() {
+            self.reported.retain(|_, at| at.elapsed() < window);
+        }
+
+        let now = Instant::now();
+        let mut changes: Vec<Change> = Vec::with_capacity(pending.len());
+        for (key, entry) in pending {
+            if !window.is_zero() {
+                let key = (self.format)(&key);
+                if self.reported.contains_key(&key.path) {
+                    continue;
+                }
+                self.reported.insert(key.path, now);
+            }
+            let c = (self.format)(&entry);
+            // Read now rather than at the event, so it's the settled size.
+            let size = if self.opts.with_size {
+                fs::metadata(&c.path).ok().map(|m| m.len())
+            } else {
+                None
+            };
+            changes.push(Change {
+                path: self.opts.printed_path(c.path),
+                tag: c.tag,
+                ext: c.ext,
+                target: c.target,
+                size,
+                mtimes: c.mtimes,
+                kind: c.kind,
+            });
+        }
+        if changes.is_empty() {
+            return;
+        }
+        if self.opts.sort {
+            changes.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.tag.cmp(&b.tag)));
+        }
+        let limit_reached = self.opts.max_events > 0 && {
+            let remaining = self
+                .opts
+                .max_events
+                .saturating_sub(self.written.load(Ordering::Relaxed));
+            changes.truncate(remaining as usize);
+            changes.len() as u64 == remaining
+        };
+        if changes.is_empty() {
+            return;
+        }
+
+        let count = changes.len();
+        self.written.fetch_add(count as u64, Ordering::Relaxed);
+        self.last_report = Some(Instant::now());
+        self.opts.metrics.flushes.fetch_add(1, Ordering::Relaxed);
+        if let Some(record) = &mut self.record
+            && let Err(e) = record.write(&changes)
+        {
+            log(
+                LogLevel::Warn,
+                format_args!("warning: stopped recording: {}", e),
+            );
+            self.record = None;
+        }
+        for line in render_batch(&self.opts, self.opts.format, self.opts.color, &changes) {
+            self.out.line(&line);
+        }
+        for (format, out) in &mut self.sinks {
+            for line in render_batch(&self.opts, *format, false, &changes) {
+                out.line(&line);
+            }
+        }
+
+        for (tag, runner) in &self.rule_batches {
+            let paths: Vec<PathBuf> = changes
+                .iter()
+                .filter(|c| c.tag == *tag)
+                .map(|c| c.path.clone())
+                .collect();
+            if !paths.is_empty() {
+                runner.submit(paths);
+            }
+        }
+        if let Some(runner) = &self.batch {
+            runner.submit(changes.into_iter().map(|c| c.path).collect());
+        }
+
+        if limit_reached {
+            self.opts.shutdown.request();
+        }
+    }
+}
+
+/// The output lines for one batch of `changes` in `format`, including the
+/// `--summary` line. For [`OutputFormat::Print0`] they are bare paths.
+fn render_batch(
+    opts: &DebounceOptions,
+    format: OutputFormat,
+    color: bool,
+    changes: &[Change],
+) -> Vec<String> {
+    let count = changes.len();
+    let mut lines = Vec::new();
+    match format {
+        OutputFormat::Print0 => {
+            lines.extend(changes.iter().map(|c| display_path(&c.path)));
+        }
+        OutputFormat::Csv => {
+            let now = format_rfc3339(SystemTime::now());
+            lines.extend(
+                changes
+                    .iter()
+                    .map(|c| csv_row(&[&c.tag, &display_path(&c.path), &now])),
+            );
+        }
+        OutputFormat::Text if opts.group_by_dir => {
+            for (dir, files) in group_by_dir(changes) {
+                lines.push(format!("{}:", dir));
+                for f in files {
+                    lines.push(format!("  {}", f));
+                }
+            }
+        }
+        OutputFormat::JsonBatch if opts.group_by_dir => {
+            let dirs: Vec<String> = group_by_dir(changes)
+                .into_iter()
+                .map(|(dir, files)| {
+                    let files: Vec<String> = files.iter().map(|f| json_string(f)).collect();
+                    format!("{}:[{}]", json_string(&dir), files.join(","))
+                })
+                .collect();
+            lines.push(format!(
+                "{{\"event\":\"batch\",\"dirs\":{{{}}},\"count\":{}}}",
+                dirs.join(","),
+                count
+            ));
+        }
+        OutputFormat::Text => {
+            let now = SystemTime::now();
+            let dense = dense_dirs(opts, changes);
+            let mut collapsed = HashSet::new();
+            for c in changes {
+                if let Some(dir) = c.path.parent()
+                    && let Some(n) = dense.get(dir)
+                {
+                    // At the directory's first file; the rest are dropped.
+                    if collapsed.insert(dir) {
+                        lines.push(format!("changed-dir: {} ({} files)", collapsed_dir(dir), n));
+                    }
+                    continue;
+                }
+                let mut line = opts.template.render(c, now, color);
+                if let Some((old, new)) = c.mtimes {
+                    line.push_str(&format!(" ({} -> {})", unix_secs(old), unix_secs(new)));
+                }
+                if let Some(size) = c.size {
+                    line.push_str(&format!(" ({} bytes)", size));
+                }
+                lines.push(line);
+            }
+        }
+        OutputFormat::JsonBatch => {
+            let dense = dense_dirs(opts, changes);
+            for (dir, n) in &dense {
+                lines.push(format!(
+                    "{{\"event\":\"changed-dir\",\"path\":{},\"collapsed\":true,\"count\":{}}}",
+                    json_string(&collapsed_dir(dir)),
+                    n
+                ));
+            }
+            let changes: Vec<&Change> = changes
+                .iter()
+                .filter(|c| c.path.parent().is_none_or(|dir| !dense.contains_key(dir)))
+                .collect();
+            let paths: Vec<String> = changes
+                .iter()
+                .map(|c| json_string(&display_path(&c.path)))
+                .collect();
+            let exts: Vec<String> = changes
+                .iter()
+                .map(|c| c.ext.as_deref().map_or("null".to_string(), json_string))
+                .collect();
+            // Removed files get `null`.
+            let sizes = if opts.with_size {
+                let sizes: Vec<String> = changes
+                    .iter()
+                    .map(|c| c.size.map_or("null".to_string(), |s| s.to_string()))
+                    .collect();
+                format!(",\"sizes\":[{}]", sizes.join(","))
+            } else {
+                String::new()
+            };
+            // Only modified files have mtimes; the rest get `null`.
+            let mtimes = if opts.with_mtime {
+                let secs = |pick: fn((SystemTime, SystemTime)) -> SystemTime| {
+                    changes
+                        .iter()
+                        .map(|c| {
+                            c.mtimes
+                                .map_or("null".to_string(), |m| unix_secs(pick(m)).to_string())
+                        })
+                        .collect::<Vec<String>>()
+                        .join(",")
+                };
+                format!(
+                    ",\"old_mtimes\":[{}],\"new_mtimes\":[{}]",
+                    secs(|(old, _)| old),
+                    secs(|(_, new)| new)
+                )
+            } else {
+                String::new()
+            };
+            // Every file may have been collapsed.
+            if !changes.is_empty() || dense.is_empty() {
+                lines.push(format!(
+                    "{{\"event\":\"batch\",\"paths\":[{}],\"exts\":[{}]{}{},\"count\":{}}}",
+                    paths.join(","),
+                    exts.join(","),
+                    sizes,
+                    mtimes,
+                    changes.len()
+                ));
+            }
+        }
+    }
+
+    if opts.summary {
+        lines.extend(match format {
+            OutputFormat::Text => Some(format!(
+                "--- {} {} changed ---",
+                count,
+                if count == 1 { "file" } else { "files" }
+            )),
+            OutputFormat::JsonBatch => {
+                Some(format!("{{\"event\":\"summary\",\"count\":{}}}", count))
+            }
+            OutputFormat::Print0 | OutputFormat::Csv => None,
+        });
+    }
+
+    lines
+        .into_iter()
+        .map(|line| labelled(opts, format, line))
+        .collect()
+}
+
+/// `line` labelled with `--label`: prefixed in text, with a `watcher` field
+/// added to a JSON object.
+fn labelled(opts: &DebounceOptions, format: OutputFormat, line: String) -> String {
+    let Some(label) = &opts.label else {
+        return line;
+    };
+    match format {
+        OutputFormat::Text | OutputFormat::Print0 => format!("[{}] {}", label, line),
+        OutputFormat::JsonBatch => match line.strip_suffix('}') {
+            Some(object) => format!("{},\"watcher\":{}}}", object, json_string(label)),
+            None => line,
+        },
+        // Rows keep to the header's columns.
+        OutputFormat::Csv => line,
+    }
+}
+
+/// Xorshift generator for `--debounce-jitter` offsets. Spreading flushes
+/// doesn't need good randomness, just a different sequence per process.
+struct Jitter {
+    max: Duration,
+    state: u64,
+}
+
+impl Jitter {
+    fn new(max: Duration) -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        Jitter {
+            max,
+            // Xorshift never leaves zero, so force a bit on.
+            state: ((std::process::id() as u64) << 32 ^ nanos) | 1,
+        }
+    }
+
+    /// A random offset in `0..max`.
+    fn next(&mut self) -> Duration {
+        let max = self.max.as_nanos() as u64;
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        Duration::from_nanos(self.state % max)
+    }
+}
+
+impl Debouncer {
+    fn new(opts: DebounceOptions, writer: Box<dyn Write + Send>) -> Self {
+        Debouncer::with_formatter(opts, writer, Change::clone)
+    }
+}
+
+impl<T: Batched> Debouncer<T> {
+    /// A debouncer for entries of type `T`, each written as the change
+    /// `format` turns it into.
+    fn with_formatter(
+        opts: DebounceOptions,
+        writer: Box<dyn Write + Send>,
+        format: impl Fn(&T) -> Change + Send + 'static,
+    ) -> Self {
+        let shutdown_timeout = opts.shutdown_timeout;
+        let metrics = opts.metrics.clone();
+        // A throttle backlog needs the thread to trickle it out.
+        if opts.debounce.is_zero()
+            && opts.per_ext.iter().all(|(_, d)| d.is_zero())
+            && opts.throttle == 0
+            && opts.flush_every.is_zero()
+            && opts.flush_cooldown.is_zero()
+            && !opts.buffer_all
+        {
+            let batch = Batcher::with_formatter(opts, writer, format);
+            return Debouncer {
+                written: Arc::clone(&batch.written),
+                direct: Some(Mutex::new(batch)),
+                tx: None,
+                handle: None,
+                dropped: Cell::new(0),
+                metrics,
+                shutdown_timeout,
+            };
+        }
+
+        let (tx, rx) = mpsc::sync_channel::<Queued<T>>(opts.queue_size);
+
+        let leading = opts.leading;
+        let every = opts.flush_every;
+        let buffer_all = opts.buffer_all;
+        let mut jitter = Jitter::new(opts.jitter);
+        let mut batch = Batcher::with_formatter(opts, writer, format);
+        let written = Arc::clone(&batch.written);
+        let handle = thread::spawn(move || {
+            // When each debounce group flushes: its window (plus jitter)
+            // after its most recent event. A group is removed once flushed.
+            let mut deadlines: HashMap<Option<String>, Instant> = HashMap::new();
+            // The next `--flush-every` tick, on a fixed schedule from startup.
+            let mut next_tick = (!every.is_zero() && !buffer_all).then(|| Instant::now() + every);
+
+            loop {
+                if buffer_all && DUMP.swap(false, Ordering::SeqCst) {
+                    batch.flush();
+                    batch.flush_if_due();
+                }
+                // Anything due during a `--flush-cooldown` waits for its end.
+                let held = batch.held_until();
+                let due_in = |at: Instant| {
+                    held.map_or(at, |h| at.max(h))
+                        .saturating_duration_since(Instant::now())
+                };
+                let quiet_in = deadlines.values().map(|d| due_in(*d)).min();
+                let tick_in = next_tick.map(due_in);
+                // Nothing wakes the thread for a dump, so check for one.
+                let dump_in = buffer_all.then_some(Duration::from_millis(50));
+                let timeout = [quiet_in, tick_in, batch.flush_due_in(), dump_in]
+                    .into_iter()
+                    .flatten()
+                    .min();
+
+                let msg = match timeout {
+                    None => match rx.recv() {
+                        Ok(msg) => Some(msg),
+                        Err(_) => break,
+                    },
+                    Some(timeout) => match rx.recv_timeout(timeout) {
+                        Ok(msg) => Some(msg),
+                        Err(mpsc::RecvTimeoutError::Timeout) => {
+                            let now = Instant::now();
+                            let quiet: Vec<Option<String>> = deadlines
+                                .iter()
+                                .filter(|&(_, d)| *d <= now)
+                                .map(|(g, _)| g.clone())
+                                .collect();
+                            for group in quiet {
+                                // Each group's batch restarts the cooldown.
+                                if batch.held_until().is_some() {
+                                    break;
+                                }
+                                batch.flush_group(&group);
+                                deadlines.remove(&group);
+                            }
+                            if let Some(tick) = next_tick.as_mut()
+                                && *tick <= now
+                                && batch.held_until().is_none()
+                            {
+                                batch.flush();
+                                deadlines.clear();
+                                // Skip ticks missed while blocked on output.
+                                while *tick <= now {
+                                    *tick += every;
+                                }
+                            }
+                            batch.flush_if_due();
+                            None
+                        }
+                        Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                    },
+                };
+
+                match msg {
+                    Some(Queued::Entry(entry)) if buffer_all => batch.add(entry),
+                    Some(Queued::Entry(entry)) => {
+                        let group = entry.group(&batch.opts);
+                        let idle = !deadlines.contains_key(&group);
+                        batch.add(entry);
+                        if leading && idle && batch.held_until().is_none() {
+                            batch.flush_group(&group);
+                            batch.flush_if_due();
+                        }
+                        let window = batch.opts.window(&group);
+                        // With `--flush-every`, a zero window waits for the tick.
+                        if !(window.is_zero() && next_tick.is_some()) {
+                            deadlines.insert(group, Instant::now() + window + jitter.next());
+                        }
+                    }
+                    Some(Queued::Error(message)) => batch.error(&message),
+                    Some(Queued::Ready) => batch.ready(),
+                    None => {}
+                }
+            }
+
+            batch.flush();
+            batch.finish();
+        });
+
+        Debouncer {
+            direct: None,
+            tx: Some(tx),
+            handle: Some(handle),
+            written,
+            dropped: Cell::new(0),
+            metrics,
+            shutdown_timeout,
+        }
+    }
+
+    /// Queues a change without blocking; if the writer has fallen behind and
+    /// the queue is full, the change is dropped and counted instead. With a
+    /// zero debounce the change is written and flushed immediately.
+    fn send(&self, entry: T) {
+        if let Some(direct) = &self.direct {
+            if let Ok(mut batch) = direct.lock() {
+                batch.add(entry);
+                batch.flush();
+                batch.flush_outputs();
+            }
+            return;
+        }
+        if let Some(tx) = &self.tx
+            && let Err(mpsc::TrySendError::Full(_)) = tx.try_send(Queued::Entry(entry))
+        {
+            self.dropped.set(self.dropped.get() + 1);
+            self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes an error entry into the change output, between batches rather
+    /// than inside one.
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    fn error(&self, message: &str) {
+        if let Some(direct) = &self.direct {
+            if let Ok(mut batch) = direct.lock() {
+                batch.error(message);
+            }
+            return;
+        }
+        if let Some(tx) = &self.tx
+            && let Err(mpsc::TrySendError::Full(_)) = tx.try_send(Queued::Error(message.into()))
+        {
+            self.dropped.set(self.dropped.get() + 1);
+            self.metrics.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Writes the `--ready-marker` line, after anything already queued.
+    /// Unlike changes it is never dropped, so this may block on the queue.
+    fn ready(&self) {
+        if let Some(direct) = &self.direct {
+            if let Ok(mut batch) = direct.lock() {
+                batch.ready();
+            }
+            return;
+        }
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Queued::Ready);
+        }
+    }
+
+    /// Flushes outstanding changes and stops the thread, returning how many
+    /// changes were written in total.
+    fn shutdown(&mut self) -> u64 {
+        if let Some(direct) = self.direct.take() {
+            return direct
+                .into_inner()
+                .map(|b| b.written.load(Ordering::Relaxed))
+                .unwrap_or(0);
+        }
+        self.tx.take();
+        let Some(handle) = self.handle.take() else {
+            return 0;
+        };
+        // A writer or `--batch-command` that never returns would otherwise
+        // hang the exit, so past the timeout the thread is left behind.
+        let started = Instant::now();
+        while !handle.is_finished() {
+            if !self.shutdown_timeout.is_zero() && started.elapsed() >= self.shutdown_timeout {
+                log(
+                    LogLevel::Warn,
+                    format_args!(
+                        "warning: output still draining after {:?}, exiting without it",
+                        self.shutdown_timeout
+                    ),
+                );
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        if handle.is_finished() {
+            let _ = handle.join();
+        }
+        let written = self.written.load(Ordering::Relaxed);
+        let dropped = self.dropped.get();
+        if dropped > 0 {
+            log(
+                LogLevel::Warn,
+                format_args!("warning: dropped {} changes (output queue full)", dropped),
+            );
+        }
+        written
+    }
+}
+
+impl<T: Batched> Drop for Debouncer<T> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Failed `--verify-writes` reads after which a change is reported anyway.
+#[cfg(feature = "native")]
+const VERIFY_WRITE_RETRIES: u32 = 10;
+
+/// `--min-size`/`--min-age` thresholds a file must meet before it is reported,
+/// plus the `--verify-writes` read check.
+#[cfg(feature = "native")]
+#[derive(Clone, Copy, Default)]
+struct Readiness {
+    min_size: u64,
+    min_age: Duration,
+    verify_writes: bool,
+}
+
+#[cfg(feature = "native")]
+impl Readiness {
+    fn from_config(config: &Config) -> Self {
+        Readiness {
+            min_size: config.min_size,
+            min_age: config.min_age,
+            verify_writes: config.verify_writes,
+        }
+    }
+
+    /// Whether `path` meets the thresholds. Paths without metadata (e.g. just
+    /// removed) are always ready so removals are not held back.
+    fn is_ready(&self, path: &Path) -> bool {
+        if self.min_size == 0 && self.min_age.is_zero() {
+            return true;
+        }
+        let meta = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(_) => return true,
+        };
+        let age = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.elapsed().ok())
+            .unwrap_or(Duration::MAX);
+        meta.len() >= self.min_size && age >= self.min_age
+    }
+
+    /// With `--verify-writes`, whether `path` can be opened and read to the
+    /// end. Missing paths count as readable so removals are not held back.
+    fn is_readable(&self, path: &Path) -> bool {
+        if !self.verify_writes {
+            return true;
+        }
+        match fs::File::open(path) {
+            Ok(mut file) => io::copy(&mut file, &mut io::sink()).is_ok(),
+            Err(e) => e.kind() == io::ErrorKind::NotFound,
+        }
+    }
+}
+
+/// Event counters reported by `--stats` when the watcher exits.
+#[derive(Default)]
+#[cfg(feature = "native")]
+struct Stats {
+    events: u64,
+    ignored: u64,
+    kind: u64,
+    extension: u64,
+    sent: u64,
+}
+
+#[cfg(feature = "native")]
+impl Stats {
+    fn report(&self, written: u64) {
+        eprintln!(
+            "stats: events={} filtered-ignore={} filtered-kind={} filtered-extension={} debounced={} flushed={}",
+            self.events,
+            self.ignored,
+            self.kind,
+            self.extension,
+            self.sent.saturating_sub(written),
+            written
+        );
+    }
+}
+
+/// Counters served by `--metrics-addr`. They are kept whether or not
+/// anything serves them.
+#[derive(Debug, Default)]
+struct Metrics {
+    /// Raw events in watcher mode; differences between scans when polling.
+    events: AtomicU64,
+    /// Batches written.
+    flushes: AtomicU64,
+    /// Changes dropped because the output fell behind.
+    dropped: AtomicU64,
+    /// Roots watched, counting `--control` additions and removals.
+    watched_paths: AtomicU64,
+}
+
+impl Metrics {
+    /// The counters for `config`'s roots, served on `--metrics-addr` if set.
+    fn start(config: &Config) -> Result<Arc<Metrics>, WatchError> {
+        let metrics = Arc::new(Metrics::default());
+        metrics
+            .watched_paths
+            .store(config.paths.len() as u64, Ordering::Relaxed);
+        if let Some(addr) = config.metrics_addr {
+            let listener = TcpListener::bind(addr).map_err(|e| WatchError::Metrics(addr, e))?;
+            serve_metrics(listener, &metrics).map_err(|e| WatchError::Metrics(addr, e))?;
+        }
+        Ok(metrics)
+    }
+
+    /// The Prometheus text exposition of the counters.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, kind, help, value) in [
+            (
+                "events_total",
+                "counter",
+                "Filesystem events seen.",
+                &self.events,
+            ),
+            (
+                "flushes_total",
+                "counter",
+                "Batches written.",
+                &self.flushes,
+            ),
+            (
+                "dropped_total",
+                "counter",
+                "Changes dropped because the output fell behind.",
+                &self.dropped,
+            ),
+            (
+                "current_watched_paths",
+                "gauge",
+                "Paths being watched.",
+                &self.watched_paths,
+            ),
+        ] {
+            out.push_str(&format!(
+                "# HELP filewatcher_{name} {help}\n# TYPE filewatcher_{name} {kind}\nfilewatcher_{name} {}\n",
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+}
+
+/// Answers every connection to `listener` with `metrics`, as a bare
+/// HTTP/1.0 response whatever the request. The thread ends once the run
+/// has dropped its counters.
+fn serve_metrics(listener: TcpListener, metrics: &Arc<Metrics>) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let metrics = Arc::downgrade(metrics);
+    thread::spawn(move || {
+        while let Some(metrics) = metrics.upgrade() {
+            match listener.accept() {
+                Ok((stream, _)) => respond_metrics(stream, &metrics),
+                Err(_) => {
+                    drop(metrics);
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn respond_metrics(mut stream: TcpStream, metrics: &Metrics) {
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(1)));
+    // Read the request so closing with it unread doesn't reset the
+    // connection before the client has the response.
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request);
+    let body = metrics.render();
+    let _ = write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+}
+
+/// Emits a liveness line on stderr when nothing has happened for a while.
+struct Heartbeat {
+    interval: Duration,
+    last: Instant,
+    json: bool,
+}
+
+impl Heartbeat {
+    fn new(config: &Config) -> Self {
+        Heartbeat {
+            interval: config.heartbeat,
+            last: Instant::now(),
+            json: config.format == OutputFormat::JsonBatch,
+        }
+    }
+
+    fn activity(&mut self) {
+        self.last = Instant::now();
+    }
+
+    /// Time until the next heartbeat is due, or `None` if disabled.
+    #[cfg(feature = "native")]
+    fn due_in(&self) -> Option<Duration> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        Some(self.interval.saturating_sub(self.last.elapsed()))
+    }
+
+    fn tick(&mut self) {
+        if !self.interval.is_zero() && self.last.elapsed() >= self.interval {
+            if self.json {
+                eprintln!("{{\"event\":\"heartbeat\"}}");
+            } else {
+                eprintln!("heartbeat");
+            }
+            self.last = Instant::now();
+        }
+    }
+}
+
+/// `--coalesce-by-size`: remembers the size each path was last sent at, and
+/// drops a change that finds the file the same size again within the
+/// debounce window, as with the extra modify events some editors fire during
+/// one save.
+#[cfg(feature = "native")]
+struct SizeCoalescer {
+    /// `None` when disabled.
+    window: Option<Duration>,
+    sent: HashMap<PathBuf, (u64, Instant)>,
+}
+
+#[cfg(feature = "native")]
+impl SizeCoalescer {
+    fn new(config: &Config) -> Self {
+        SizeCoalescer {
+            window: config.coalesce_by_size.then_some(config.debounce),
+            sent: HashMap::new(),
+        }
+    }
+
+    /// Removes the changes in `burst` that are repeats by size, and records
+    /// the sizes of the rest. Files that can't be read are always kept.
+    fn filter(&mut self, burst: &mut Vec<Change>) {
+        let Some(window) = self.window else {
+            return;
+        };
+        self.sent.retain(|_, (_, at)| at.elapsed() < window);
+        burst.retain(|change| {
+            let Ok(size) = fs::metadata(&change.path).map(|m| m.len()) else {
+                self.sent.remove(&change.path);
+                return true;
+            };
+            if self
+                .sent
+                .get(&change.path)
+                .is_some_and(|(last, _)| *last == size)
+            {
+                return false;
+            }
+            self.sent
+                .insert(change.path.clone(), (size, Instant::now()));
+            true
+        });
+    }
+}
+
+/// `--content-only`: remembers a hash of each file's contents and drops a
+/// change that finds them the same, as after a bare `touch`. Shared by both
+/// backends; the cost is a full read of every file that changes.
+struct ContentFilter {
+    /// `None` when disabled.
+    hashes: Option<HashMap<PathBuf, u64>>,
+}
+
+impl ContentFilter {
+    fn new(enabled: bool) -> Self {
+        ContentFilter {
+            hashes: enabled.then(HashMap::new),
+        }
+    }
+
+    /// Records the current contents of `paths`, so their first change is
+    /// compared against the files as they were at startup.
+    fn seed<'a>(&mut self, paths: impl Iterator<Item = &'a Path>) {
+        let Some(hashes) = &mut self.hashes else {
+            return;
+        };
+        for path in paths {
+            if let Ok(hash) = hash_file(path) {
+                hashes.insert(path.to_path_buf(), hash);
+            }
+        }
+    }
+
+    /// Whether a change to `path` should be reported: always when disabled
+    /// or when the file can't be read (it was removed, or is a directory),
+    /// otherwise only if its contents differ from the last time.
+    fn changed(&mut self, path: &Path) -> bool {
+        let Some(hashes) = &mut self.hashes else {
+            return true;
+        };
+        let Ok(hash) = hash_file(path) else {
+            hashes.remove(path);
+            return true;
+        };
+        hashes.insert(path.to_path_buf(), hash) != Some(hash)
+    }
+}
+
+/// `--alert-rate`: counts events per debounce window (one second without a
+/// debounce) and warns when a window ends over the limit. With
+/// `--alert-pause`, changes are dropped from then until a window ends under
+/// it again.
+#[cfg(feature = "native")]
+struct RateAlert {
+    limit: u64,
+    window: Duration,
+    pause: bool,
+    start: Instant,
+    count: u64,
+    paused: bool,
+}
+
+#[cfg(feature = "native")]
+impl RateAlert {
+    fn new(config: &Config) -> Self {
+        RateAlert {
+            limit: config.alert_rate,
+            window: if config.debounce.is_zero() {
+                Duration::from_secs(1)
+            } else {
+                config.debounce
+            },
+            pause: config.alert_pause,
+            start: Instant::now(),
+            count: 0,
+            paused: false,
+        }
+    }
+
+    fn event(&mut self) {
+        if self.limit > 0 {
+            self.tick();
+            self.count += 1;
+        }
+    }
+
+    /// Time until the current window ends, or `None` if disabled.
+    fn due_in(&self) -> Option<Duration> {
+        (self.limit > 0).then(|| self.window.saturating_sub(self.start.elapsed()))
+    }
+
+    /// Closes the window if it is over, warning if it went over the limit.
+    fn tick(&mut self) {
+        if self.limit == 0 || self.start.elapsed() < self.window {
+            return;
+        }
+        let over = self.count > self.limit;
+        if over {
+            log(
+                LogLevel::Warn,
+                format_args!("warning: high change rate, {} events in window", self.count),
+            );
+        }
+        if self.pause && over != self.paused {
+            if over {
+                log(
+                    LogLevel::Warn,
+                    format_args!("warning: pausing reporting until the change rate drops"),
+                );
+            } else {
+                log(
+                    LogLevel::Warn,
+                    format_args!("change rate back under {}, reporting resumed", self.limit),
+                );
+            }
+            self.paused = over;
+        }
+        self.start = Instant::now();
+        self.count = 0;
+    }
+
+    /// Whether changes are currently being dropped.
+    fn paused(&self) -> bool {
+        self.paused
+    }
+}
+
+#[derive(Clone)]
+struct ScanOptions {
+    filter: Filter,
+    follow_symlinks: bool,
+    recursive: bool,
+    compare: PollCompare,
+    watch_dirs: bool,
+    min_size: u64,
+    fast: bool,
+    warn_unreadable: bool,
+}
+
+/// What the poller records per file to detect changes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stamp {
+    Mtime(SystemTime),
+    Hash(u64),
+}
+
+/// A snapshot of the matching files (and, with `--watch-dirs`, directories)
+/// below the roots. Produced by [`scan`] and compared with [`diff`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanState {
+    /// Keyed by the path under the root as given (made absolute but not
+    /// canonicalized), so repointing a symlinked root between scans does not
+    /// turn every file into a removal plus a creation.
+    files: HashMap<PathBuf, Stamp>,
+    /// Directories below the roots; only populated with `--watch-dirs`.
+    dirs: HashSet<PathBuf>,
+    /// Where each symlink below the roots points, as read by `read_link`:
+    /// links to directories, and links to files that match the filter.
+    links: HashMap<PathBuf, PathBuf>,
+    /// Canonical path for each `files`/`dirs` key that differs from it.
+    real: HashMap<PathBuf, PathBuf>,
+    /// Directory listings kept for `--poll-fast`.
+    listings: HashMap<PathBuf, Listing>,
+    /// Directories `read_dir` failed on, so `--warn-unreadable` warns about
+    /// each only in the first scan it fails in.
+    unreadable: HashSet<PathBuf>,
+}
+
+/// A directory's entries as read at `read_at`. With `--poll-fast` the next
+/// scan reuses them instead of calling `read_dir` while the directory's mtime
+/// is unchanged; files are still stamped individually, since editing a file
+/// in place does not touch its directory's mtime.
+#[derive(Clone, Debug, PartialEq)]
+struct Listing {
+    mtime: SystemTime,
+    read_at: SystemTime,
+    entries: Vec<ListedEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ListedEntry {
+    path: PathBuf,
+    is_dir: bool,
+    is_symlink: bool,
+}
+
+/// A listing is only trusted if it was read at least this long after the
+/// directory's mtime, so an entry added within the same (coarse) timestamp
+/// tick is not missed.
+const DIR_MTIME_GRANULARITY: Duration = Duration::from_secs(2);
+
+impl ScanState {
+    /// The matching files in this snapshot, in no particular order.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.keys().map(|k| self.real_path(k))
+    }
+
+    /// The files as a JSON object mapping each path to its stamp, as written
+    /// by `filewatcher snapshot`. Directories are not included.
+    pub fn to_json(&self) -> String {
+        let mut entries: Vec<(&PathBuf, &Stamp)> = self.files.iter().collect();
+        entries.sort_by_key(|(path, _)| *path);
+        let fields: Vec<String> = entries
+            .into_iter()
+            .map(|(path, stamp)| {
+                let stamp = match stamp {
+                    Stamp::Mtime(t) => {
+                        let d = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+                        format!("mtime:{}.{:09}", d.as_secs(), d.subsec_nanos())
+                    }
+                    Stamp::Hash(h) => format!("hash:{:016x}", h),
+                };
+                format!(
+                    "{}:{}",
+                    json_string(&display_path(path)),
+                    json_string(&stamp)
+                )
+            })
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// Reads back [`ScanState::to_json`] output, or `None` if `s` isn't one.
+    pub fn from_json(s: &str) -> Option<ScanState> {
+        let mut state = ScanState::default();
+        for (path, stamp) in parse_json_object(s)? {
+            let stamp = match stamp.split_once(':')? {
+                ("mtime", t) => {
+                    let (secs, nanos) = t.split_once('.')?;
+                    let since = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+                    Stamp::Mtime(SystemTime::UNIX_EPOCH + since)
+                }
+                ("hash", h) => Stamp::Hash(u64::from_str_radix(h, 16).ok()?),
+                _ => return None,
+            };
+            state.files.insert(PathBuf::from(path), stamp);
+        }
+        Some(state)
+    }
+
+    /// The canonical path behind a `files`/`dirs` key, which is what gets
+    /// reported.
+    fn real_path<'a>(&'a self, key: &'a Path) -> &'a Path {
+        self.real.get(key).map_or(key, PathBuf::as_path)
+    }
+
+    /// Adds `scanned`, a scan of the canonical `root`, re-keying its entries
+    /// under `logical`.
+    fn merge(&mut self, scanned: ScanState, root: &Path, logical: &Path) {
+        let mut rekey = |path: PathBuf| -> PathBuf {
+            match path.strip_prefix(root) {
+                Ok(rel) if root != logical => {
+                    let key = logical.join(rel);
+                    self.real.insert(key.clone(), path);
+                    key
+                }
+                _ => path,
+            }
+        };
+        let files: Vec<(PathBuf, Stamp)> = scanned
+            .files
+            .into_iter()
+            .map(|(path, stamp)| (rekey(path), stamp))
+            .collect();
+        let dirs: Vec<PathBuf> = scanned.dirs.into_iter().map(&mut rekey).collect();
+        let links: Vec<(PathBuf, PathBuf)> = scanned
+            .links
+            .into_iter()
+            .map(|(path, target)| (rekey(path), target))
+            .collect();
+        self.files.extend(files);
+        self.dirs.extend(dirs);
+        self.links.extend(links);
+        self.listings.extend(scanned.listings);
+        self.unreadable.extend(scanned.unreadable);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+    DirCreated,
+    DirRemoved,
+    /// A symlink now points somewhere else.
+    SymlinkChanged,
+}
+
+/// One difference between two [`ScanState`]s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// The link's new target, for [`ChangeKind::SymlinkChanged`].
+    pub target: Option<PathBuf>,
+    /// The previous and new mtime, for a [`ChangeKind::Modified`] file in
+    /// snapshots compared by mtime.
+    pub mtimes: Option<(SystemTime, SystemTime)>,
+}
+
+/// Scans every root in `config.paths` once. Roots that cannot be resolved are
+/// skipped, as they are between polls.
+pub fn scan(config: &Config) -> ScanState {
+    scan_roots(
+        &config.paths,
+        &ScanOptions::from_config(config),
+        &ScanState::default(),
+    )
+}
+
+/// Scans `roots`; `prev` is the previous scan, whose listings `--poll-fast`
+/// may reuse.
+fn scan_roots(roots: &[PathBuf], opts: &ScanOptions, prev: &ScanState) -> ScanState {
+    let mut state = ScanState::default();
+    for root in roots {
+        if let Ok(abs) = fs::canonicalize(root) {
+            let logical = std::path::absolute(root).unwrap_or_else(|_| abs.clone());
+            let mut scanned = ScanState::default();
+            scan_dir(&abs, opts, prev, &mut scanned);
+            state.merge(scanned, &abs, &logical);
+        }
+    }
+    state
+}
+
+/// Whole seconds since the Unix epoch; zero for earlier times.
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Compares two snapshots, returning the changes sorted by path. Entries are
+/// matched by their path under the root as given, and reported by their
+/// canonical path in the snapshot they were seen in.
+pub fn diff(old: &ScanState, new: &ScanState) -> Vec<ChangeEvent> {
+    let mut events = Vec::new();
+    let mut repointed = HashSet::new();
+    for (path, target) in &new.links {
+        if old.links.get(path).is_some_and(|prev| prev != target) {
+            repointed.insert(path);
+            events.push(ChangeEvent {
+                path: new.real_path(path).to_path_buf(),
+                kind: ChangeKind::SymlinkChanged,
+                target: Some(target.clone()),
+                mtimes: None,
+            });
+        }
+    }
+    for (path, stamp) in &new.files {
+        let kind = match old.files.get(path) {
+            Some(prev) if prev == stamp => continue,
+            // The new target's stamp; already reported as a repointed link.
+            Some(_) if repointed.contains(path) => continue,
+            Some(_) => ChangeKind::Modified,
+            None => ChangeKind::Created,
+        };
+        let mtimes = match (old.files.get(path), stamp) {
+            (Some(Stamp::Mtime(prev)), Stamp::Mtime(now)) => Some((*prev, *now)),
+            _ => None,
+        };
+        events.push(ChangeEvent {
+            path: new.real_path(path).to_path_buf(),
+            kind,
+            target: None,
+            mtimes,
+        });
+    }
+    for path in old.files.keys() {
+        if !new.files.contains_key(path) {
+            events.push(ChangeEvent {
+                path: old.real_path(path).to_path_buf(),
+                kind: ChangeKind::Removed,
+                target: None,
+                mtimes: None,
+            });
+        }
+    }
+    for path in new.dirs.difference(&old.dirs) {
+        events.push(ChangeEvent {
+            path: new.real_path(path).to_path_buf(),
+            kind: ChangeKind::DirCreated,
+            target: None,
+            mtimes: None,
+        });
+    }
+    for path in old.dirs.difference(&new.dirs) {
+        events.push(ChangeEvent {
+            path: old.real_path(path).to_path_buf(),
+            kind: ChangeKind::DirRemoved,
+            target: None,
+            mtimes: None,
+        });
+    }
+    events.sort_by(|a, b| a.path.cmp(&b.path));
+    events
+}
+
+/// 64-bit FNV-1a over the file contents.
+fn hash_file(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hash);
+        }
+        for b in &buf[..n] {
+            hash ^= u64::from(*b);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions::from_config(&Config::default())
+    }
+}
+
+impl ScanOptions {
+    fn from_config(config: &Config) -> Self {
+        ScanOptions {
+            filter: Filter::from_config(config),
+            follow_symlinks: config.follow_symlinks,
+            recursive: config.recursive,
+            compare: config.poll_compare,
+            watch_dirs: config.watch_dirs,
+            min_size: config.min_size,
+            fast: config.poll_fast,
+            warn_unreadable: config.warn_unreadable,
+        }
+    }
+
+    fn stamp(&self, path: &Path) -> Option<Stamp> {
+        match self.compare {
+            PollCompare::Mtime => fs::metadata(path)
+                .and_then(|m| m.modified())
+                .ok()
+                .map(Stamp::Mtime),
+            PollCompare::Hash => hash_file(path).ok().map(Stamp::Hash),
+        }
+    }
+
+    /// Forwards a scanned path to the debouncer under its filter tag.
+    fn send(
+        &self,
+        debouncer: &Debouncer,
+        path: &Path,
+        kind: ChangeKind,
+        mtimes: Option<(SystemTime, SystemTime)>,
+    ) {
+        if let Some((tag, ext)) = self.filter.classify(path) {
+            debouncer.send(Change {
+                path: path.to_path_buf(),
+                tag: tag.to_string(),
+                ext: ext.map(str::to_string),
+                target: None,
+                size: None,
+                mtimes,
+                kind: Some(kind),
+            });
+        }
+    }
+}
+
+/// Lists `dir`, reusing `prev`'s listing under `--poll-fast` when the
+/// directory has not changed since it was read.
+fn list_dir(
+    dir: &Path,
+    opts: &ScanOptions,
+    prev: &ScanState,
+    state: &mut ScanState,
+) -> Option<Vec<ListedEntry>> {
+    let mtime = if opts.fast {
+        fs::metadata(dir).and_then(|m| m.modified()).ok()
+    } else {
+        None
+    };
+    if let Some(mtime) = mtime
+        && let Some(cached) = prev.listings.get(dir)
+        && cached.mtime == mtime
+        && cached
+            .read_at
+            .duration_since(mtime)
+            .is_ok_and(|d| d >= DIR_MTIME_GRANULARITY)
+    {
+        state.listings.insert(dir.to_path_buf(), cached.clone());
+        return Some(cached.entries.clone());
+    }
+
+    let read_at = SystemTime::now();
+    let listing = match fs::read_dir(dir) {
+        Ok(listing) => listing,
+        Err(e) => {
+            if opts.warn_unreadable && !prev.unreadable.contains(dir) {
+                log(
+                    LogLevel::Warn,
+                    format_args!("warning: cannot read {}: {}", dir.display(), e),
+                );
+            }
+            state.unreadable.insert(dir.to_path_buf());
+            return None;
+        }
+    };
+    let entries: Vec<ListedEntry> = listing
+        .flatten()
+        .filter_map(|entry| {
+            let file_type = entry.file_type().ok()?;
+            let path = entry.path();
+            let is_symlink = file_type.is_symlink();
+            let is_dir = if is_symlink {
+                path.is_dir()
+            } else {
+                file_type.is_dir()
+            };
+            Some(ListedEntry {
+                path,
+                is_dir,
+                is_symlink,
+            })
+        })
+        .collect();
+    if let Some(mtime) = mtime {
+        state.listings.insert(
+            dir.to_path_buf(),
+            Listing {
+                mtime,
+                read_at,
+                entries: entries.clone(),
+            },
+        );
+    }
+    Some(entries)
+}
+
+fn scan_dir(root: &Path, opts: &ScanOptions, prev: &ScanState, state: &mut ScanState) {
+    // Canonical directories already descended into; only tracked when following
+    // symlinks, since that is the only way to revisit a directory.
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    if opts.follow_symlinks
+        && let Ok(real) = fs::canonicalize(root)
+    {
+        visited.insert(real);
+    }
+
+    let watch_also = &opts.filter.watch_also;
+    // Each directory to list, and whether it is inside an ignored directory
+    // that is only being entered to reach a `--watch-also` one.
+    let mut stack = vec![(root.to_path_buf(), false)];
+    while let Some((dir, passing)) = stack.pop() {
+        let entries = match list_dir(&dir, opts, prev, state) {
+            Some(e) => e,
+            None => continue,
+        };
+        for ListedEntry {
+            path,
+            is_dir,
+            is_symlink,
+        } in entries
+        {
+            let leads_to_also = is_dir && watch_also.iter().any(|d| d.starts_with(&path));
+            let in_also = watch_also.iter().any(|d| path.starts_with(d));
+            // Ignored by name unless under, or on the way to, a watched one.
+            let ignored = if passing {
+                !in_also && !leads_to_also
+            } else {
+                is_ignored(&path) && !leads_to_also
+            };
+            let passes_through = !in_also && (passing || is_ignored(&path));
+            if is_symlink
+                && !ignored
+                && !opts.filter.ignored(&path)
+                && (is_dir || opts.filter.matches(&path))
+                && let Ok(target) = fs::read_link(&path)
+            {
+                state.links.insert(path.clone(), target);
+            }
+            if is_dir {
+                if ignored || opts.filter.ignored(&path) || (is_symlink && !opts.follow_symlinks) {
+                    continue;
+                }
+                if opts.follow_symlinks {
+                    let first_visit =
+                        fs::canonicalize(&path).is_ok_and(|real| visited.insert(real));
+                    if !first_visit {
+                        continue;
+                    }
+                }
+                if opts.watch_dirs {
+                    state.dirs.insert(path.clone());
+                }
+                if opts.recursive {
+                    stack.push((path, passes_through));
+                }
+            } else if !passing
+                && (!ignored || opts.filter.hidden_files && is_hidden(&path))
+                && opts.filter.matches(&path)
+                && !opts.filter.ignored(&path)
+                && (opts.min_size == 0
+                    || fs::metadata(&path).is_ok_and(|m| m.len() >= opts.min_size))
+                && let Some(stamp) = opts.stamp(&path)
+            {
+                state.files.insert(path, stamp);
+            }
+        }
+    }
+}
+
+/// Whether events of `kind` are dropped: metadata, access and ambiguous
+/// events are, unless `--watch-metadata`/`--watch-access`/
+/// `--include-ambiguous` ask for them, and removals are with `--no-removals`.
+#[cfg(feature = "native")]
+fn skips_kind(config: &Config, kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Modify(ModifyKind::Metadata(_)) => !config.watch_metadata,
+        EventKind::Access(_) => !config.watch_access,
+        EventKind::Remove(_) => !config.removals,
+        // The backend saw something but couldn't tell what (e.g. a
+        // platform event notify has no mapping for); the path may not even
+        // have changed.
+        EventKind::Any => !config.include_ambiguous,
+        // Backend-specific events, such as notices about the watch itself
+        // rather than about a file.
+        EventKind::Other => !config.include_ambiguous,
+        _ => false,
+    }
+}
+
+/// The [`ChangeKind`] a notify event is offered to a
+/// [`run_watcher_filtered`] predicate as. Anything but a creation or removal
+/// counts as a modification.
+#[cfg(feature = "native")]
+fn change_kind(path: &Path, kind: &EventKind) -> ChangeKind {
+    match kind {
+        EventKind::Create(CreateKind::Folder) => ChangeKind::DirCreated,
+        EventKind::Create(_) if path.is_dir() => ChangeKind::DirCreated,
+        EventKind::Create(_) => ChangeKind::Created,
+        EventKind::Remove(RemoveKind::Folder) => ChangeKind::DirRemoved,
+        EventKind::Remove(_) => ChangeKind::Removed,
+        _ => ChangeKind::Modified,
+    }
+}
+
+/// Most queued messages the watcher handles per wakeup.
+#[cfg(feature = "native")]
+const MAX_DRAIN: usize = 1024;
+
+/// What the watcher loop waits on: backend events, `--control` commands, or
+/// a wake-up from [`request_shutdown`].
+#[cfg(feature = "native")]
+enum WatchMsg {
+    Event(notify::Result<notify::Event>),
+    Control(Result<ControlCmd, String>),
+    /// A `--control-socket` command, answered with one line on the sender.
+    #[cfg_attr(not(unix), allow(dead_code))]
+    Socket(Result<SocketCmd, String>, mpsc::Sender<String>),
+    Shutdown,
+}
+
+/// A `--control` command read from stdin as a JSON line.
+#[derive(Debug, PartialEq)]
+#[cfg(feature = "native")]
+enum ControlCmd {
+    Watch(PathBuf),
+    Unwatch(PathBuf),
+}
+
+#[cfg(feature = "native")]
+fn parse_control(line: &str) -> Result<ControlCmd, String> {
+    let fields = parse_json_object(line).ok_or("expected a JSON object of strings")?;
+    let path = fields.get("path").ok_or("missing \"path\"")?;
+    match fields.get("cmd").map(String::as_str) {
+        Some("watch") => Ok(ControlCmd::Watch(PathBuf::from(path))),
+        Some("unwatch") => Ok(ControlCmd::Unwatch(PathBuf::from(path))),
+        Some(other) => Err(format!("unknown cmd '{}'", other)),
+        None => Err("missing \"cmd\"".into()),
+    }
+}
+
+/// A `--control-socket` command.
+#[derive(Debug, PartialEq)]
+#[cfg(feature = "native")]
+#[cfg_attr(not(unix), allow(dead_code))]
+enum SocketCmd {
+    /// `add PATH` and `remove PATH`, as `watch`/`unwatch` on stdin.
+    Control(ControlCmd),
+    Status,
+    /// Watch every configured root afresh, e.g. after one was recreated.
+    Reload,
+    Shutdown,
+}
+
+/// Parses one `--control-socket` line, e.g. `add /srv/app`.
+#[cfg(feature = "native")]
+#[cfg_attr(not(unix), allow(dead_code))]
+fn parse_socket_command(line: &str) -> Result<SocketCmd, String> {
+    let line = line.trim();
+    let (word, arg) = line
+        .split_once(char::is_whitespace)
+        .map_or((line, ""), |(word, arg)| (word, arg.trim()));
+    match (word, arg) {
+        ("status", "") => Ok(SocketCmd::Status),
+        ("reload", "") => Ok(SocketCmd::Reload),
+        ("shutdown", "") => Ok(SocketCmd::Shutdown),
+        ("status" | "reload" | "shutdown", _) => Err(format!("{} takes no argument", word)),
+        ("add" | "remove", "") => Err(format!("{} requires a path", word)),
+        ("add", path) => Ok(SocketCmd::Control(ControlCmd::Watch(PathBuf::from(path)))),
+        ("remove", path) => Ok(SocketCmd::Control(ControlCmd::Unwatch(PathBuf::from(path)))),
+        _ => Err(format!("unknown command '{}'", word)),
+    }
+}
+
+/// Accepts `--control-socket` connections until dropped, then removes the
+/// socket file.
+#[cfg(all(feature = "native", unix))]
+struct ControlSocket {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+#[cfg(all(feature = "native", unix))]
+impl ControlSocket {
+    fn listen(path: &Path, tx: mpsc::Sender<WatchMsg>) -> io::Result<ControlSocket> {
+        // A socket left behind by a run that didn't exit cleanly would make
+        // the bind fail; one that still answers belongs to a live watcher.
+        if fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+            && UnixStream::connect(path).is_err()
+        {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let tx = tx.clone();
+                        thread::spawn(move || serve_control_socket(stream, tx));
+                    }
+                    Err(_) => thread::sleep(Duration::from_millis(100)),
+                }
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            stop,
+        })
+    }
+}
+
+#[cfg(all(feature = "native", unix))]
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Answers one connection's commands, a line for each, until it closes or
+/// the watcher stops.
+#[cfg(all(feature = "native", unix))]
+fn serve_control_socket(stream: UnixStream, tx: mpsc::Sender<WatchMsg>) {
+    use std::io::BufRead;
+
+    let _ = stream.set_nonblocking(false);
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    for line in io::BufReader::new(stream).lines() {
+        let Ok(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply_tx, reply_rx) = mpsc::channel();
+        if tx
+            .send(WatchMsg::Socket(parse_socket_command(&line), reply_tx))
+            .is_err()
+        {
+            break;
+        }
+        // Unanswered means the watcher stopped first.
+        let Ok(reply) = reply_rx.recv() else { break };
+        if writeln!(writer, "{}", reply).is_err() {
+            break;
+        }
+    }
+}
+
+/// Reads `--control` commands from stdin until it closes.
+#[cfg(feature = "native")]
+fn spawn_control_reader(tx: mpsc::Sender<WatchMsg>) {
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if tx.send(WatchMsg::Control(parse_control(&line))).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Applies a `--control` command and reports the outcome as a JSON line on
+/// stdout.
+#[cfg(feature = "native")]
+fn apply_control(
+    watcher: &mut dyn Watcher,
+    filter: &mut Filter,
+    mode: RecursiveMode,
+    metrics: &Metrics,
+    cmd: Result<ControlCmd, String>,
+) {
+    let result = cmd.and_then(|cmd| run_control(watcher, filter, mode, metrics, cmd));
+    let line = match result {
+        Ok((event, path)) => format!(
+            "{{\"event\":\"{}\",\"path\":{}}}",
+            event,
+            json_string(&display_path(&path))
+        ),
+        Err(message) => format!(
+            "{{\"event\":\"error\",\"message\":{}}}",
+            json_string(&message)
+        ),
+    };
+    let mut stdout = io::stdout().lock();
+    let _ = writeln!(stdout, "{}", line);
+    let _ = stdout.flush();
+}
+
+/// Watches or unwatches a path, giving what happened and the path it
+/// happened to. A newly watched path becomes one of `filter`'s roots.
+#[cfg(feature = "native")]
+fn run_control(
+    watcher: &mut dyn Watcher,
+    filter: &mut Filter,
+    mode: RecursiveMode,
+    metrics: &Metrics,
+    cmd: ControlCmd,
+) -> Result<(&'static str, PathBuf), String> {
+    match cmd {
+        ControlCmd::Watch(path) => {
+            let abs = fs::canonicalize(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            if !abs.is_dir() {
+                return Err(format!("{} is not a directory", path.display()));
+            }
+            watcher
+                .watch(&abs, mode)
+                .map_err(|e| format!("failed to watch {}: {}", abs.display(), e))?;
+            metrics.watched_paths.fetch_add(1, Ordering::Relaxed);
+            if !filter.roots.contains(&abs) {
+                filter.roots.push(abs.clone());
+            }
+            Ok(("watching", abs))
+        }
+        ControlCmd::Unwatch(path) => {
+            let abs = fs::canonicalize(&path).unwrap_or(path);
+            watcher
+                .unwatch(&abs)
+                .map_err(|e| format!("failed to unwatch {}: {}", abs.display(), e))?;
+            metrics.watched_paths.fetch_sub(1, Ordering::Relaxed);
+            Ok(("unwatched", abs))
+        }
+    }
+}
+
+/// Answers a `--control-socket` command; `shutdown` sets `stop`.
+#[cfg(feature = "native")]
+fn answer_socket(
+    watcher: &mut dyn Watcher,
+    filter: &mut Filter,
+    config: &Config,
+    metrics: &Metrics,
+    cmd: Result<SocketCmd, String>,
+    stop: &mut bool,
+) -> String {
+    let answer = cmd.and_then(|cmd| match cmd {
+        SocketCmd::Control(cmd) => {
+            { run_control(watcher, filter, recursive_mode(config), metrics, cmd) }
+                .map(|(event, path)| format!("{} {}", event, display_path(&path)))
+        }
+        SocketCmd::Status => Ok(format!(
+            "watching {} paths, {} events, {} batches",
+            metrics.watched_paths.load(Ordering::Relaxed),
+            metrics.events.load(Ordering::Relaxed),
+            metrics.flushes.load(Ordering::Relaxed)
+        )),
+        SocketCmd::Reload => {
+            for root in &config.paths {
+                let abs =
+                    fs::canonicalize(root).map_err(|e| format!("{}: {}", root.display(), e))?;
+                let _ = watcher.unwatch(&abs);
+                watcher
+                    .watch(&abs, recursive_mode(config))
+                    .map_err(|e| format!("failed to watch {}: {}", abs.display(), e))?;
+            }
+            Ok(format!("reloaded {} paths", config.paths.len()))
+        }
+        SocketCmd::Shutdown => {
+            *stop = true;
+            Ok("shutting down".into())
+        }
+    });
+    match answer {
+        Ok(message) => format!("ok {}", message),
+        Err(message) => format!("error {}", message),
+    }
+}
+
+#[cfg(feature = "native")]
+fn recursive_mode(config: &Config) -> RecursiveMode {
+    if config.recursive {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    }
+}
+
+#[cfg(feature = "native")]
+fn watch_roots(
+    shutdown: &Shutdown,
+    config: &Config,
+    tx: mpsc::Sender<WatchMsg>,
+) -> Result<Box<dyn Watcher + Send>, WatchError> {
+    let notify_config = notify::Config::default().with_follow_symlinks(config.follow_symlinks);
+    let mode = recursive_mode(config);
+    let handler = move |res| {
+        let _ = tx.send(WatchMsg::Event(res));
+    };
+    let created: notify::Result<Box<dyn Watcher + Send>> = match config.backend {
+        Backend::Native => notify::RecommendedWatcher::new(handler, notify_config)
+            .map(|w| Box::new(w) as Box<dyn Watcher + Send>),
+        Backend::Poll => notify::PollWatcher::new(
+            handler,
+            notify_config.with_poll_interval(config.poll_interval),
+        )
+        .map(|w| Box::new(w) as Box<dyn Watcher + Send>),
+    };
+    let mut watcher =
+        created.map_err(|source| WatchError::WatchSetupFailed { path: None, source })?;
+
+    for root in &config.paths {
+        let mut attempt = 0;
+        loop {
+            let result = fs::canonicalize(root)
+                .map_err(|e| WatchError::Path(root.clone(), e))
+                .and_then(|abs| {
+                    watcher
+                        .watch(&abs, mode)
+                        .map_err(|source| WatchError::WatchSetupFailed {
+                            path: Some(abs),
+                            source,
+                        })
+                });
+            let Err(e) = result else { break };
+            // Running out of watches won't fix itself.
+            let at_limit =
+                matches!(&e, WatchError::WatchSetupFailed { source, .. } if is_watch_limit(source));
+            if attempt >= config.watch_retries || at_limit || shutdown.requested() {
+                return Err(e);
+            }
+            let delay = watch_retry_delay(attempt);
+            attempt += 1;
+            log(
+                LogLevel::Warn,
+                format_args!(
+                    "{}; retrying in {:?} ({}/{})",
+                    e, delay, attempt, config.watch_retries
+                ),
+            );
+            thread::sleep(delay);
+        }
+    }
+    Ok(watcher)
+}
+
+/// Backoff before `--watch-retries` retry number `attempt` (from zero):
+/// 100ms, doubling up to 5s.
+#[cfg_attr(not(feature = "native"), allow(dead_code))]
+fn watch_retry_delay(attempt: u32) -> Duration {
+    (Duration::from_millis(100) * 2u32.saturating_pow(attempt)).min(Duration::from_secs(5))
+}
+
+/// With `--wait-for-paths`, blocks until every root exists, checking every
+/// poll interval. Returns `false` if shutdown was requested first.
+fn wait_for_roots(config: &Config, shutdown: &Shutdown) -> bool {
+    if !config.wait_for_paths {
+        return true;
+    }
+    let mut announced = false;
+    loop {
+        let missing: Vec<&PathBuf> = config.paths.iter().filter(|p| !p.exists()).collect();
+        if missing.is_empty() {
+            return true;
+        }
+        if !announced {
+            for p in &missing {
+                log(
+                    LogLevel::Warn,
+                    format_args!("waiting for {} to appear", p.display()),
+                );
+            }
+            announced = true;
+        }
+        if shutdown.requested() {
+            return false;
+        }
+        thread::sleep(config.poll_interval);
+    }
+}
+
+pub fn run_watcher(config: &Config, writer: Box<dyn Write + Send>) -> Result<(), WatchError> {
+    run_watcher_filtered(config, writer, accept_all)
+}
+
+/// Like [`run_watcher`], but each change that passes the built-in checks is
+/// also offered to `filter`, and dropped if it returns `false`.
+pub fn run_watcher_filtered(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    filter: impl Fn(&Path, ChangeKind) -> bool,
+) -> Result<(), WatchError> {
+    run_watcher_with(config, writer, Shutdown::Global, &filter)
+}
+
+/// A [`run_watcher_filtered`] predicate; the built-in runners accept
+/// whatever their own filters let through.
+type Accept<'a> = dyn Fn(&Path, ChangeKind) -> bool + 'a;
+
+fn accept_all(_: &Path, _: ChangeKind) -> bool {
+    true
+}
+
+/// Like [`run_watcher`], but stops when `shutdown` is set instead of on
+/// [`request_shutdown`], so several watchers in one process can be stopped
+/// independently. Signals still only set the global [`SHUTDOWN`].
+pub fn run_watcher_with_shutdown(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), WatchError> {
+    run_watcher_with(config, writer, Shutdown::Flag(shutdown), &accept_all)
+}
+
+/// [`run_watcher`], stopped by `shutdown` rather than always the global flag.
+#[cfg(feature = "native")]
+pub(crate) fn run_watcher_with(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    shutdown: Shutdown,
+    accept: &Accept<'_>,
+) -> Result<(), WatchError> {
+    set_log_level(config.log_level);
+    if !wait_for_roots(config, &shutdown) {
+        return Ok(());
+    }
+    let (tx, rx) = mpsc::channel();
+    wake_on_shutdown(tx.clone());
+    if config.control {
+        spawn_control_reader(tx.clone());
+    }
+    // Kept alive for the duration of the loop; dropping it removes the socket.
+    #[cfg(unix)]
+    let _control_socket = match &config.control_socket {
+        Some(path) => Some(
+            ControlSocket::listen(path, tx.clone())
+                .map_err(|e| WatchError::ControlSocket(path.clone(), e))?,
+        ),
+        None => None,
+    };
+    // Kept alive for the duration of the loop; dropping it stops events.
+    let mut watcher = match watch_roots(&shutdown, config, tx) {
+        Ok(w) => w,
+        Err(e @ WatchError::WatchSetupFailed { .. }) if config.poll_fallback => {
+            log(
+                LogLevel::Warn,
+                format_args!("{}; falling back to polling", e),
+            );
+            return run_poller_with(config, writer, shutdown, accept);
+        }
+        Err(e) => return Err(e),
+    };
+    let metrics = Metrics::start(config)?;
+
+    let mut opts = DebounceOptions::from_config(config);
+    opts.shutdown = shutdown.clone();
+    opts.metrics = metrics.clone();
+    let mut debouncer = Debouncer::new(opts, writer);
+    let mut filter = Filter::from_config(config);
+    let mut heartbeat = Heartbeat::new(config);
+    let mut stats = Stats::default();
+    let readiness = Readiness::from_config(config);
+    let mut alert = RateAlert::new(config);
+    let mut sizes = SizeCoalescer::new(config);
+    let mut content = ContentFilter::new(config.content_only);
+    if config.content_only {
+        let scan = ScanOptions::from_config(config);
+        content.seed(scan_roots(&config.paths, &scan, &ScanState::default()).files());
+    }
+    log(
+        LogLevel::Info,
+        format_args!(
+            "info: watching {} paths ({} backend), ready",
+            config.paths.len(),
+            match config.backend {
+                Backend::Native => "native",
+                Backend::Poll => "poll",
+            }
+        ),
+    );
+    if config.ready_marker {
+        debouncer.ready();
+    }
+    run_start_command(config);
+    // Changes held back by `--min-size`/`--min-age`/`--verify-writes`,
+    // re-checked every debounce, with their failed verify reads so far.
+    let mut deferred: HashMap<Change, u32> = HashMap::new();
+    let mut last_recheck = Instant::now();
+    let started = Instant::now();
+
+    if config.process_existing {
+        let scan = ScanOptions::from_config(config);
+        let mut existing: Vec<PathBuf> = scan_roots(&config.paths, &scan, &ScanState::default())
+            .files()
+            .filter(|path| accept(path, ChangeKind::Created))
+            .map(Path::to_path_buf)
+            .collect();
+        existing.sort();
+        for path in &existing {
+            scan.send(&debouncer, path, ChangeKind::Created, None);
+        }
+        stats.sent += existing.len() as u64;
+    }
+
+    loop {
+        if shutdown.requested() {
+            break;
+        }
+
+        // Block until an event or shutdown, waking early only for timed work.
+        let recheck_in =
+            (!deferred.is_empty()).then(|| config.debounce.saturating_sub(last_recheck.elapsed()));
+        let timeout = [
+            heartbeat.due_in(),
+            recheck_in,
+            alert.due_in(),
+            shutdown.check_every(config.shutdown_check),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let first = match timeout {
+            None => rx.recv().map_err(|_| mpsc::RecvTimeoutError::Disconnected),
+            Some(t) => rx.recv_timeout(t),
+        };
+
+        // Having woken up, drain whatever else is already queued before
+        // blocking again. Bursts (a checkout, a build) then cost one wakeup,
+        // and repeats of a path within the burst reach the debouncer once.
+        // The drain is capped so a constant stream cannot starve the timed
+        // work below.
+        let msgs: Vec<WatchMsg> = match first {
+            Ok(msg) => std::iter::once(msg)
+                .chain(rx.try_iter().take(MAX_DRAIN))
+                .collect(),
+            Err(mpsc::RecvTimeoutError::Timeout) => Vec::new(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+
+        let mut burst: Vec<Change> = Vec::new();
+        let mut seen: HashSet<Change> = HashSet::new();
+        let mut stop = false;
+        for msg in msgs {
+            match msg {
+                WatchMsg::Shutdown => {
+                    stop = true;
+                    break;
+                }
+                WatchMsg::Control(cmd) => apply_control(
+                    &mut *watcher,
+                    &mut filter,
+                    recursive_mode(config),
+                    &metrics,
+                    cmd,
+                ),
+                WatchMsg::Socket(cmd, reply) => {
+                    let _ = reply.send(answer_socket(
+                        &mut *watcher,
+                        &mut filter,
+                        config,
+                        &metrics,
+                        cmd,
+                        &mut stop,
+                    ));
+                    if stop {
+                        break;
+                    }
+                }
+                // Swallow the startup storm.
+                WatchMsg::Event(Ok(event)) if started.elapsed() < config.initial_delay => {
+                    log(
+                        LogLevel::Debug,
+                        format_args!("debug: {:?} dropped (--initial-delay)", event.kind),
+                    );
+                }
+                WatchMsg::Event(Ok(event)) => {
+                    stats.events += 1;
+                    metrics.events.fetch_add(1, Ordering::Relaxed);
+                    alert.event();
+                    if logs(LogLevel::Debug) {
+                        let paths: Vec<String> =
+                            event.paths.iter().map(|p| display_path(p)).collect();
+                        eprintln!("debug: {:?} {}", event.kind, paths.join(" "));
+                    }
+                    for path in &event.paths {
+                        if filter.always_ignored(path) || filter.ignored(path) {
+                            stats.ignored += 1;
+                            log(
+                                LogLevel::Debug,
+                                format_args!("debug:   {} dropped: ignored", display_path(path)),
+                            );
+                            continue;
+                        }
+                        if skips_kind(config, &event.kind) {
+                            stats.kind += 1;
+                            log(
+                                LogLevel::Debug,
+                                format_args!(
+                                    "debug:   {} dropped: {:?} events are skipped",
+                                    display_path(path),
+                                    event.kind
+                                ),
+                            );
+                            continue;
+                        }
+
+                        match filter.classify(path) {
+                            Some(_) if !accept(path, change_kind(path, &event.kind)) => {
+                                log(
+                                    LogLevel::Debug,
+                                    format_args!("debug:   {} dropped: filter", display_path(path)),
+                                );
+                            }
+                            Some((tag, ext)) => {
+                                let tag = if config.verbose_events {
+                                    format!("{:?}", event.kind)
+                                } else {
+                                    tag.to_string()
+                                };
+                                let change = Change {
+                                    path: path.clone(),
+                                    tag,
+                                    ext: ext.map(str::to_string),
+                                    target: None,
+                                    size: None,
+                                    mtimes: None,
+                                    kind: Some(change_kind(path, &event.kind)),
+                                };
+                                if !readiness.is_ready(path) || !readiness.is_readable(path) {
+                                    deferred.entry(change).or_insert(0);
+                                } else if seen.insert(change.clone()) {
+                                    burst.push(change);
+                                }
+                                heartbeat.activity();
+                            }
+                            None => {
+                                stats.extension += 1;
+                                log(
+                                    LogLevel::Debug,
+                                    format_args!(
+                                        "debug:   {} dropped: no matching extension",
+                                        display_path(path)
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+                WatchMsg::Event(Err(e)) if config.errors_to_output => {
+                    debouncer.error(&e.to_string());
+                }
+                WatchMsg::Event(Err(e)) => {
+                    log(LogLevel::Error, format_args!("watcher error: {}", e));
+                }
+            }
+        }
+
+        alert.tick();
+        if alert.paused() {
+            burst.clear();
+        }
+        sizes.filter(&mut burst);
+        burst.retain(|change| content.changed(&change.path));
+        stats.sent += burst.len() as u64;
+        for change in burst {
+            debouncer.send(change);
+        }
+        if stop {
+            break;
+        }
+
+        if !deferred.is_empty() && last_recheck.elapsed() >= config.debounce {
+            last_recheck = Instant::now();
+            deferred.retain(|change, failed_reads| {
+                if !readiness.is_ready(&change.path) {
+                    return true;
+                }
+                if !readiness.is_readable(&change.path) {
+                    *failed_reads += 1;
+                    if *failed_reads < VERIFY_WRITE_RETRIES {
+                        return true;
+                    }
+                    log(
+                        LogLevel::Warn,
+                        format_args!(
+                            "warning: {} still unreadable after {} checks, reporting anyway",
+                            display_path(&change.path),
+                            failed_reads
+                        ),
+                    );
+                }
+                if content.changed(&change.path) {
+                    debouncer.send(change.clone());
+                    stats.sent += 1;
+                }
+                false
+            });
+        }
+
+        heartbeat.tick();
+    }
+
+    let written = debouncer.shutdown();
+    if config.stats {
+        stats.report(written);
+    }
+    Ok(())
+}
+
+/// Without the `native` feature there is no event backend, so watching means
+/// polling.
+#[cfg(not(feature = "native"))]
+pub(crate) fn run_watcher_with(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    shutdown: Shutdown,
+    accept: &Accept<'_>,
+) -> Result<(), WatchError> {
+    run_poller_with(config, writer, shutdown, accept)
+}
+
+/// Like [`run_watcher`], but writes through a writer the caller keeps a
+/// handle to. The lock is taken once per flush, so each batch lands whole
+/// and lines the caller writes under the same lock never split it.
+pub fn run_watcher_shared(
+    config: &Config,
+    writer: Arc<Mutex<dyn Write + Send>>,
+) -> Result<(), WatchError> {
+    run_watcher(config, Box::new(SharedWriter::new(writer)))
+}
+
+/// Buffers writes and hands them to the shared writer in one locked
+/// `write_all` on each flush.
+struct SharedWriter {
+    inner: Arc<Mutex<dyn Write + Send>>,
+    buf: Vec<u8>,
+}
+
+impl SharedWriter {
+    fn new(inner: Arc<Mutex<dyn Write + Send>>) -> Self {
+        SharedWriter {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut inner = self
+            .inner
+            .lock()
+            .map_err(|_| io::Error::other("shared writer lock poisoned"))?;
+        inner.write_all(&self.buf)?;
+        self.buf.clear();
+        inner.flush()
+    }
+}
+
+impl Drop for SharedWriter {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Writes a JSON snapshot of the matching files (see [`ScanState::to_json`]).
+pub fn run_snapshot(config: &Config, mut writer: Box<dyn Write + Send>) -> Result<(), WatchError> {
+    check_roots(config)?;
+    writeln!(writer, "{}", scan(config).to_json())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Rescans and writes a `created:`/`modified:`/`removed:` line per file that
+/// differs from the snapshot in `snapshot`. Returns whether anything did.
+pub fn run_diff(
+    config: &Config,
+    snapshot: &Path,
+    mut writer: Box<dyn Write + Send>,
+) -> Result<bool, WatchError> {
+    check_roots(config)?;
+    let saved = fs::read_to_string(snapshot).map_err(|e| WatchError::Path(snapshot.into(), e))?;
+    let before = ScanState::from_json(&saved).ok_or_else(|| {
+        WatchError::InvalidArg(format!(
+            "{} is not a filewatcher snapshot",
+            snapshot.display()
+        ))
+    })?;
+    let events = diff(&before, &scan(config));
+    for event in &events {
+        let kind = match event.kind {
+            ChangeKind::Created | ChangeKind::DirCreated => "created",
+            ChangeKind::Modified | ChangeKind::SymlinkChanged => "modified",
+            ChangeKind::Removed | ChangeKind::DirRemoved => "removed",
+        };
+        writeln!(writer, "{}: {}", kind, display_path(&event.path))?;
+    }
+    writer.flush()?;
+    Ok(!events.is_empty())
+}
+
+pub fn run_list(config: &Config, mut writer: Box<dyn Write + Send>) -> Result<(), WatchError> {
+    check_roots(config)?;
+    let state = scan(config);
+
+    let mut paths: Vec<&Path> = state.files().collect();
+    paths.sort();
+    for p in paths {
+        writeln!(writer, "{}", display_path(p))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Fails with the first root that cannot be resolved.
+fn check_roots(config: &Config) -> Result<(), WatchError> {
+    for root in &config.paths {
+        fs::canonicalize(root).map_err(|e| WatchError::Path(root.clone(), e))?;
+    }
+    Ok(())
+}
+
+/// Sleeps for `interval`, returning early on shutdown or a [`RESCAN`]
+/// request.
+fn poll_wait(interval: Duration, shutdown: &Shutdown) {
+    let deadline = Instant::now() + interval;
+    loop {
+        if RESCAN.swap(false, Ordering::SeqCst) || shutdown.requested() {
+            return;
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        thread::sleep(left.min(Duration::from_millis(50)));
+    }
+}
+
+pub fn run_poller(config: &Config, writer: Box<dyn Write + Send>) -> Result<(), WatchError> {
+    run_poller_with(config, writer, Shutdown::Global, &accept_all)
+}
+
+/// Like [`run_poller`], but stops when `shutdown` is set; see
+/// [`run_watcher_with_shutdown`].
+pub fn run_poller_with_shutdown(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    shutdown: Arc<AtomicBool>,
+) -> Result<(), WatchError> {
+    run_poller_with(config, writer, Shutdown::Flag(shutdown), &accept_all)
+}
+
+/// [`run_poller`], stopped by `shutdown` rather than always the global flag.
+pub(crate) fn run_poller_with(
+    config: &Config,
+    writer: Box<dyn Write + Send>,
+    shutdown: Shutdown,
+    accept: &Accept<'_>,
+) -> Result<(), WatchError> {
+    set_log_level(config.log_level);
+    if !wait_for_roots(config, &shutdown) {
+        return Ok(());
+    }
+    check_roots(config)?;
+    let metrics = Metrics::start(config)?;
+    let mut opts = DebounceOptions::from_config(config);
+    opts.shutdown = shutdown.clone();
+    opts.metrics = metrics.clone();
+    let mut debouncer = Debouncer::new(opts, writer);
+    let scan = ScanOptions::from_config(config);
+    let mut heartbeat = Heartbeat::new(config);
+    let saved = config.state_file.as_deref().and_then(load_state);
+    let mut state = match saved {
+        Some(mut saved) => {
+            let current = scan_roots(&config.paths, &scan, &ScanState::default());
+            // Only files are saved.
+            saved.dirs = current.dirs.clone();
+            saved.links = current.links.clone();
+            send_events(config, &scan, &debouncer, accept, &diff(&saved, &current));
+            current
+        }
+        None if config.initial_scan => scan_roots(&config.paths, &scan, &ScanState::default()),
+        None => ScanState::default(),
+    };
+    save_state(config, &state);
+    // Hash comparison already ignores a bare touch.
+    let mut content =
+        ContentFilter::new(config.content_only && config.poll_compare != PollCompare::Hash);
+    content.seed(state.files());
+    log(
+        LogLevel::Info,
+        format_args!(
+            "info: polling {} paths every {:?}, ready",
+            config.paths.len(),
+            config.poll_interval
+        ),
+    );
+    if config.ready_marker {
+        debouncer.ready();
+    }
+    run_start_command(config);
+    let started = Instant::now();
+
+    if config.process_existing {
+        let mut existing: Vec<&Path> = state
+            .files()
+            .filter(|path| accept(path, ChangeKind::Created))
+            .collect();
+        existing.sort();
+        for path in existing {
+            scan.send(&debouncer, path, ChangeKind::Created, None);
+        }
+    }
+
+    // Catch up on what changed after the `--since` checkpoint.
+    if let Some(since) = config.since {
+        let mut newer: Vec<&Path> = state
+            .files()
+            .filter(|path| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|mtime| mtime > since)
+            })
+            .filter(|path| accept(path, ChangeKind::Modified))
+            .collect();
+        newer.sort();
+        for path in newer {
+            scan.send(&debouncer, path, ChangeKind::Modified, None);
+        }
+    }
+
+    loop {
+        poll_wait(config.poll_interval, &shutdown);
+
+        if shutdown.requested() {
+            break;
+        }
+
+        let current = scan_roots(&config.paths, &scan, &state);
+        let mut events = if started.elapsed() < config.initial_delay {
+            Vec::new()
+        } else {
+            diff(&state, &current)
+        };
+        metrics
+            .events
+            .fetch_add(events.len() as u64, Ordering::Relaxed);
+        let found = !events.is_empty();
+        events.retain(|event| content.changed(&event.path));
+        send_events(config, &scan, &debouncer, accept, &events);
+
+        if events.is_empty() {
+            heartbeat.tick();
+        } else {
+            heartbeat.activity();
+        }
+        if found {
+            save_state(config, &current);
+        }
+
+        state = current;
+    }
+
+    debouncer.shutdown();
+    save_state(config, &state);
+    Ok(())
+}
+
+fn send_events(
+    config: &Config,
+    scan: &ScanOptions,
+    debouncer: &Debouncer,
+    accept: &Accept<'_>,
+    events: &[ChangeEvent],
+) {
+    for event in events {
+        if !accept(&event.path, event.kind) {
+            continue;
+        }
+        match event.kind {
+            ChangeKind::Removed | ChangeKind::DirRemoved if !config.removals => {}
+            ChangeKind::Created | ChangeKind::Modified | ChangeKind::Removed => scan.send(
+                debouncer,
+                &event.path,
+                event.kind,
+                event.mtimes.filter(|_| config.with_mtime),
+            ),
+            ChangeKind::DirCreated => debouncer.send(Change {
+                path: event.path.clone(),
+                tag: "dir-created".into(),
+                ext: None,
+                target: None,
+                size: None,
+                mtimes: None,
+                kind: Some(event.kind),
+            }),
+            ChangeKind::DirRemoved => debouncer.send(Change {
+                path: event.path.clone(),
+                tag: "dir-removed".into(),
+                ext: None,
+                target: None,
+                size: None,
+                mtimes: None,
+                kind: Some(event.kind),
+            }),
+            ChangeKind::SymlinkChanged => debouncer.send(Change {
+                path: event.path.clone(),
+                tag: "symlink-changed".into(),
+                ext: None,
+                target: event.target.clone(),
+                size: None,
+                mtimes: None,
+                kind: Some(event.kind),
+            }),
+        }
+    }
+}
+
+/// Reads a `--state-file`. A missing file is a first run; an unreadable or
+/// malformed one is reported and otherwise treated the same.
+fn load_state(file: &Path) -> Option<ScanState> {
+    let contents = match fs::read_to_string(file) {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log(
+                LogLevel::Warn,
+                format_args!("warning: ignoring state file {}: {}", file.display(), e),
+            );
+            return None;
+        }
+    };
+    let state = ScanState::from_json(&contents);
+    if state.is_none() {
+        log(
+            LogLevel::Warn,
+            format_args!(
+                "warning: ignoring state file {}: not a filewatcher snapshot",
+                file.display()
+            ),
+        );
+    }
+    state
+}
+
+/// Writes `state` to the `--state-file`, if any, through a temporary file so
+/// a crash mid-write leaves the previous state intact.
+fn save_state(config: &Config, state: &ScanState) {
+    let Some(file) = &config.state_file else {
+        return;
+    };
+    let mut tmp = file.clone().into_os_string();
+    tmp.push(".tmp");
+    let result = fs::write(&tmp, state.to_json() + "\n").and_then(|()| fs::rename(&tmp, file));
+    if let Err(e) = result {
+        log(
+            LogLevel::Warn,
+            format_args!("warning: could not save state to {}: {}", file.display(), e),
+        );
+    }
+}
+
+/// Re-emits a `--record` file to `writer` with its original timing, without
+/// watching anything. Changes recorded together are written as one batch,
+/// through the configured output format.
+pub fn run_replay(
+    config: &Config,
+    file: &Path,
+    writer: Box<dyn Write + Send>,
+) -> Result<(), WatchError> {
+    let contents = fs::read_to_string(file).map_err(|e| WatchError::Path(file.to_path_buf(), e))?;
+    let mut records = Vec::new();
+    for (n, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = parse_record(line).ok_or_else(|| {
+            WatchError::Path(
+                file.to_path_buf(),
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: not a recorded change", n + 1),
+                ),
+            )
+        })?;
+        records.push(record);
+    }
+
+    let mut opts = DebounceOptions::from_config(config);
+    // Paths were recorded as printed.
+    opts.roots.clear();
+    opts.record = None;
+    let shutdown = opts.shutdown.clone();
+    let mut batch = Batcher::new(opts, writer);
+    let started = Instant::now();
+    let mut records = records.into_iter().peekable();
+    while let Some((at, change)) = records.next() {
+        while started.elapsed() < at && !shutdown.requested() {
+            poll_wait(at.saturating_sub(started.elapsed()), &shutdown);
+        }
+        if shutdown.requested() {
+            break;
+        }
+        batch.add(change);
+        while let Some((_, change)) = records.next_if(|(t, _)| *t == at) {
+            batch.add(change);
+        }
+        batch.flush();
+        batch.flush_outputs();
+    }
+    batch.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufWriter;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn filter_for(exts: &[&str]) -> Filter {
+        Filter {
+            rules: vec![Rule {
+                extensions: exts.iter().map(|e| e.to_string()).collect(),
+                tag: DEFAULT_TAG.into(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn parse_args_from(args: &[String]) -> Result<Command, WatchError> {
+        parse_args_with_env(args, &|_| None)
+    }
+
+    fn parse_config(args: &[String]) -> Result<Config, WatchError> {
+        match parse_args_from(args)? {
+            Command::Run(config) => Ok(*config),
+            other => panic!("expected a config, got {:?}", other),
+        }
+    }
+
+    type RunFn = fn(&Config, Box<dyn Write + Send>, Arc<AtomicBool>) -> Result<(), WatchError>;
+
+    /// A watcher or poller running on its own thread with a private shutdown
+    /// flag, so tests can drive it without spawning the binary or touching
+    /// the global [`SHUTDOWN`].
+    struct InProcess {
+        out: SharedBuf,
+        flag: Arc<AtomicBool>,
+        handle: thread::JoinHandle<Result<(), WatchError>>,
+    }
+
+    impl InProcess {
+        fn start(config: Config, run: RunFn) -> Self {
+            let out = SharedBuf::default();
+            let flag = Arc::new(AtomicBool::new(false));
+            let writer = Box::new(out.clone());
+            let shutdown = flag.clone();
+            let handle = thread::spawn(move || run(&config, writer, shutdown));
+            InProcess { out, flag, handle }
+        }
+
+        /// Waits up to five seconds for the output to contain `expected`.
+        fn wait_for(&self, expected: &str) -> bool {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while Instant::now() < deadline {
+                if self.out.contents().contains(expected) {
+                    return true;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            false
+        }
+
+        /// Requests shutdown, waits for the run to return, and gives back
+        /// everything it wrote.
+        fn stop(self) -> String {
+            self.flag.store(true, Ordering::SeqCst);
+            self.handle.join().unwrap().unwrap();
+            self.out.contents()
+        }
+    }
+
+    fn change(path: &str) -> Change {
+        Change {
+            path: PathBuf::from(path),
+            tag: DEFAULT_TAG.to_string(),
+            ext: None,
+            target: None,
+            size: None,
+            mtimes: None,
+            kind: None,
+        }
+    }
+
+    impl SharedBuf {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[test]
+    fn builder_applies_cli_defaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder().path(dir.path()).build().unwrap();
+        assert_eq!(config.extensions, vec![".php"]);
+        assert!(!config.poll);
+        assert_eq!(config.poll_interval, Duration::from_millis(500));
+        assert_eq!(config.debounce, Duration::from_millis(300));
+        assert_eq!(config.paths, vec![dir.path().to_path_buf()]);
+    }
+
+    #[test]
+    fn builder_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .extensions(["js", ".css"])
+            .poll(true)
+            .debounce(Duration::from_millis(50))
+            .path(dir.path())
+            .build()
+            .unwrap();
+        assert_eq!(config.extensions, vec![".js", ".css"]);
+        assert!(config.poll);
+        assert_eq!(config.debounce, Duration::from_millis(50));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn builder_sets_control_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("ctl.sock");
+        let config = Config::builder()
+            .control_socket(&socket)
+            .path(dir.path())
+            .build()
+            .unwrap();
+        assert_eq!(config.control_socket, Some(socket));
+    }
+
+    #[test]
+    fn builder_requires_existing_directory() {
+        assert!(matches!(
+            Config::builder().build(),
+            Err(WatchError::NoPaths)
+        ));
+        assert!(matches!(
+            Config::builder()
+                .path("/nonexistent/path/that/does/not/exist")
+                .build(),
+            Err(WatchError::Path(..))
+        ));
+    }
+
+    #[test]
+    fn parse_chdir_must_be_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&["--chdir", root, root])).unwrap();
+        assert_eq!(config.chdir.as_deref(), Some(dir.path()));
+
+        let missing = dir.path().join("missing");
+        assert!(matches!(
+            parse_config(&args(&["--chdir", missing.to_str().unwrap(), root])),
+            Err(WatchError::Path(..))
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn batch_command_runs_in_chdir() {
+        let dir = tempfile::tempdir().unwrap();
+        run_batch_command(
+            &BatchCommand::Shell("pwd > cwd.txt".into()),
+            Some(dir.path()),
+            &[],
+        );
+        let cwd = fs::read_to_string(dir.path().join("cwd.txt")).unwrap();
+        assert_eq!(
+            fs::canonicalize(cwd.trim()).unwrap(),
+            fs::canonicalize(dir.path()).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_on_start_runs_the_batch_command_before_any_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("src");
+        fs::create_dir(&root).unwrap();
+        let config = parse_config(&args(&[
+            root.to_str().unwrap(),
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--batch-command",
+            "tr '\\0' ' ' >> runs.txt; echo >> runs.txt",
+            "--chdir",
+            dir.path().to_str().unwrap(),
+            "--exec-on-start",
+            "--max-events",
+            "1",
+        ]))
+        .unwrap();
+
+        let run = InProcess::start(config, run_poller_with_shutdown);
+        // The start run happens once the initial scan is done.
+        let runs = dir.path().join("runs.txt");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !runs.exists() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::write(root.join("a.php"), "").unwrap();
+        assert!(run.wait_for("a.php"));
+        run.stop();
+
+        // The start run gets no paths, and the first change still counts.
+        let runs = fs::read_to_string(runs).unwrap();
+        let runs: Vec<&str> = runs.lines().collect();
+        assert_eq!(runs.len(), 2, "{:?}", runs);
+        assert_eq!(runs[0], "");
+        assert!(runs[1].ends_with("a.php "), "{:?}", runs);
+
+        let err = parse_config(&args(&[".", "--exec-on-start"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--exec-on-start requires --batch-command or a command after --"
+        );
+    }
+
+    #[test]
+    fn parse_trailing_command_after_double_dash() {
+        let config = parse_config(&args(&[
+            ".",
+            "--ext",
+            "blade.php",
+            "--debounce",
+            "200",
+            "--",
+            "php",
+            "artisan",
+            "view:clear",
+            "--ext",
+            "--",
+            "it's",
+        ]))
+        .unwrap();
+        // Flags before `--` still apply.
+        assert_eq!(config.extensions, vec![".blade.php".to_string()]);
+        assert_eq!(config.debounce, Duration::from_millis(200));
+        assert_eq!(config.paths, vec![PathBuf::from(".")]);
+        // Everything after it is kept verbatim, flag-like words included.
+        assert_eq!(
+            config.batch_argv,
+            ["php", "artisan", "view:clear", "--ext", "--", "it's"]
+        );
+        assert_eq!(
+            BatchCommand::from_config(&config),
+            Some(BatchCommand::Argv(config.batch_argv.clone()))
+        );
+
+        let config = parse_config(&args(&[".", "--"])).unwrap();
+        assert!(config.batch_argv.is_empty());
+        assert_eq!(BatchCommand::from_config(&config), None);
+        assert!(parse_config(&args(&[".", "--", "--help"])).is_ok());
+        let err = parse_config(&args(&[".", "--batch-command", "make", "--", "make"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--batch-command can't be combined with a command after --"
+        );
+
+        // A `--` taken as a flag's value doesn't end the flags.
+        let config = parse_config(&args(&[".", "--label", "--", "--", "make", "-B"])).unwrap();
+        assert_eq!(config.label.as_deref(), Some("--"));
+        assert_eq!(config.batch_argv, ["make", "-B"]);
+        let config = parse_config(&args(&[".", "--label", "--", "--debounce", "0"])).unwrap();
+        assert_eq!(config.label.as_deref(), Some("--"));
+        assert_eq!(config.debounce, Duration::ZERO);
+        assert!(config.batch_argv.is_empty());
+
+        // The builder checks the same combinations.
+        let built = Config::builder()
+            .path(".")
+            .batch_command(Some("make".into()))
+            .batch_argv(vec!["make".into()])
+            .build();
+        assert!(built.is_err());
+        assert!(
+            Config::builder()
+                .path(".")
+                .exec_on_start(true)
+                .build()
+                .is_err()
+        );
+        assert!(
+            Config::builder()
+                .path(".")
+                .batch_argv(vec!["make".into()])
+                .exec_on_start(true)
+                .build()
+                .is_ok()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn batch_argv_runs_without_a_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let command = BatchCommand::Argv(vec![
+            "sh".into(),
+            "-c".into(),
+            "printf '%s\\n' \"$1\" > arg.txt".into(),
+            "sh".into(),
+            "$HOME; a b".into(),
+        ]);
+        let status = run_batch_command(&command, Some(dir.path()), &[]).unwrap();
+        assert!(status.success());
+        let arg = fs::read_to_string(dir.path().join("arg.txt")).unwrap();
+        assert_eq!(arg, "$HOME; a b\n");
+    }
+
+    #[test]
+    fn exec_debounce_merges_batches_into_one_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = BatchRunner::new(
+            BatchCommand::Shell("xargs -0 echo >> runs.txt".into()),
+            Some(dir.path().to_path_buf()),
+            Duration::from_millis(200),
+        );
+        runner.submit(vec![PathBuf::from("a.php")]);
+        thread::sleep(Duration::from_millis(50));
+        runner.submit(vec![PathBuf::from("b.php"), PathBuf::from("a.php")]);
+        thread::sleep(Duration::from_millis(400));
+        runner.submit(vec![PathBuf::from("c.php")]);
+        // Dropping runs what is held without waiting out the delay.
+        drop(runner);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("runs.txt")).unwrap(),
+            "a.php b.php\nc.php\n"
+        );
+
+        let config = parse_config(&args(&["--exec-debounce", "2s", "."])).unwrap();
+        assert_eq!(config.exec_debounce, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn validate_rejects_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("User.php");
+        fs::write(&file, "<?php").unwrap();
+
+        let err = Config::builder().path(&file).build().unwrap_err();
+        assert!(matches!(&err, WatchError::NotADirectory(p) if p == &file));
+        assert_eq!(
+            err.to_string(),
+            format!("{} is not a directory", file.display())
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn watch_limit_error_suggests_fix() {
+        let limit = WatchError::WatchSetupFailed {
+            path: Some(PathBuf::from("/app")),
+            source: notify::Error::new(notify::ErrorKind::MaxFilesWatch),
+        };
+        assert!(limit.to_string().contains("fs.inotify.max_user_watches"));
+
+        let other = WatchError::WatchSetupFailed {
+            path: Some(PathBuf::from("/app")),
+            source: notify::Error::path_not_found(),
+        };
+        assert!(!other.to_string().contains("max_user_watches"));
+    }
+
+    #[test]
+    fn parse_args_errors_are_invalid_arg() {
+        assert!(matches!(
+            parse_args_from(&args(&["--bogus"])),
+            Err(WatchError::InvalidArg(msg)) if msg == "unknown flag: --bogus"
+        ));
+        assert!(matches!(
+            parse_args_from(&args(&["--debounce"])),
+            Err(WatchError::InvalidArg(_))
+        ));
+    }
+
+    #[test]
+    fn parse_log_level() {
+        let level = |extra: &[&str]| {
+            let mut list = vec!["."];
+            list.extend_from_slice(extra);
+            parse_config(&args(&list)).map(|c| c.log_level)
+        };
+        assert_eq!(level(&[]).unwrap(), LogLevel::Warn);
+        assert_eq!(level(&["--log-level", "info"]).unwrap(), LogLevel::Info);
+        assert_eq!(level(&["--debug"]).unwrap(), LogLevel::Debug);
+        assert_eq!(
+            level(&["--log-level", "loud"]).unwrap_err().to_string(),
+            "invalid --log-level 'loud': expected error, warn, info or debug"
+        );
+        assert!(LogLevel::Error < LogLevel::Warn && LogLevel::Info < LogLevel::Debug);
+    }
+
+    #[test]
+    fn parse_extensions_single() {
+        assert_eq!(parse_extensions("php"), vec![".php"]);
+    }
+
+    #[test]
+    fn parse_extensions_multiple() {
+        assert_eq!(
+            parse_extensions("php,blade.php,js"),
+            vec![".php", ".blade.php", ".js"]
+        );
+    }
+
+    #[test]
+    fn parse_extensions_with_dots() {
+        assert_eq!(parse_extensions(".php,.js"), vec![".php", ".js"]);
+    }
+
+    #[test]
+    fn parse_extensions_whitespace() {
+        assert_eq!(parse_extensions(" php , js "), vec![".php", ".js"]);
+    }
+
+    #[test]
+    fn parse_extensions_empty() {
+        let result: Vec<String> = parse_extensions("");
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn parse_extensions_trailing_comma() {
+        assert_eq!(parse_extensions("php,"), vec![".php"]);
+    }
+
+    #[test]
+    fn parse_extensions_keeps_globs() {
+        assert_eq!(
+            parse_extensions("*.blade.php,config.*,js"),
+            vec!["*.blade.php", "config.*", ".js"]
+        );
+    }
+
+    #[test]
+    fn debouncer_leading_edge_flushes_first_event_immediately() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(500),
+            leading: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("first.php"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(buf.contents(), "changed: first.php\n");
+
+        debouncer.send(change("second.php"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(buf.contents(), "changed: first.php\n");
+
+        debouncer.shutdown();
+        assert_eq!(buf.contents(), "changed: first.php\nchanged: second.php\n");
+    }
+
+    #[test]
+    fn debouncer_per_extension_windows_flush_independently() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(400),
+            per_ext: vec![(".css".to_string(), Duration::from_millis(20))],
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("app.php"));
+        debouncer.send(change("app.css"));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(buf.contents(), "changed: app.css\n");
+        debouncer.shutdown();
+        assert_eq!(buf.contents(), "changed: app.css\nchanged: app.php\n");
+    }
+
+    #[test]
+    fn debouncer_per_ext_mode_keeps_busy_extension_from_delaying_others() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(100),
+            mode: DebounceMode::PerExt,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        let with_ext = |path: &str, ext: &str| Change {
+            ext: Some(ext.into()),
+            ..change(path)
+        };
+
+        debouncer.send(with_ext("app.php", ".php"));
+        // Keep `.log` busy for well past the `.php` window.
+        for _ in 0..8 {
+            debouncer.send(with_ext("app.log", ".log"));
+            thread::sleep(Duration::from_millis(40));
+        }
+        assert_eq!(buf.contents(), "changed: app.php\n");
+        debouncer.shutdown();
+        assert_eq!(buf.contents(), "changed: app.php\nchanged: app.log\n");
+
+        let config = parse_config(&args(&["--debounce-mode", "per-ext", "."])).unwrap();
+        assert_eq!(config.debounce_mode, DebounceMode::PerExt);
+        assert!(parse_config(&args(&["--debounce-mode", "per-file", "."])).is_err());
+    }
+
+    #[test]
+    fn debouncer_groups_by_rule_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(100),
+            rule_commands: vec![
+                ("backend".into(), "xargs -0 echo >> backend.txt".into()),
+                ("frontend".into(), "xargs -0 echo >> frontend.txt".into()),
+            ],
+            group_by_command: true,
+            chdir: Some(dir.path().to_path_buf()),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        let tagged = |path: &str, tag: &str| Change {
+            tag: tag.into(),
+            ..change(path)
+        };
+
+        debouncer.send(tagged("User.php", "backend"));
+        // Keep the frontend rule busy for well past the backend window.
+        for _ in 0..8 {
+            debouncer.send(tagged("app.css", "frontend"));
+            thread::sleep(Duration::from_millis(40));
+        }
+        assert_eq!(buf.contents(), "backend: User.php\n");
+        debouncer.shutdown();
+        assert_eq!(buf.contents(), "backend: User.php\nfrontend: app.css\n");
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("backend.txt"), "User.php\n");
+        assert_eq!(read("frontend.txt"), "app.css\n");
+
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            "--rule",
+            "php:backend",
+            "--rule-command",
+            "backend=make lint",
+            "--debounce-group-by-command",
+            root,
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.rule_commands,
+            vec![("backend".to_string(), "make lint".to_string())]
+        );
+        assert!(config.debounce_group_by_command);
+        assert!(parse_config(&args(&["--rule-command", "backend", root])).is_err());
+        let err = parse_config(&args(&["--rule-command", "backend=true", root])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--rule-command: no --rule is tagged 'backend'"
+        );
+    }
+
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size_str("0").unwrap(), 0);
+        assert_eq!(parse_size_str("1500").unwrap(), 1500);
+        assert_eq!(parse_size_str("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size_str("1m").unwrap(), 1024 * 1024);
+        assert_eq!(parse_size_str("1.5M").unwrap(), 1024 * 1024 * 3 / 2);
+        assert_eq!(parse_size_str("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+
+        for bad in ["", "M", "1.5", "-1M", "1X", "1MB", "ten", "NaNM"] {
+            assert!(parse_size_str(bad).is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn filter_skips_files_over_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = dir.path().join("small.php");
+        let large = dir.path().join("bundle.php");
+        fs::write(&small, "<?php").unwrap();
+        fs::write(&large, vec![b'x'; 2048]).unwrap();
+
+        let config =
+            parse_config(&args(&["--max-size", "1K", dir.path().to_str().unwrap()])).unwrap();
+        let filter = Filter::from_config(&config);
+        assert!(filter.matches(&small));
+        assert!(!filter.matches(&large));
+        // Gone files (removals) are not held to the limit.
+        assert!(filter.matches(&dir.path().join("deleted.php")));
+    }
+
+    #[test]
+    fn parse_debounce_per_extension() {
+        let config = parse_config(&args(&["--debounce", "php=1s, .css=50ms,200ms", "."])).unwrap();
+        assert_eq!(config.debounce, Duration::from_millis(200));
+        assert_eq!(
+            config.debounce_per_ext,
+            vec![
+                (".php".to_string(), Duration::from_secs(1)),
+                (".css".to_string(), Duration::from_millis(50)),
+            ]
+        );
+
+        let config = parse_config(&args(&["--debounce", "css=0", "."])).unwrap();
+        assert_eq!(config.debounce, Duration::from_millis(300));
+        assert!(parse_config(&args(&["--debounce", "=50ms", "."])).is_err());
+        assert!(parse_config(&args(&["--debounce", "css=fast", "."])).is_err());
+    }
+
+    #[test]
+    fn watch_retry_delay_doubles_up_to_cap() {
+        assert_eq!(watch_retry_delay(0), Duration::from_millis(100));
+        assert_eq!(watch_retry_delay(1), Duration::from_millis(200));
+        assert_eq!(watch_retry_delay(3), Duration::from_millis(800));
+        assert_eq!(watch_retry_delay(10), Duration::from_secs(5));
+        assert_eq!(watch_retry_delay(40), Duration::from_secs(5));
+
+        let config = parse_config(&args(&["--watch-retries", "3", "."])).unwrap();
+        assert_eq!(config.watch_retries, 3);
+        assert!(parse_config(&args(&["--watch-retries", "-1", "."])).is_err());
+    }
+
+    #[test]
+    fn debouncer_flush_every_batches_on_schedule() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::ZERO,
+            flush_every: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(50));
+        debouncer.send(change("b.php"));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(buf.contents(), "", "flushed before the tick");
+
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(buf.contents(), "changed: a.php\nchanged: b.php\n");
+
+        // Nothing pending, so the next ticks write nothing.
+        thread::sleep(Duration::from_millis(450));
+        debouncer.send(change("c.php"));
+        debouncer.shutdown();
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\nchanged: b.php\nchanged: c.php\n"
+        );
+    }
+
+    #[test]
+    fn poller_runs_in_process_with_its_own_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.php"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            root,
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--process-existing",
+            "--relative",
+        ]))
+        .unwrap();
+
+        let run = InProcess::start(config, run_poller_with_shutdown);
+        assert!(run.wait_for("a.php"));
+        fs::write(dir.path().join("b.php"), "").unwrap();
+        assert!(run.wait_for("b.php"));
+        assert_eq!(run.stop(), "changed: a.php\nchanged: b.php\n");
+    }
+
+    #[test]
+    fn poller_since_reports_files_modified_after_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = fs::File::create(dir.path().join("old.php")).unwrap();
+        old.set_modified(SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        fs::write(dir.path().join("new.php"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            root,
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--since",
+            "1h",
+            "--relative",
+        ]))
+        .unwrap();
+
+        let run = InProcess::start(config, run_poller_with_shutdown);
+        assert!(run.wait_for("new.php"));
+        assert_eq!(run.stop(), "changed: new.php\n");
+    }
+
+    #[test]
+    fn parse_duration_str_rejects_out_of_range_values() {
+        assert_eq!(
+            parse_duration_str("1.5s").unwrap(),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(parse_duration_str("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration_str("1h").unwrap(), Duration::from_secs(3600));
+        for bad in [
+            "-1h", "-1s", "-0.5m", "NaNm", "NaNs", "infh", "1e300h", "1e300s",
+        ] {
+            let err = parse_duration_str(bad).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("invalid duration '{}': ", bad)),
+                "{}",
+                err
+            );
+        }
+        assert!(parse_config(&args(&["--debounce", "NaNm", "."])).is_err());
+        assert!(parse_config(&args(&[".", "--poll", "--since", "-1h"])).is_err());
+        assert!(parse_config(&args(&[".", "--poll", "--since", "1e300h"])).is_err());
+    }
+
+    #[test]
+    fn parse_rfc3339_reads_utc_and_offsets() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(parse_rfc3339("2024-02-29T12:34:56Z"), Some(t));
+        assert_eq!(parse_rfc3339("2024-02-29T14:34:56+02:00"), Some(t));
+        assert_eq!(parse_rfc3339("2024-02-29T07:34:56-05:00"), Some(t));
+        assert_eq!(
+            parse_rfc3339("2024-02-29T12:34:56.25Z"),
+            Some(t + Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_rfc3339(&format_rfc3339(t)).map(format_rfc3339),
+            Some("2024-02-29T12:34:56Z".to_string())
+        );
+        for bad in [
+            "2024-02-29",
+            "2024-02-29T12:34:56",
+            "2024-13-01T00:00:00Z",
+            "2024-02-29 12:34:56Z",
+            "1969-12-31T23:59:59Z",
+            "2024-02-30T00:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "2024-04-31T00:00:00Z",
+            "2024-02-29T00:00:00+24:00",
+            "2024-02-29T00:00:00+99:99",
+            "2024-02-29T00:00:00-05:60",
+        ] {
+            assert_eq!(parse_rfc3339(bad), None, "{}", bad);
+        }
+
+        assert_eq!(
+            parse_rfc3339("2000-02-29T23:59:59+23:59"),
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400 + 59))
+        );
+
+        let since = |extra: &[&str]| {
+            let mut argv = args(&[".", "--poll"]);
+            argv.extend(args(extra));
+            parse_config(&argv)
+        };
+        assert_eq!(
+            since(&["--since", "2024-02-29T12:34:56Z"]).unwrap().since,
+            Some(t)
+        );
+        assert!(since(&["--since", "1h"]).is_ok());
+        assert!(since(&["--since", "yesterday"]).is_err());
+        let err = since(&["--since", "1h", "--no-initial-scan"]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--since can't be combined with --no-initial-scan"
+        );
+        // Only the poller has an initial scan to report from.
+        let err = parse_config(&args(&[".", "--since", "1h"])).unwrap_err();
+        assert_eq!(err.to_string(), "--since requires --poll");
+    }
+
+    #[test]
+    fn dedup_roots_drops_nested_and_repeated_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("app/Models")).unwrap();
+        fs::create_dir(dir.path().join("routes")).unwrap();
+        let root = dir.path().to_str().unwrap();
+        let app = dir.path().join("app");
+        let models = dir.path().join("app/Models");
+        let routes = dir.path().join("routes");
+        let list = [
+            models.to_str().unwrap(),
+            app.to_str().unwrap(),
+            routes.to_str().unwrap(),
+            app.to_str().unwrap(),
+        ];
+
+        // Only warned about without the flag.
+        let config = parse_config(&args(&list)).unwrap();
+        assert_eq!(config.paths.len(), 4);
+
+        let mut with_flag = list.to_vec();
+        with_flag.push("--dedup-roots");
+        let config = parse_config(&args(&with_flag)).unwrap();
+        assert_eq!(config.paths, vec![app.clone(), routes]);
+
+        // Watching the root and a subdirectory reports a file once.
+        fs::write(app.join("a.php"), "").unwrap();
+        let config = parse_config(&args(&[
+            app.to_str().unwrap(),
+            root,
+            "--dedup-roots",
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--process-existing",
+            "--relative",
+        ]))
+        .unwrap();
+        assert_eq!(config.paths, vec![PathBuf::from(root)]);
+        let run = InProcess::start(config, run_poller_with_shutdown);
+        assert!(run.wait_for("a.php"));
+        assert_eq!(run.stop(), "changed: app/a.php\n");
+    }
+
+    #[test]
+    fn shutdown_flags_stop_only_their_own_run() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let mut runs: Vec<InProcess> = dirs
+            .iter()
+            .map(|dir| {
+                fs::write(dir.path().join("a.php"), "").unwrap();
+                let config = parse_config(&args(&[
+                    dir.path().to_str().unwrap(),
+                    "--ext",
+                    "php",
+                    "--poll",
+                    "--poll-interval",
+                    "20ms",
+                    "--debounce",
+                    "0",
+                    "--process-existing",
+                    "--relative",
+                ]))
+                .unwrap();
+                InProcess::start(config, run_poller_with_shutdown)
+            })
+            .collect();
+        assert!(runs.iter().all(|run| run.wait_for("a.php")));
+
+        let second = runs.pop().unwrap();
+        assert_eq!(runs.pop().unwrap().stop(), "changed: a.php\n");
+        fs::write(dirs[1].path().join("b.php"), "").unwrap();
+        assert!(second.wait_for("b.php"));
+        second.stop();
+    }
+
+    #[test]
+    fn filter_callback_drops_rejected_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.php"), "").unwrap();
+        fs::write(dir.path().join("skip-a.php"), "").unwrap();
+        let config = parse_config(&args(&[
+            dir.path().to_str().unwrap(),
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--process-existing",
+            "--relative",
+        ]))
+        .unwrap();
+        let run = InProcess::start(config, |config, writer, flag| {
+            let accept = |path: &Path, kind: ChangeKind| match kind {
+                ChangeKind::Created if path.ends_with("b.php") => false,
+                _ => !path.ends_with("skip-a.php"),
+            };
+            run_poller_with(config, writer, Shutdown::Flag(flag), &accept)
+        });
+        assert!(run.wait_for("a.php"));
+        fs::write(dir.path().join("b.php"), "").unwrap();
+        fs::write(dir.path().join("skip-a.php"), "changed").unwrap();
+        fs::write(dir.path().join("c.php"), "").unwrap();
+        assert!(run.wait_for("c.php"));
+        // b.php is only reported once it is modified rather than created.
+        fs::write(dir.path().join("b.php"), "changed").unwrap();
+        assert!(run.wait_for("b.php"));
+        assert_eq!(
+            run.stop(),
+            "changed: a.php\nchanged: c.php\nchanged: b.php\n"
+        );
+    }
+
+    #[test]
+    fn ready_marker_precedes_the_first_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.php"), "").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = |extra: &[&str]| {
+            let mut argv = args(&[
+                root,
+                "--ext",
+                "php",
+                "--poll",
+                "--poll-interval",
+                "20ms",
+                "--debounce",
+                "0",
+                "--process-existing",
+                "--relative",
+                "--ready-marker",
+            ]);
+            argv.extend(args(extra));
+            parse_config(&argv).unwrap()
+        };
+
+        let run = InProcess::start(config(&[]), run_poller_with_shutdown);
+        assert!(run.wait_for("a.php"));
+        assert_eq!(run.stop(), "ready\nchanged: a.php\n");
+
+        let run = InProcess::start(
+            config(&["--format", "json-batch"]),
+            run_poller_with_shutdown,
+        );
+        assert!(run.wait_for("a.php"));
+        assert!(run.stop().starts_with("{\"event\":\"ready\"}\n"));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn watcher_runs_in_process_with_its_own_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        // A root inside a dot-directory is watched like any other.
+        let app = dir.path().join(".config/app");
+        fs::create_dir_all(&app).unwrap();
+        fs::write(app.join("a.php"), "").unwrap();
+        let root = app.to_str().unwrap();
+        let config = parse_config(&args(&[
+            root,
+            "--ext",
+            "php",
+            "--debounce",
+            "0",
+            "--process-existing",
+            "--relative",
+        ]))
+        .unwrap();
+
+        // The watch is in place before existing files are reported.
+        let run = InProcess::start(config, run_watcher_with_shutdown);
+        assert!(run.wait_for("changed: a.php\n"));
+        fs::write(app.join("b.php"), "x").unwrap();
+        assert!(run.wait_for("changed: b.php\n"));
+        run.stop();
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn watcher_checks_callers_flag_every_shutdown_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::builder()
+            .path(dir.path())
+            .ready_marker(true)
+            .shutdown_check(Duration::from_millis(300))
+            .build()
+            .unwrap();
+
+        let run = InProcess::start(config, run_watcher_with_shutdown);
+        assert!(run.wait_for("ready\n"));
+        let started = Instant::now();
+        run.stop();
+        let waited = started.elapsed();
+        assert!(waited < Duration::from_millis(800), "{:?}", waited);
+
+        let flag = Shutdown::Flag(Arc::default());
+        assert_eq!(flag.check_every(Duration::ZERO), Some(MIN_SHUTDOWN_CHECK));
+        assert_eq!(Shutdown::Global.check_every(Duration::ZERO), None);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn watcher_runs_on_notify_poll_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = dir.path().join(".cache/app");
+        fs::create_dir_all(&app).unwrap();
+        let root = app.to_str().unwrap();
+        let config = parse_config(&args(&[
+            root,
+            "--ext",
+            "php",
+            "--debounce",
+            "0",
+            "--relative",
+            "--backend",
+            "poll",
+            "--poll-interval",
+            "50ms",
+            "--ready-marker",
+        ]))
+        .unwrap();
+        assert_eq!(config.backend, Backend::Poll);
+
+        let run = InProcess::start(config, run_watcher_with_shutdown);
+        assert!(run.wait_for("ready\n"));
+        fs::write(app.join("b.php"), "x").unwrap();
+        assert!(run.wait_for("changed: b.php\n"));
+        run.stop();
+
+        let err = parse_config(&args(&[root, "--backend", "kqueue"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid --backend 'kqueue': expected native or poll"
+        );
+    }
+
+    #[test]
+    fn output_sinks_get_their_own_format() {
+        let config = parse_config(&args(&[
+            ".",
+            "--ext",
+            "php",
+            "--output",
+            "-",
+            "--output",
+            "json:events.jsonl",
+            "--output",
+            "other.log",
+            "--output",
+            "c:\\out.log",
+        ]))
+        .unwrap();
+        assert_eq!(config.output, None);
+        assert_eq!(
+            config.sinks,
+            vec![
+                Sink {
+                    format: OutputFormat::JsonBatch,
+                    output: Some(PathBuf::from("events.jsonl")),
+                },
+                Sink {
+                    format: OutputFormat::Text,
+                    output: Some(PathBuf::from("other.log")),
+                },
+                Sink {
+                    format: OutputFormat::Text,
+                    output: Some(PathBuf::from("c:\\out.log")),
+                },
+            ]
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let events = dir.path().join("events.jsonl");
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::ZERO,
+            sinks: vec![
+                Sink {
+                    format: OutputFormat::JsonBatch,
+                    output: Some(events.clone()),
+                },
+                // Can't be opened; the other outputs carry on without it.
+                Sink {
+                    format: OutputFormat::Text,
+                    output: Some(dir.path().join("missing/out.log")),
+                },
+            ],
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        debouncer.send(change("a.php"));
+        debouncer.shutdown();
+
+        assert_eq!(buf.contents(), "changed: a.php\n");
+        assert_eq!(
+            fs::read_to_string(&events).unwrap(),
+            "{\"event\":\"batch\",\"paths\":[\"a.php\"],\"exts\":[null],\"count\":1}\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_shutdown_gives_up_on_a_stuck_writer() {
+        /// Blocks every write until its sender is dropped.
+        struct Stuck(mpsc::Receiver<()>);
+
+        impl Write for Stuck {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let _ = self.0.recv();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let (release, rx) = mpsc::channel();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            shutdown_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(Stuck(rx)));
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(50));
+
+        let started = Instant::now();
+        // The change was handed to the writer, so it counts.
+        assert_eq!(debouncer.shutdown(), 1);
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(100), "{:?}", waited);
+        assert!(waited < Duration::from_secs(1), "{:?}", waited);
+        drop(release);
+    }
+
+    #[test]
+    fn debouncer_flush_cooldown_spaces_batches() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            flush_cooldown: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(buf.contents(), "changed: a.php\n");
+
+        // Both quiet long before the cooldown ends, then land as one batch.
+        debouncer.send(change("b.php"));
+        thread::sleep(Duration::from_millis(50));
+        debouncer.send(change("c.php"));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\n",
+            "flushed during the cooldown"
+        );
+
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\nchanged: b.php\nchanged: c.php\n"
+        );
+        debouncer.shutdown();
+    }
+
+    #[test]
+    fn record_line_round_trip() {
+        let plain = change("app/\"q\".php");
+        let line = record_line(1200, &plain);
+        assert_eq!(
+            line,
+            "{\"ms\":1200,\"kind\":\"changed\",\"path\":\"app/\\\"q\\\".php\"}"
+        );
+        assert_eq!(
+            parse_record(&line),
+            Some((Duration::from_millis(1200), plain))
+        );
+
+        let link = Change {
+            tag: "symlink-changed".into(),
+            ext: Some(".php".into()),
+            target: Some(PathBuf::from("releases/2")),
+            size: None,
+            mtimes: None,
+            kind: None,
+            ..change("current")
+        };
+        assert_eq!(
+            parse_record(&record_line(5, &link)),
+            Some((Duration::from_millis(5), link))
+        );
+        assert!(parse_record("{\"ms\":\"soon\",\"kind\":\"changed\",\"path\":\"a\"}").is_none());
+        assert!(parse_record("{\"ms\":1,\"path\":\"a\"}").is_none());
+        assert!(parse_record("{\"ms\":1-,\"kind\":\"changed\",\"path\":\"a\"}").is_none());
+    }
+
+    #[test]
+    fn jitter_stays_below_max() {
+        let mut jitter = Jitter::new(Duration::from_millis(50));
+        let offsets: Vec<Duration> = (0..100).map(|_| jitter.next()).collect();
+        assert!(offsets.iter().all(|d| *d < Duration::from_millis(50)));
+        assert!(offsets.iter().any(|d| *d != offsets[0]));
+
+        let mut none = Jitter::new(Duration::ZERO);
+        assert_eq!(none.next(), Duration::ZERO);
+    }
+
+    #[test]
+    fn debouncer_dedup_window_suppresses_repeats() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            dedup_window: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(100));
+        debouncer.send(change("a.php"));
+        debouncer.send(change("b.php"));
+        debouncer.shutdown();
+
+        assert_eq!(buf.contents(), "changed: a.php\nchanged: b.php\n");
+    }
+
+    #[test]
+    fn debouncer_coalesce_transient_drops_created_then_removed() {
+        let kind = |path: &str, kind| Change {
+            kind: Some(kind),
+            ..change(path)
+        };
+        for coalesce in [false, true] {
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(50),
+                coalesce_transient: coalesce,
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            // An editor's swap file, there and gone within the window.
+            debouncer.send(kind(".app.php.swp", ChangeKind::Created));
+            debouncer.send(kind(".app.php.swp", ChangeKind::Modified));
+            debouncer.send(kind(".app.php.swp", ChangeKind::Removed));
+            // Existing files changed or removed are still reported.
+            debouncer.send(kind("app.php", ChangeKind::Modified));
+            debouncer.send(kind("old.php", ChangeKind::Removed));
+            // Removed and recreated is a change.
+            debouncer.send(kind("new.php", ChangeKind::Removed));
+            debouncer.send(kind("new.php", ChangeKind::Created));
+            debouncer.shutdown();
+
+            let swap = if coalesce {
+                ""
+            } else {
+                "changed: .app.php.swp\n"
+            };
+            assert_eq!(
+                buf.contents(),
+                format!(
+                    "{}changed: app.php\nchanged: new.php\nchanged: old.php\n",
+                    swap
+                )
+            );
+        }
+
+        // With `--verbose-events` each event has its own tag.
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(50),
+            coalesce_transient: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        let verbose = |path: &str, tag: &str, kind| Change {
+            tag: tag.to_string(),
+            kind: Some(kind),
+            ..change(path)
+        };
+        debouncer.send(verbose(".app.php.swp", "Create(File)", ChangeKind::Created));
+        debouncer.send(verbose(".app.php.swp", "Remove(File)", ChangeKind::Removed));
+        debouncer.send(verbose(
+            "app.php",
+            "Modify(Data(Content))",
+            ChangeKind::Modified,
+        ));
+        debouncer.shutdown();
+        assert_eq!(buf.contents(), "Modify(Data(Content)): app.php\n");
+    }
+
+    #[test]
+    fn debouncer_batches_other_entry_types() {
+        /// A build job, deduplicated on its target alone.
+        #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+        struct Job {
+            target: &'static str,
+            reason: &'static str,
+        }
+
+        impl Batched for Job {
+            fn key(&self, _opts: &DebounceOptions) -> Job {
+                Job {
+                    reason: "",
+                    ..self.clone()
+                }
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut debouncer =
+            Debouncer::with_formatter(opts, Box::new(buf.clone()), |job: &Job| Change {
+                tag: format!("build ({})", job.reason),
+                ..change(job.target)
+            });
+        debouncer.send(Job {
+            target: "assets",
+            reason: "css",
+        });
+        debouncer.send(Job {
+            target: "app",
+            reason: "php",
+        });
+        debouncer.send(Job {
+            target: "assets",
+            reason: "js",
+        });
+        thread::sleep(Duration::from_millis(100));
+        debouncer.send(Job {
+            target: "app",
+            reason: "routes",
+        });
+
+        assert_eq!(debouncer.shutdown(), 3);
+        // One line per target in each batch, with the first reason seen.
+        assert_eq!(
+            buf.contents(),
+            "build (php): app\nbuild (css): assets\nbuild (routes): app\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_ignore_case_keeps_first_spelling() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            ignore_case: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("app/App.php"));
+        debouncer.send(change("app/app.php"));
+        debouncer.shutdown();
+
+        assert_eq!(buf.contents(), "changed: app/App.php\n");
+    }
+
+    #[test]
+    fn debouncer_relative_uses_longest_root() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            roots: vec![
+                PathBuf::from("/srv/app/vendor-src"),
+                PathBuf::from("/srv/app"),
+            ],
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("/srv/app/vendor-src/lib.php"));
+        thread::sleep(Duration::from_millis(100));
+        debouncer.send(change("/srv/app/Models/User.php"));
+        thread::sleep(Duration::from_millis(100));
+        debouncer.send(change("/elsewhere/x.php"));
+        debouncer.shutdown();
+
+        assert_eq!(
+            buf.contents(),
+            "changed: lib.php\nchanged: Models/User.php\nchanged: /elsewhere/x.php\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_relative_to_requires_absolute_base() {
+        let config = parse_config(&args(&["/srv", "--relative-to", "/srv/shared"])).unwrap();
+        assert_eq!(config.relative_to, Some(PathBuf::from("/srv/shared")));
+        // A base that doesn't exist is used as given.
+        let opts = DebounceOptions::from_config(&config);
+        assert_eq!(opts.roots, vec![PathBuf::from("/srv/shared")]);
+        assert_eq!(
+            opts.printed_path(PathBuf::from("/srv/shared/app/a.php")),
+            PathBuf::from("app/a.php")
+        );
+        assert_eq!(
+            opts.printed_path(PathBuf::from("/srv/other/b.php")),
+            PathBuf::from("/srv/other/b.php")
+        );
+
+        let err = parse_config(&args(&[".", "--relative-to", "shared"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--relative-to must be an absolute path: shared"
+        );
+        assert!(parse_config(&args(&["--relative-to"])).is_err());
+        let err = parse_config(&args(&[".", "--relative", "--relative-to", "/srv"])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--relative can't be combined with --relative-to"
+        );
+        let built = Config::builder()
+            .path(".")
+            .relative(true)
+            .relative_to("/srv")
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn canonical_path_resolves_links_and_deleted_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(real.join("src")).unwrap();
+        fs::write(real.join("src/a.php"), "").unwrap();
+        std::os::unix::fs::symlink(real.join("src"), real.join("link")).unwrap();
+
+        let via_link = dir.path().join("link/a.php");
+        assert_eq!(canonical_path(via_link), real.join("src/a.php"));
+        let gone = dir.path().join("link/gone.php");
+        assert_eq!(canonical_path(gone), real.join("src/gone.php"));
+        let missing = PathBuf::from("/no/such/dir/x.php");
+        assert_eq!(canonical_path(missing.clone()), missing);
+    }
+
+    #[test]
+    fn debouncer_with_size_reads_size_at_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.php");
+        let gone = dir.path().join("gone.php");
+        let root = dir.path().to_path_buf();
+
+        for format in [
+            OutputFormat::Text,
+            OutputFormat::JsonBatch,
+            OutputFormat::Print0,
+        ] {
+            fs::write(&file, "x").unwrap();
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(50),
+                with_size: true,
+                format,
+                roots: vec![root.clone()],
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            debouncer.send(change(file.to_str().unwrap()));
+            debouncer.send(change(gone.to_str().unwrap()));
+            // Grows within the window; the flushed size is the final one.
+            fs::write(&file, "<?php\n").unwrap();
+            debouncer.shutdown();
+
+            let expected = match format {
+                OutputFormat::Text => "changed: a.php (6 bytes)\nchanged: gone.php\n",
+                OutputFormat::JsonBatch => {
+                    "{\"event\":\"batch\",\"paths\":[\"a.php\",\"gone.php\"],\"exts\":[null,null],\"sizes\":[6,null],\"count\":2}\n"
+                }
+                // Bare paths only.
+                OutputFormat::Print0 => "a.php\0gone.php\0",
+                OutputFormat::Csv => unreachable!(),
+            };
+            assert_eq!(buf.contents(), expected);
+        }
+    }
+
+    #[test]
+    fn debouncer_with_mtime_spans_coalesced_polls() {
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let modified = |first, second| Change {
+            mtimes: Some((at(first), at(second))),
+            ..change("app.php")
+        };
+        for format in [OutputFormat::Text, OutputFormat::JsonBatch] {
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(50),
+                with_mtime: true,
+                format,
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            debouncer.send(modified(1_700_000_000, 1_700_000_010));
+            debouncer.send(modified(1_700_000_010, 1_700_000_042));
+            debouncer.send(change("new.php"));
+            debouncer.shutdown();
+
+            let expected = match format {
+                OutputFormat::Text => {
+                    "changed: app.php (1700000000 -> 1700000042)\nchanged: new.php\n"
+                }
+                _ => {
+                    "{\"event\":\"batch\",\"paths\":[\"app.php\",\"new.php\"],\"exts\":[null,null],\"old_mtimes\":[1700000000,null],\"new_mtimes\":[1700000042,null],\"count\":2}\n"
+                }
+            };
+            assert_eq!(buf.contents(), expected);
+        }
+    }
+
+    #[test]
+    fn debouncer_labels_every_line() {
+        for (format, expected) in [
+            (
+                OutputFormat::Text,
+                "[backend] ready\n[backend] changed: a.php\n[backend] --- 1 file changed ---\n",
+            ),
+            (
+                OutputFormat::JsonBatch,
+                "{\"event\":\"ready\",\"watcher\":\"backend\"}\n\
+                 {\"event\":\"batch\",\"paths\":[\"a.php\"],\"exts\":[\".php\"],\"count\":1,\"watcher\":\"backend\"}\n\
+                 {\"event\":\"summary\",\"count\":1,\"watcher\":\"backend\"}\n",
+            ),
+        ] {
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                format,
+                summary: true,
+                label: Some("backend".into()),
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            debouncer.ready();
+            debouncer.send(Change {
+                ext: Some(".php".into()),
+                ..change("a.php")
+            });
+            debouncer.shutdown();
+            assert_eq!(buf.contents(), expected);
+        }
+    }
+
+    #[test]
+    fn debouncer_collapses_dense_dirs() {
+        for (format, expected) in [
+            (
+                OutputFormat::Text,
+                "changed: app.php\nchanged-dir: build/ (3 files)\nchanged: build/sub/x.php\n",
+            ),
+            (
+                OutputFormat::JsonBatch,
+                "{\"event\":\"changed-dir\",\"path\":\"build/\",\"collapsed\":true,\"count\":3}\n\
+                 {\"event\":\"batch\",\"paths\":[\"app.php\",\"build/sub/x.php\"],\"exts\":[null,null],\"count\":2}\n",
+            ),
+        ] {
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(50),
+                collapse_threshold: 2,
+                format,
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            for path in [
+                "app.php",
+                "build/a.php",
+                "build/b.php",
+                "build/c.php",
+                "build/sub/x.php",
+            ] {
+                debouncer.send(change(path));
+            }
+            debouncer.shutdown();
+            assert_eq!(buf.contents(), expected);
+        }
+    }
+
+    #[test]
+    fn debouncer_group_by_dir() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            group_by_dir: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("app/Models/User.php"));
+        debouncer.send(change("app/Http/Kernel.php"));
+        debouncer.send(change("app/Models/Post.php"));
+        debouncer.send(change("routes.php"));
+        debouncer.shutdown();
+
+        assert_eq!(
+            buf.contents(),
+            ".:\n  routes.php\napp/Http:\n  Kernel.php\napp/Models:\n  Post.php\n  User.php\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_json_group_by_dir() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            format: OutputFormat::JsonBatch,
+            group_by_dir: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("app/Models/User.php"));
+        debouncer.send(change("app/Models/Post.php"));
+        debouncer.shutdown();
+
+        assert_eq!(
+            buf.contents(),
+            "{\"event\":\"batch\",\"dirs\":{\"app/Models\":[\"Post.php\",\"User.php\"]},\"count\":2}\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_zero_debounce_writes_immediately() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(BufWriter::new(buf.clone())));
+        assert!(debouncer.handle.is_none());
+
+        debouncer.send(change("a.php"));
+        assert_eq!(buf.contents(), "changed: a.php\n");
+        debouncer.send(change("b.php"));
+        assert_eq!(buf.contents(), "changed: a.php\nchanged: b.php\n");
+        assert_eq!(debouncer.shutdown(), 2);
+    }
+
+    #[test]
+    fn debouncer_sorts_each_batch() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        for name in ["d.php", "b.php", "a.php", "c.php", "app/z.php"] {
+            debouncer.send(change(name));
+        }
+        debouncer.shutdown();
+
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\nchanged: app/z.php\nchanged: b.php\nchanged: c.php\nchanged: d.php\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_throttle_trickles_backlog() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            throttle: 10,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        for i in 0..12 {
+            debouncer.send(change(&format!("{:02}.php", i)));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(buf.contents().lines().count(), 10);
+
+        let started = Instant::now();
+        debouncer.shutdown();
+        assert_eq!(buf.contents().lines().count(), 12);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn debouncer_throttle_drain_fits_the_shutdown_timeout() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            throttle: 2,
+            shutdown_timeout: Duration::from_millis(400),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        for i in 0..12 {
+            debouncer.send(change(&format!("{:02}.php", i)));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(buf.contents().lines().count(), 2);
+
+        // Trickled for half the timeout, then the rest is written at once.
+        let started = Instant::now();
+        assert_eq!(debouncer.shutdown(), 12);
+        assert_eq!(buf.contents().lines().count(), 12);
+        assert!(started.elapsed() < Duration::from_millis(400));
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn rate_alert_pauses_until_a_quiet_window() {
+        let config = parse_config(&args(&[
+            ".",
+            "--debounce",
+            "20ms",
+            "--alert-rate",
+            "2",
+            "--alert-pause",
+        ]))
+        .unwrap();
+        let mut alert = RateAlert::new(&config);
+        for _ in 0..3 {
+            alert.event();
+        }
+        // Judged once the window is over.
+        assert!(!alert.paused());
+        thread::sleep(Duration::from_millis(25));
+        alert.tick();
+        assert!(alert.paused());
+
+        // Two events is not over the limit.
+        alert.event();
+        alert.event();
+        thread::sleep(Duration::from_millis(25));
+        alert.tick();
+        assert!(!alert.paused());
+
+        // Without --alert-pause it only warns.
+        let config = Config {
+            alert_pause: false,
+            ..config
+        };
+        let mut alert = RateAlert::new(&config);
+        for _ in 0..3 {
+            alert.event();
+        }
+        thread::sleep(Duration::from_millis(25));
+        alert.tick();
+        assert!(!alert.paused());
+        assert_eq!(RateAlert::new(&Config::default()).due_in(), None);
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn size_coalescer_drops_same_size_repeats_within_the_window() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("app.php");
+        fs::write(&file, "<?php").unwrap();
+        let config = parse_config(&args(&[
+            dir.path().to_str().unwrap(),
+            "--debounce",
+            "50ms",
+            "--coalesce-by-size",
+        ]))
+        .unwrap();
+        let mut sizes = SizeCoalescer::new(&config);
+        let burst = |sizes: &mut SizeCoalescer| {
+            let mut burst = vec![change(file.to_str().unwrap())];
+            sizes.filter(&mut burst);
+            burst.len()
+        };
+
+        assert_eq!(burst(&mut sizes), 1);
+        assert_eq!(burst(&mut sizes), 0);
+        fs::write(&file, "<?php echo 1;").unwrap();
+        assert_eq!(burst(&mut sizes), 1);
+        // The same size is reported again once the window has passed.
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(burst(&mut sizes), 1);
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(burst(&mut sizes), 1);
+        assert_eq!(burst(&mut SizeCoalescer::new(&Config::default())), 1);
+    }
+
+    #[test]
+    fn content_filter_drops_unchanged_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("app.php");
+        fs::write(&file, "<?php").unwrap();
+        let mut content = ContentFilter::new(true);
+        content.seed([file.as_path()].into_iter());
+
+        // A touch leaves the contents as they were at startup.
+        assert!(!content.changed(&file));
+        fs::write(&file, "<?php echo 1;").unwrap();
+        assert!(content.changed(&file));
+        assert!(!content.changed(&file));
+
+        fs::remove_file(&file).unwrap();
+        assert!(content.changed(&file));
+        // Recreated with the old contents, it is new again.
+        fs::write(&file, "<?php echo 1;").unwrap();
+        assert!(content.changed(&file));
+        assert!(ContentFilter::new(false).changed(&file));
+    }
+
+    #[test]
+    fn poller_content_only_ignores_a_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let touched = dir.path().join("touched.php");
+        fs::write(&touched, "<?php").unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            root,
+            "--ext",
+            "php",
+            "--poll",
+            "--poll-interval",
+            "20ms",
+            "--debounce",
+            "0",
+            "--content-only",
+            "--relative",
+        ]))
+        .unwrap();
+
+        let run = InProcess::start(config, run_poller_with_shutdown);
+        fs::File::options()
+            .write(true)
+            .open(&touched)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        fs::write(dir.path().join("edited.php"), "<?php").unwrap();
+        assert!(run.wait_for("edited.php"));
+        assert_eq!(run.stop(), "changed: edited.php\n");
+    }
+
+    #[test]
+    fn metrics_are_served_over_http() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.events.store(3, Ordering::Relaxed);
+        metrics.watched_paths.store(2, Ordering::Relaxed);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        serve_metrics(listener, &metrics).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        assert!(
+            response
+                .contains("# TYPE filewatcher_events_total counter\nfilewatcher_events_total 3\n")
+        );
+        assert!(response.contains("filewatcher_flushes_total 0\n"));
+        assert!(response.contains(
+            "# TYPE filewatcher_current_watched_paths gauge\nfilewatcher_current_watched_paths 2\n"
+        ));
+
+        let err = parse_config(&args(&["--metrics-addr", "localhost", "."])).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("invalid metrics address 'localhost'")
+        );
+    }
+
+    #[test]
+    fn debouncer_buffer_all_writes_on_dump() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            buffer_all: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        debouncer.send(change("a.php"));
+        debouncer.send(change("b.php"));
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(buf.contents(), "");
+
+        request_dump();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while buf.contents().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(buf.contents(), "changed: a.php\nchanged: b.php\n");
+
+        // Cleared by the dump; what is left is written on exit.
+        debouncer.send(change("c.php"));
+        debouncer.shutdown();
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\nchanged: b.php\nchanged: c.php\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_max_events_truncates_final_batch() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(20),
+            max_events: 3,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("a.php"));
+        thread::sleep(Duration::from_millis(100));
+        for name in ["b.php", "c.php", "d.php"] {
+            debouncer.send(change(name));
+        }
+        thread::sleep(Duration::from_millis(100));
+        debouncer.send(change("e.php"));
+
+        assert_eq!(debouncer.shutdown(), 3);
+        assert_eq!(
+            buf.contents(),
+            "changed: a.php\nchanged: b.php\nchanged: c.php\n"
+        );
+    }
+
+    #[test]
+    fn debouncer_json_batch_is_one_line() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            format: OutputFormat::JsonBatch,
+            summary: true,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(Change {
+            ext: Some(".php".into()),
+            ..change("app/\"quoted\".php")
+        });
+        debouncer.send(change("README"));
+        debouncer.shutdown();
+
+        assert_eq!(
+            buf.contents(),
+            "{\"event\":\"batch\",\"paths\":[\"README\",\"app/\\\"quoted\\\".php\"],\
+             \"exts\":[null,\".php\"],\"count\":2}\n\
+             {\"event\":\"summary\",\"count\":2}\n"
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn skips_metadata_and_access_unless_asked() {
+        use notify::event::{AccessKind, DataChange, MetadataKind};
+
+        let metadata = EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions));
+        let access = EventKind::Access(AccessKind::Read);
+        let data = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+
+        let mut config = Config::default();
+        assert!(skips_kind(&config, &metadata));
+        assert!(skips_kind(&config, &access));
+        assert!(!skips_kind(&config, &data));
+
+        config.watch_metadata = true;
+        config.watch_access = true;
+        assert!(!skips_kind(&config, &metadata));
+        assert!(!skips_kind(&config, &access));
+
+        let remove = EventKind::Remove(notify::event::RemoveKind::File);
+        assert!(!skips_kind(&config, &remove));
+        config.removals = false;
+        assert!(skips_kind(&config, &remove));
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn skips_ambiguous_kinds_unless_asked() {
+        let mut config = Config::default();
+        assert!(skips_kind(&config, &EventKind::Any));
+        assert!(skips_kind(&config, &EventKind::Other));
+        // A modification of unknown shape still says the file changed.
+        assert!(!skips_kind(&config, &EventKind::Modify(ModifyKind::Any)));
+
+        config.include_ambiguous = true;
+        assert!(!skips_kind(&config, &EventKind::Any));
+        assert!(!skips_kind(&config, &EventKind::Other));
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn parse_control_commands() {
+        assert_eq!(
+            parse_control(r#"{"cmd":"watch","path":"/srv/app"}"#),
+            Ok(ControlCmd::Watch(PathBuf::from("/srv/app")))
+        );
+        assert_eq!(
+            parse_control(r#" { "path" : "a\"b\u0041", "cmd" : "unwatch" } "#),
+            Ok(ControlCmd::Unwatch(PathBuf::from("a\"bA")))
+        );
+        assert!(parse_control(r#"{"cmd":"reload","path":"x"}"#).is_err());
+        assert!(parse_control(r#"{"cmd":"watch"}"#).is_err());
+        assert!(parse_control(r#"{"cmd":"watch","path":1}"#).is_err());
+        assert!(parse_control("watch /srv/app").is_err());
+    }
+
+    #[cfg(feature = "native")]
+    #[test]
+    fn parse_socket_commands() {
+        assert_eq!(parse_socket_command("status\r"), Ok(SocketCmd::Status));
+        assert_eq!(
+            parse_socket_command("add /srv/my app"),
+            Ok(SocketCmd::Control(ControlCmd::Watch(PathBuf::from(
+                "/srv/my app"
+            ))))
+        );
+        assert_eq!(
+            parse_socket_command("remove  /srv/app "),
+            Ok(SocketCmd::Control(ControlCmd::Unwatch(PathBuf::from(
+                "/srv/app"
+            ))))
+        );
+        assert_eq!(
+            parse_socket_command("add"),
+            Err("add requires a path".into())
+        );
+        assert_eq!(
+            parse_socket_command("shutdown now"),
+            Err("shutdown takes no argument".into())
+        );
+        assert_eq!(
+            parse_socket_command("restart"),
+            Err("unknown command 'restart'".into())
+        );
+    }
+
+    #[cfg(all(feature = "native", unix))]
+    #[test]
+    fn control_socket_answers_commands_and_is_removed_on_exit() {
+        use std::io::BufRead;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("app");
+        let extra = dir.path().join("extra");
+        fs::create_dir(&root).unwrap();
+        fs::create_dir(&extra).unwrap();
+        let socket = dir.path().join("fw.sock");
+        let config = Config {
+            control_socket: Some(socket.clone()),
+            ready_marker: true,
+            ..Config::builder().path(&root).build().unwrap()
+        };
+
+        let run = InProcess::start(config, run_watcher_with_shutdown);
+        assert!(run.wait_for("ready\n"));
+        let stream = UnixStream::connect(&socket).unwrap();
+        let mut replies = io::BufReader::new(stream.try_clone().unwrap()).lines();
+        let mut ask = |line: &str| {
+            writeln!(&stream, "{}", line).unwrap();
+            replies.next().unwrap().unwrap()
+        };
+
+        assert_eq!(ask("status"), "ok watching 1 paths, 0 events, 0 batches");
+        let extra = fs::canonicalize(&extra).unwrap();
+        assert_eq!(
+            ask(&format!("add {}", extra.display())),
+            format!("ok watching {}", extra.display())
+        );
+        assert_eq!(ask("reload"), "ok reloaded 1 paths");
+        assert_eq!(ask("bogus"), "error unknown command 'bogus'");
+        assert_eq!(ask("shutdown"), "ok shutting down");
+        run.stop();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn csv_row_quotes_tricky_fields() {
+        assert_eq!(
+            csv_row(&["changed", "app/User.php", "t"]),
+            "changed,app/User.php,t"
+        );
+        assert_eq!(
+            csv_row(&["changed", "a,b.php", "t"]),
+            "changed,\"a,b.php\",t"
+        );
+        assert_eq!(
+            csv_row(&["changed", "say \"hi\".php", "t"]),
+            "changed,\"say \"\"hi\"\".php\",t"
+        );
+        assert_eq!(
+            csv_row(&["changed", "two\nlines.php", "t"]),
+            "changed,\"two\nlines.php\",t"
+        );
+        assert_eq!(
+            csv_row(&["changed", "cr\r.php", ""]),
+            "changed,\"cr\r.php\","
+        );
+    }
+
+    #[test]
+    fn debouncer_writes_csv_rows_after_a_header() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(50),
+            format: OutputFormat::Csv,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+        debouncer.send(change("a,b.php"));
+        debouncer.shutdown();
+
+        let out = buf.contents();
+        assert!(out.starts_with("kind,path,timestamp\n"), "{}", out);
+        let row = out.lines().nth(1).unwrap();
+        let timestamp = row.strip_prefix("changed,\"a,b.php\",").unwrap();
+        assert_eq!(timestamp.len(), "2024-02-29T12:34:56Z".len(), "{}", row);
+
+        let config = parse_config(&args(&["--format", "csv", "."])).unwrap();
+        assert_eq!(config.format, OutputFormat::Csv);
+        let err = parse_config(&args(&["--format", "csv", "--summary", "."])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--format csv can't be combined with --summary"
+        );
+        for flag in ["--with-size", "--with-mtime"] {
+            let err = parse_config(&args(&["--format", "csv", flag, "."])).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("--format csv can't be combined with {}", flag)
+            );
+            let err = parse_config(&args(&["--output", "csv:out.csv", flag, "."])).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("--format csv can't be combined with {}", flag)
+            );
+        }
+    }
+
+    #[test]
+    fn csv_header_is_not_repeated_when_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("changes.csv");
+        let run = |append: bool| {
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(10),
+                format: OutputFormat::Csv,
+                output: Some(file.clone()),
+                output_append: append,
+                ..Default::default()
+            };
+            let writer = open_output_file(&file, append).unwrap();
+            let mut debouncer = Debouncer::new(opts, Box::new(writer));
+            debouncer.send(change("a.php"));
+            debouncer.shutdown();
+            fs::read_to_string(&file).unwrap()
+        };
+
+        let headers = |out: &str| out.lines().filter(|l| *l == CSV_HEADER).count();
+        assert_eq!(headers(&run(true)), 1);
+        let out = run(true);
+        assert_eq!((headers(&out), out.lines().count()), (1, 3));
+        // Truncating starts over with a header.
+        let out = run(false);
+        assert_eq!((headers(&out), out.lines().count()), (1, 2));
+    }
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a\\b\n\u{1}"), "\"a\\\\b\\n\\u0001\"");
+    }
+
+    #[test]
+    fn template_renders_placeholders() {
+        let t = Template::parse("[{kind}] {path} ({ext}) {{x}}").unwrap();
+        assert_eq!(
+            t.render(&change("app/User.php"), SystemTime::UNIX_EPOCH, false),
+            "[changed] app/User.php (.php) {x}"
+        );
+        let blade = Change {
+            ext: Some(".blade.php".into()),
+            ..change("welcome.blade.php")
+        };
+        assert_eq!(
+            t.render(&blade, SystemTime::UNIX_EPOCH, false),
+            "[changed] welcome.blade.php (.blade.php) {x}"
+        );
+        let link = Change {
+            tag: "symlink-changed".into(),
+            target: Some(PathBuf::from("releases/2")),
+            size: None,
+            mtimes: None,
+            kind: None,
+            ..change("current")
+        };
+        assert_eq!(
+            Template::default().render(&link, SystemTime::UNIX_EPOCH, false),
+            "symlink-changed: current -> releases/2"
+        );
+        assert_eq!(
+            Template::parse("{kind}: {path}").unwrap(),
+            Template::default()
+        );
+    }
+
+    #[test]
+    fn template_colors_kind() {
+        let t = Template::default();
+        let now = SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            t.render(&change("app/User.php"), now, true),
+            "\x1b[33mchanged\x1b[0m: app/User.php"
+        );
+        let created = Change {
+            path: PathBuf::from("app/Jobs"),
+            tag: "dir-created".into(),
+            ext: None,
+            target: None,
+            size: None,
+            mtimes: None,
+            kind: None,
+        };
+        assert_eq!(
+            t.render(&created, now, true),
+            "\x1b[32mdir-created\x1b[0m: app/Jobs"
+        );
+    }
+
+    #[test]
+    fn template_rejects_unknown_placeholders() {
+        assert!(Template::parse("{file}").is_err());
+        assert!(Template::parse("{path").is_err());
+        assert!(Template::parse("path}").is_err());
+    }
+
+    #[test]
+    fn format_rfc3339_utc() {
+        let t = SystemTime::UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(format_rfc3339(t), "2024-02-29T12:34:56Z");
+        assert_eq!(
+            format_rfc3339(SystemTime::UNIX_EPOCH),
+            "1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn debouncer_drops_when_queue_full() {
+        struct SlowWriter;
+
+        impl Write for SlowWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(300));
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            queue_size: 2,
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(SlowWriter));
+
+        debouncer.send(change("first.php"));
+        thread::sleep(Duration::from_millis(100));
+        for i in 0..10 {
+            debouncer.send(change(&format!("file{}.php", i)));
+        }
+
+        assert_eq!(debouncer.dropped.get(), 8);
+        debouncer.shutdown();
+    }
+
+    #[test]
+    fn env_defaults_sit_below_config_file_and_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let env = |var: &str| match var {
+            "FILEWATCHER_EXT" => Some("js,css".to_string()),
+            "FILEWATCHER_DEBOUNCE" => Some("500".to_string()),
+            "FILEWATCHER_POLL_INTERVAL" => Some("2s".to_string()),
+            "FILEWATCHER_IGNORE" => Some(" ".to_string()),
+            _ => None,
+        };
+        let parse = |list: &[&str]| match parse_args_with_env(&args(list), &env).unwrap() {
+            Command::Run(config) => config,
+            other => panic!("expected a config, got {:?}", other),
+        };
+
+        let config = parse(&[root]);
+        assert_eq!(config.extensions, vec![".js", ".css"]);
+        assert_eq!(config.debounce, Duration::from_millis(500));
+        assert_eq!(config.poll_interval, Duration::from_secs(2));
+        assert!(config.ignore.is_empty(), "blank variables are unset");
+
+        let file = dir.path().join("filewatcher.toml");
+        fs::write(&file, "debounce = 300\n").unwrap();
+        let config = parse(&["--config", file.to_str().unwrap(), "--ext", "php", root]);
+        assert_eq!(config.extensions, vec![".php"]);
+        assert_eq!(config.debounce, Duration::from_millis(300));
+        assert_eq!(config.poll_interval, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn ignore_patterns_apply_under_every_root() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let roots: Vec<PathBuf> = dirs
+            .iter()
+            .map(|d| fs::canonicalize(d.path()).unwrap())
+            .collect();
+        let config = parse_config(&args(&[
+            roots[0].to_str().unwrap(),
+            roots[1].to_str().unwrap(),
+            "--ext",
+            "php,log",
+            "--ignore",
+            "*.log, build/",
+        ]))
+        .unwrap();
+        assert_eq!(config.ignore, vec!["*.log", "build/"]);
+        let filter = Filter::from_config(&config);
+        for root in &roots {
+            assert!(filter.ignored(&root.join("storage/app.log")));
+            assert!(filter.ignored(&root.join("build/app.php")));
+            assert!(!filter.ignored(&root.join("app/app.php")));
+        }
+    }
+
+    #[test]
+    fn print0_rejects_other_line_shapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&["--print0", root])).unwrap();
+        assert_eq!(config.format, OutputFormat::Print0);
+
+        for (flags, message) in [
+            (
+                &["--format", "json-batch"][..],
+                "--print0 can't be combined with --format",
+            ),
+            (
+                &["--template", "{path}"],
+                "--print0 can't be combined with --template",
+            ),
+            (&["--summary"], "--print0 can't be combined with --summary"),
+            (
+                &["--label", "api"],
+                "--print0 can't be combined with --label",
+            ),
+            (
+                &["--collapse-threshold", "5"],
+                "--print0 can't be combined with --collapse-threshold",
+            ),
+        ] {
+            let mut list = vec!["--print0", root];
+            list.extend(flags);
+            assert_eq!(parse_config(&args(&list)).unwrap_err().to_string(), message);
+        }
+    }
+
+    #[test]
+    fn parse_config_file_values() {
+        let file = "# watcher settings\n\
+                    ext = [\"php\", 'js'] # trailing comment\n\
+                    debounce = 200\n\
+                    poll_interval = \"1s\"\n\
+                    stats = true\n\
+                    poll = false\n\
+                    template = \"{kind}\\t\\\"{path}\\\"\"\n";
+        assert_eq!(
+            parse_config_file(file).unwrap(),
+            vec![
+                ("ext".into(), Some(vec!["php".into(), "js".into()])),
+                ("debounce".into(), Some(vec!["200".into()])),
+                ("poll-interval".into(), Some(vec!["1s".into()])),
+                ("stats".into(), None),
+                ("template".into(), Some(vec!["{kind}\t\"{path}\"".into()])),
+            ]
+        );
+
+        for (bad, message) in [
+            ("[watch]", "line 1: tables are not supported"),
+            ("ext php", "line 1: expected `key = value`"),
+            ("ext = php", "line 1: invalid value 'php' (quote strings)"),
+            ("ext = [\"php\"", "line 1: unterminated array"),
+            ("ext = \"php", "line 1: unterminated string"),
+            (
+                "debounce = 200 300",
+                "line 1: unexpected '300' after the value",
+            ),
+        ] {
+            assert_eq!(parse_config_file(bad).unwrap_err(), message, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn config_file_flags_yield_to_the_command_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let file = dir.path().join("filewatcher.toml");
+        fs::write(
+            &file,
+            format!(
+                "paths = [{:?}]\next = [\"js\", \"css\"]\ndebounce = 500\nstats = true\n",
+                root
+            ),
+        )
+        .unwrap();
+        let file = file.to_str().unwrap();
+
+        let config = parse_config(&args(&["--config", file])).unwrap();
+        assert_eq!(config.paths, vec![PathBuf::from(root)]);
+        assert_eq!(config.extensions, vec![".js", ".css"]);
+        assert_eq!(config.debounce, Duration::from_millis(500));
+        assert!(config.stats);
+
+        let config = parse_config(&args(&[
+            "--config",
+            file,
+            "--ext",
+            "php",
+            "--debounce",
+            "50",
+            root,
+        ]))
+        .unwrap();
+        assert_eq!(config.extensions, vec![".php"]);
+        assert_eq!(config.debounce, Duration::from_millis(50));
+        assert_eq!(config.paths, vec![PathBuf::from(root)]);
+
+        fs::write(
+            dir.path().join("bad.toml"),
+            "stats = true\nwatch_everything = true\n",
+        )
+        .unwrap();
+        let bad = dir.path().join("bad.toml");
+        let err = parse_config(&args(&["--config", bad.to_str().unwrap(), root])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("{}: unknown key 'watch-everything'", bad.display())
+        );
+    }
+
+    #[test]
+    fn parse_path_list_skips_blank_and_comments() {
+        let list = "app\n\n# generated\n  config  \nroutes\r\n";
+        assert_eq!(
+            parse_path_list(list),
+            vec![
+                PathBuf::from("app"),
+                PathBuf::from("config"),
+                PathBuf::from("routes")
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_writer_writes_each_flush_under_one_lock() {
+        let buf = SharedBuf::default();
+        let shared: Arc<Mutex<dyn Write + Send>> = Arc::new(Mutex::new(buf.clone()));
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, Box::new(SharedWriter::new(shared.clone())));
+
+        {
+            let mut caller = shared.lock().unwrap();
+            debouncer.send(change("a.php"));
+            debouncer.send(change("b.php"));
+            thread::sleep(Duration::from_millis(100));
+            writeln!(caller, "log: before").unwrap();
         }
+        debouncer.shutdown();
+        writeln!(shared.lock().unwrap(), "log: after").unwrap();
+
+        assert_eq!(
+            buf.contents(),
+            "log: before\nchanged: a.php\nchanged: b.php\nlog: after\n"
+        );
     }
 
-    fn flush(pending: &mut HashSet<String>, writer: &mut Box<dyn Write + Send>) {
-        if pending.is_empty() {
-            return;
-        }
-        for p in pending.drain() {
-            let _ = writeln!(writer, "changed: {}", p);
+    #[test]
+    fn debouncer_writes_errors_between_batches() {
+        for (format, expected) in [
+            (OutputFormat::Text, "changed: a.php\nerror: watch lost\n"),
+            (
+                OutputFormat::JsonBatch,
+                "{\"event\":\"batch\",\"paths\":[\"a.php\"],\"exts\":[null],\"count\":1}\n\
+                 {\"event\":\"error\",\"message\":\"watch lost\"}\n",
+            ),
+        ] {
+            let buf = SharedBuf::default();
+            let opts = DebounceOptions {
+                debounce: Duration::from_millis(10),
+                format,
+                ..Default::default()
+            };
+            let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+            debouncer.send(change("a.php"));
+            thread::sleep(Duration::from_millis(100));
+            debouncer.error("watch lost");
+            debouncer.shutdown();
+            assert_eq!(buf.contents(), expected);
         }
-        let _ = writer.flush();
     }
-}
 
-impl Drop for Debouncer {
-    fn drop(&mut self) {
-        self.shutdown();
+    #[test]
+    fn debouncer_flushes_buffered_output_on_shutdown() {
+        let buf = SharedBuf::default();
+        let writer = Box::new(BufWriter::new(buf.clone()));
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            flush_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut debouncer = Debouncer::new(opts, writer);
+
+        debouncer.send(change("app/User.php"));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(buf.contents(), "", "output flushed before interval elapsed");
+
+        assert_eq!(debouncer.shutdown(), 1);
+        assert_eq!(buf.contents(), "changed: app/User.php\n");
     }
-}
 
-fn scan_dir(root: &Path, extensions: &[String], state: &mut HashMap<PathBuf, SystemTime>) {
-    let mut stack = vec![root.to_path_buf()];
-    while let Some(dir) = stack.pop() {
-        let entries = match fs::read_dir(&dir) {
-            Ok(e) => e,
-            Err(_) => continue,
+    #[test]
+    fn debouncer_summary_line_follows_batch() {
+        let buf = SharedBuf::default();
+        let opts = DebounceOptions {
+            debounce: Duration::from_millis(10),
+            summary: true,
+            ..Default::default()
         };
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() {
-                if !is_ignored(&path) {
-                    stack.push(path);
-                }
-            } else if matches_extension(&path.to_string_lossy(), extensions) {
-                if let Ok(meta) = fs::metadata(&path) {
-                    if let Ok(mtime) = meta.modified() {
-                        state.insert(path, mtime);
-                    }
-                }
-            }
-        }
+        let mut debouncer = Debouncer::new(opts, Box::new(buf.clone()));
+
+        debouncer.send(change("a.php"));
+        debouncer.send(change("b.php"));
+        debouncer.shutdown();
+
+        let out = buf.contents();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 3, "unexpected output: {}", out);
+        assert_eq!(lines[2], "--- 2 files changed ---");
     }
-}
 
-pub fn run_watcher(config: &Config, writer: Box<dyn Write + Send>) -> Result<(), String> {
-    let mut debouncer = Debouncer::new(config.debounce, writer);
+    #[cfg(unix)]
+    #[test]
+    fn scan_dir_symlink_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("app");
+        fs::create_dir_all(root.join("Models")).unwrap();
+        fs::write(root.join("Models/User.php"), "<?php").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("Models/loop")).unwrap();
 
-    let (tx, rx) = mpsc::channel();
-    let mut watcher = notify::RecommendedWatcher::new(tx, notify::Config::default())
-        .map_err(|e| format!("failed to create watcher: {}", e))?;
+        let mut opts = ScanOptions {
+            filter: filter_for(&[".php"]),
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let mut state = ScanState::default();
+        scan_dir(&root, &opts, &ScanState::default(), &mut state);
+        assert_eq!(state.files.len(), 1, "found: {:?}", state.files.keys());
 
-    for root in &config.paths {
-        let abs = fs::canonicalize(root).map_err(|e| format!("{}: {}", root.display(), e))?;
-        watcher
-            .watch(&abs, RecursiveMode::Recursive)
-            .map_err(|e| format!("failed to watch {}: {}", abs.display(), e))?;
-    }
+        let outside = dir.path().join("shared");
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("helpers.php"), "<?php").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("shared")).unwrap();
 
-    loop {
-        if SHUTDOWN.load(Ordering::Relaxed) {
-            break;
-        }
+        state = ScanState::default();
+        scan_dir(&root, &opts, &ScanState::default(), &mut state);
+        assert!(state.files.contains_key(&root.join("shared/helpers.php")));
 
-        match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(Ok(event)) => {
-                for path in &event.paths {
-                    if is_ignored_path(path) {
-                        continue;
-                    }
-                    if matches!(event.kind, EventKind::Modify(ModifyKind::Metadata(_))) {
-                        continue;
-                    }
-                    if matches!(event.kind, EventKind::Access(_)) {
-                        continue;
-                    }
+        opts.follow_symlinks = false;
+        state = ScanState::default();
+        scan_dir(&root, &opts, &ScanState::default(), &mut state);
+        assert_eq!(state.files.len(), 1, "found: {:?}", state.files.keys());
+    }
 
-                    let path_str = path.to_string_lossy();
-                    if matches_extension(&path_str, &config.extensions) {
-                        debouncer.send(path_str.into_owned());
-                    }
-                }
-            }
-            Ok(Err(e)) => {
-                eprintln!("watcher error: {}", e);
-            }
-            Err(mpsc::RecvTimeoutError::Timeout) => continue,
-            Err(mpsc::RecvTimeoutError::Disconnected) => break,
-        }
+    #[test]
+    fn scan_dir_no_recursive_stays_at_top_level() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("Models")).unwrap();
+        fs::write(dir.path().join("routes.php"), "<?php").unwrap();
+        fs::write(dir.path().join("Models/User.php"), "<?php").unwrap();
+
+        let opts = ScanOptions {
+            filter: filter_for(&[".php"]),
+            recursive: false,
+            watch_dirs: true,
+            ..Default::default()
+        };
+        let mut state = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut state);
+        let files: Vec<&Path> = state.files().collect();
+        assert_eq!(files, vec![dir.path().join("routes.php")]);
+        assert!(state.dirs.contains(&dir.path().join("Models")));
     }
 
-    debouncer.shutdown();
-    Ok(())
-}
+    #[test]
+    fn scan_dir_hash_ignores_touch() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("User.php");
+        fs::write(&file, "<?php").unwrap();
 
-pub fn run_poller(config: &Config, writer: Box<dyn Write + Send>) -> Result<(), String> {
-    let mut debouncer = Debouncer::new(config.debounce, writer);
-    let mut state: HashMap<PathBuf, SystemTime> = HashMap::new();
+        let opts = ScanOptions {
+            filter: filter_for(&[".php"]),
+            compare: PollCompare::Hash,
+            ..Default::default()
+        };
+        let mut before = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut before);
 
-    for root in &config.paths {
-        let abs = fs::canonicalize(root).map_err(|e| format!("{}: {}", root.display(), e))?;
-        scan_dir(&abs, &config.extensions, &mut state);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        let mut after = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut after);
+        assert_eq!(before, after);
+
+        fs::write(&file, "<?php echo 1;").unwrap();
+        after = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut after);
+        assert_ne!(before, after);
     }
 
-    loop {
-        thread::sleep(config.poll_interval);
+    #[cfg(unix)]
+    #[test]
+    fn scan_dir_fast_reuses_unchanged_listing() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.php");
+        fs::write(&file, "<?php").unwrap();
+        let old = SystemTime::now() - Duration::from_secs(60);
+        let set_dir_mtime = || {
+            fs::File::open(dir.path())
+                .unwrap()
+                .set_modified(old)
+                .unwrap()
+        };
+        set_dir_mtime();
 
-        if SHUTDOWN.load(Ordering::Relaxed) {
-            break;
-        }
+        let opts = ScanOptions {
+            filter: filter_for(&[".php"]),
+            fast: true,
+            ..Default::default()
+        };
+        let mut first = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut first);
 
-        let mut current: HashMap<PathBuf, SystemTime> = HashMap::new();
-        for root in &config.paths {
-            if let Ok(abs) = fs::canonicalize(root) {
-                scan_dir(&abs, &config.extensions, &mut current);
-            }
-        }
+        // An entry added behind an unchanged mtime is not seen, but an
+        // in-place edit to a listed file still is.
+        fs::write(dir.path().join("b.php"), "<?php").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        set_dir_mtime();
+        let mut second = ScanState::default();
+        scan_dir(dir.path(), &opts, &first, &mut second);
+        assert_eq!(second.files.len(), 1);
+        assert_ne!(first.files[&file], second.files[&file]);
 
-        for (path, mtime) in &current {
-            match state.get(path) {
-                Some(prev) if prev == mtime => {}
-                _ => {
-                    debouncer.send(path.to_string_lossy().into_owned());
-                }
-            }
-        }
+        let mut fresh = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut fresh);
+        assert_eq!(fresh.files.len(), 2);
+    }
 
-        for path in state.keys() {
-            if !current.contains_key(path) {
-                debouncer.send(path.to_string_lossy().into_owned());
-            }
+    #[test]
+    fn editor_temp_files() {
+        for name in [
+            "User.php~",
+            "#User.php#",
+            ".#User.php",
+            "4913",
+            ".User.php.swp",
+            "x.tmp",
+        ] {
+            assert!(
+                is_editor_temp(Path::new("app").join(name).as_path()),
+                "{}",
+                name
+            );
+        }
+        for name in ["User.php", "2024_01_01_create_users.php", "tmp.php"] {
+            assert!(
+                !is_editor_temp(Path::new("app").join(name).as_path()),
+                "{}",
+                name
+            );
         }
 
-        state = current;
+        let mut filter = filter_for(&[".php", ".tmp"]);
+        assert!(!filter.ignored(Path::new("app/x.tmp")));
+        filter.skip_editor_temp = true;
+        assert!(filter.ignored(Path::new("app/x.tmp")));
     }
 
-    debouncer.shutdown();
-    Ok(())
-}
+    #[test]
+    fn ignore_file_patterns() {
+        let ignore = IgnoreFile {
+            root: PathBuf::from("/srv/app"),
+            patterns: parse_ignore_patterns("# generated\n*.log\n\nbuild/\nstorage/framework/*\n"),
+        };
+        assert!(ignore.matches(Path::new("/srv/app/debug.log")));
+        assert!(ignore.matches(Path::new("/srv/app/build/app.php")));
+        assert!(ignore.matches(Path::new("/srv/app/src/build/app.php")));
+        assert!(ignore.matches(Path::new("/srv/app/storage/framework/views/x.php")));
+        assert!(!ignore.matches(Path::new("/srv/app/storage/logs.php")));
+        assert!(!ignore.matches(Path::new("/srv/app/app/User.php")));
+        assert!(!ignore.matches(Path::new("/elsewhere/debug.log")));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn parse_loads_filewatchignore() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(IGNORE_FILE), "cache\n").unwrap();
+        let config = parse_config(&args(&[dir.path().to_str().unwrap()])).unwrap();
+        assert_eq!(config.ignore_files.len(), 1);
+        assert_eq!(config.ignore_files[0].patterns, vec!["cache"]);
+
+        let filter = Filter::from_config(&config);
+        let root = fs::canonicalize(dir.path()).unwrap();
+        assert!(filter.ignored(&root.join("cache/x.php")));
+        assert!(!filter.ignored(&root.join("app/x.php")));
+    }
 
     #[test]
-    fn parse_extensions_single() {
-        assert_eq!(parse_extensions("php"), vec![".php"]);
+    fn scan_dir_watch_dirs_skips_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("app/Empty")).unwrap();
+        fs::create_dir_all(dir.path().join("vendor/pkg")).unwrap();
+
+        let mut opts = ScanOptions {
+            filter: filter_for(&[".php"]),
+            watch_dirs: true,
+            ..Default::default()
+        };
+        let mut state = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut state);
+        let expected: HashSet<PathBuf> = [dir.path().join("app"), dir.path().join("app/Empty")]
+            .into_iter()
+            .collect();
+        assert_eq!(state.dirs, expected);
+
+        opts.watch_dirs = false;
+        state = ScanState::default();
+        scan_dir(dir.path(), &opts, &ScanState::default(), &mut state);
+        assert!(state.dirs.is_empty());
     }
 
     #[test]
-    fn parse_extensions_multiple() {
+    fn scan_state_json_round_trip() {
+        let mut state = ScanState::default();
+        state.files.insert(
+            PathBuf::from("/app/\"q\".php"),
+            Stamp::Mtime(SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 5)),
+        );
+        state
+            .files
+            .insert(PathBuf::from("/app/User.php"), Stamp::Hash(0xabc));
+        let json = state.to_json();
         assert_eq!(
-            parse_extensions("php,blade.php,js"),
-            vec![".php", ".blade.php", ".js"]
+            json,
+            "{\"/app/\\\"q\\\".php\":\"mtime:1700000000.000000005\",\
+             \"/app/User.php\":\"hash:0000000000000abc\"}"
         );
+        assert_eq!(ScanState::from_json(&json), Some(state));
+        assert_eq!(ScanState::from_json("{}"), Some(ScanState::default()));
+        assert!(ScanState::from_json("{\"/a.php\":\"size:3\"}").is_none());
+        assert!(ScanState::from_json("[]").is_none());
     }
 
     #[test]
-    fn parse_extensions_with_dots() {
-        assert_eq!(parse_extensions(".php,.js"), vec![".php", ".js"]);
+    fn parse_snapshot_and_diff_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        assert!(matches!(
+            parse_args_from(&args(&["snapshot", "--ext", "js", root])),
+            Ok(Command::Snapshot(c)) if c.extensions == [".js"]
+        ));
+        assert!(matches!(
+            parse_args_from(&args(&["diff", root, "state.json"])),
+            Ok(Command::Diff(c, file)) if c.paths == [dir.path()] && file == Path::new("state.json")
+        ));
+        assert!(parse_args_from(&args(&["diff", root, "--all"])).is_err());
     }
 
     #[test]
-    fn parse_extensions_whitespace() {
-        assert_eq!(parse_extensions(" php , js "), vec![".php", ".js"]);
+    fn diff_reports_created_modified_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("User.php"), "<?php").unwrap();
+        fs::write(dir.path().join("Post.php"), "<?php").unwrap();
+
+        let config = Config {
+            extensions: vec![".php".into()],
+            poll_compare: PollCompare::Hash,
+            paths: vec![dir.path().to_path_buf()],
+            ..Default::default()
+        };
+        let before = scan(&config);
+        assert!(diff(&before, &before).is_empty());
+
+        fs::write(dir.path().join("User.php"), "<?php echo 1;").unwrap();
+        fs::remove_file(dir.path().join("Post.php")).unwrap();
+        fs::write(dir.path().join("Team.php"), "<?php").unwrap();
+        let after = scan(&config);
+
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let kinds: Vec<(PathBuf, ChangeKind)> = diff(&before, &after)
+            .into_iter()
+            .map(|e| (e.path, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (root.join("Post.php"), ChangeKind::Removed),
+                (root.join("Team.php"), ChangeKind::Created),
+                (root.join("User.php"), ChangeKind::Modified),
+            ]
+        );
     }
 
     #[test]
-    fn parse_extensions_empty() {
-        let result: Vec<String> = parse_extensions("");
-        assert!(result.is_empty());
+    fn diff_carries_mtimes_of_modified_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("User.php");
+        fs::write(&file, "<?php").unwrap();
+        let at = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        let touch = |secs| {
+            fs::File::options()
+                .write(true)
+                .open(&file)
+                .unwrap()
+                .set_modified(at(secs))
+                .unwrap()
+        };
+
+        let config = Config {
+            extensions: vec![".php".into()],
+            paths: vec![dir.path().to_path_buf()],
+            ..Default::default()
+        };
+        touch(1_700_000_000);
+        let before = scan(&config);
+        touch(1_700_000_042);
+        let events = diff(&before, &scan(&config));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].mtimes,
+            Some((at(1_700_000_000), at(1_700_000_042)))
+        );
+
+        let config = Config {
+            poll_compare: PollCompare::Hash,
+            ..config
+        };
+        let before = scan(&config);
+        fs::write(&file, "<?php echo 1;").unwrap();
+        assert_eq!(diff(&before, &scan(&config))[0].mtimes, None);
     }
 
+    #[cfg(unix)]
     #[test]
-    fn parse_extensions_trailing_comma() {
-        assert_eq!(parse_extensions("php,"), vec![".php"]);
+    fn diff_reports_repointed_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(root.join("releases/1")).unwrap();
+        fs::create_dir_all(root.join("releases/2")).unwrap();
+        fs::write(root.join("a.php"), "<?php").unwrap();
+        fs::write(root.join("b.php"), "<?php echo 1;").unwrap();
+        symlink("releases/1", root.join("current")).unwrap();
+        symlink("a.php", root.join("config.php")).unwrap();
+
+        let config = Config {
+            extensions: vec![".php".into()],
+            poll_compare: PollCompare::Hash,
+            paths: vec![root.clone()],
+            ..Default::default()
+        };
+        let before = scan(&config);
+
+        fs::remove_file(root.join("current")).unwrap();
+        symlink("releases/2", root.join("current")).unwrap();
+        fs::remove_file(root.join("config.php")).unwrap();
+        symlink("b.php", root.join("config.php")).unwrap();
+        let after = scan(&config);
+
+        // The file link's new stamp is not also reported as a modification.
+        assert_eq!(
+            diff(&before, &after),
+            vec![
+                ChangeEvent {
+                    path: root.join("config.php"),
+                    kind: ChangeKind::SymlinkChanged,
+                    target: Some(PathBuf::from("b.php")),
+                    mtimes: None,
+                },
+                ChangeEvent {
+                    path: root.join("current"),
+                    kind: ChangeKind::SymlinkChanged,
+                    target: Some(PathBuf::from("releases/2")),
+                    mtimes: None,
+                },
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diff_survives_repointed_root_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = fs::canonicalize(dir.path()).unwrap();
+        for (release, only) in [("a", "Old.php"), ("b", "New.php")] {
+            fs::create_dir(base.join(release)).unwrap();
+            fs::write(base.join(release).join("User.php"), "<?php").unwrap();
+            fs::write(base.join(release).join(only), "<?php").unwrap();
+        }
+        let current = base.join("current");
+        std::os::unix::fs::symlink(base.join("a"), &current).unwrap();
+
+        let config = Config {
+            extensions: vec![".php".into()],
+            poll_compare: PollCompare::Hash,
+            paths: vec![current.clone()],
+            ..Default::default()
+        };
+        let before = scan(&config);
+        fs::remove_file(&current).unwrap();
+        std::os::unix::fs::symlink(base.join("b"), &current).unwrap();
+        let after = scan(&config);
+
+        let kinds: Vec<(PathBuf, ChangeKind)> = diff(&before, &after)
+            .into_iter()
+            .map(|e| (e.path, e.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (base.join("a/Old.php"), ChangeKind::Removed),
+                (base.join("b/New.php"), ChangeKind::Created),
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "native")]
+    fn readiness_thresholds() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("User.php");
+        fs::write(&file, "").unwrap();
+
+        let sized = Readiness {
+            min_size: 1,
+            ..Default::default()
+        };
+        assert!(!sized.is_ready(&file));
+        fs::write(&file, "<?php").unwrap();
+        assert!(sized.is_ready(&file));
+
+        let aged = Readiness {
+            min_age: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        assert!(!aged.is_ready(&file));
+        assert!(aged.is_ready(&dir.path().join("removed.php")));
+
+        let verified = Readiness {
+            verify_writes: true,
+            ..Default::default()
+        };
+        assert!(verified.is_readable(&file));
+        assert!(verified.is_readable(&dir.path().join("removed.php")));
+        // Opens but can't be read, like a file locked mid-write.
+        #[cfg(unix)]
+        assert!(!verified.is_readable(dir.path()));
     }
 
     #[test]
@@ -420,6 +10382,18 @@ mod tests {
         assert!(!matches_extension("style.css", &exts));
     }
 
+    #[test]
+    fn matched_extension_returns_first_match() {
+        let exts = parse_extensions("blade.php,php,config.*");
+        assert_eq!(
+            matched_extension("welcome.blade.php", &exts),
+            Some(".blade.php")
+        );
+        assert_eq!(matched_extension("User.php", &exts), Some(".php"));
+        assert_eq!(matched_extension("config.yml", &exts), Some("config.*"));
+        assert_eq!(matched_extension("app.js", &exts), None);
+    }
+
     #[test]
     fn matches_extension_blade_not_plain_php() {
         let exts = vec![".blade.php".to_string()];
@@ -427,6 +10401,335 @@ mod tests {
         assert!(matches_extension("views/home.blade.php", &exts));
     }
 
+    #[test]
+    fn strict_extension_matches_whole_extension() {
+        let php = parse_extensions("php");
+        let blade = parse_extensions("blade.php");
+        for (path, suffix, strict) in [
+            ("app/app.php", true, true),
+            ("views/home.blade.php", true, false),
+            ("config/.env.php", true, true),
+            ("app/notphp", false, false),
+        ] {
+            assert_eq!(matches_extension(path, &php), suffix, "{}", path);
+            assert_eq!(
+                matched_extension_strict(path, &php).is_some(),
+                strict,
+                "{}",
+                path
+            );
+        }
+        assert_eq!(
+            matched_extension_strict("views/home.blade.php", &blade),
+            Some(".blade.php")
+        );
+        assert_eq!(matched_extension_strict("app/app.php", &blade), None);
+        assert!(matched_extension_strict("home.blade.php", &parse_extensions("*.php")).is_some());
+
+        let filter = |extra: &[&str]| {
+            let mut argv = args(&["--ext", "php", "."]);
+            argv.extend(args(extra));
+            Filter::from_config(&parse_config(&argv).unwrap())
+        };
+        assert!(filter(&[]).matches(Path::new("home.blade.php")));
+        assert!(!filter(&["--strict-ext"]).matches(Path::new("home.blade.php")));
+        assert!(filter(&["--strict-ext"]).matches(Path::new("app.php")));
+    }
+
+    #[test]
+    fn matches_extension_globs_match_filename() {
+        let exts = parse_extensions("*.blade.php,config.*,v?.json");
+        assert!(matches_extension("resources/views/home.blade.php", &exts));
+        assert!(matches_extension("app/config.json", &exts));
+        assert!(!matches_extension("app/appconfig.json", &exts));
+        assert!(!matches_extension("config/app.php", &exts));
+        assert!(matches_extension("v2.json", &exts));
+        assert!(!matches_extension("v10.json", &exts));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn matches_extension_non_utf8_path() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let exts = vec![".php".to_string()];
+        let path = Path::new(OsStr::from_bytes(b"app/caf\xe9.php"));
+        assert!(matches_extension(path, &exts));
+        assert_eq!(display_path(path), "app/caf\\xe9.php");
+    }
+
+    #[test]
+    fn display_path_utf8_unchanged() {
+        assert_eq!(display_path(Path::new("app/café.php")), "app/café.php");
+    }
+
+    #[test]
+    fn parse_rule_multiple_extensions() {
+        assert_eq!(
+            parse_rule("js,css:frontend").unwrap(),
+            Rule {
+                extensions: vec![".js".into(), ".css".into()],
+                tag: "frontend".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_rule_rejects_missing_tag() {
+        assert!(parse_rule("php").is_err());
+        assert!(parse_rule("php:").is_err());
+        assert!(parse_rule(":backend").is_err());
+    }
+
+    #[test]
+    fn match_rule_first_match_wins() {
+        let rules = vec![
+            parse_rule("blade.php:views").unwrap(),
+            parse_rule("php:backend").unwrap(),
+            parse_rule("js,css:frontend").unwrap(),
+        ];
+        let tag = |p: &str| match_rule(p, &rules).map(|r| r.tag.as_str());
+        assert_eq!(tag("resources/views/home.blade.php"), Some("views"));
+        assert_eq!(tag("app/User.php"), Some("backend"));
+        assert_eq!(tag("public/style.css"), Some("frontend"));
+        assert_eq!(tag("README.md"), None);
+    }
+
+    #[test]
+    fn filter_only_intersects_with_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let config = parse_config(&args(&[
+            "--ext",
+            "php",
+            "--ext-exclude",
+            "blade.php",
+            "--only",
+            "src/**, ./tests/**",
+            root.to_str().unwrap(),
+        ]))
+        .unwrap();
+        assert_eq!(config.only, vec!["src/**", "tests/**"]);
+        let filter = Filter::from_config(&config);
+
+        assert!(filter.matches(&root.join("src/Models/User.php")));
+        assert!(filter.matches(&root.join("tests/UserTest.php")));
+        // Extension matches but the path is outside `--only`.
+        assert!(!filter.matches(&root.join("config/app.php")));
+        // Inside `--only` but the extension doesn't match.
+        assert!(!filter.matches(&root.join("src/app.js")));
+        // Excluded wins over `--only`.
+        assert!(!filter.matches(&root.join("src/views/home.blade.php")));
+        assert!(!filter.matches(Path::new("/elsewhere/src/User.php")));
+    }
+
+    #[test]
+    fn tracked_only_skips_untracked_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let git = |args: &[&str]| {
+            let status = process::Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args(args)
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        fs::create_dir(root.join("app")).unwrap();
+        fs::write(root.join("app/User.php"), "<?php").unwrap();
+        fs::write(root.join("app/Draft.php"), "<?php").unwrap();
+        git(&["add", "app/User.php"]);
+
+        let config = parse_config(&args(&["--tracked-only", root.to_str().unwrap()])).unwrap();
+        let filter = Filter::from_config(&config);
+        assert!(filter.matches(&root.join("app/User.php")));
+        assert!(!filter.matches(&root.join("app/Draft.php")));
+        assert!(!filter.matches(&root.join("app/New.php")));
+
+        let plain = tempfile::tempdir().unwrap();
+        let err =
+            parse_config(&args(&["--tracked-only", plain.path().to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().contains("--tracked-only"), "{}", err);
+    }
+
+    // Other unix filesystems may insist on UTF-8 names.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn tracked_only_keeps_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        let name = Path::new(OsStr::from_bytes(b"caf\xe9.php"));
+        fs::write(root.join(name), "<?php").unwrap();
+        for args in [&["init", "-q"][..], &["add", "."]] {
+            let status = process::Command::new("git")
+                .arg("-C")
+                .arg(&root)
+                .args(args)
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let config = parse_config(&args(&["--tracked-only", root.to_str().unwrap()])).unwrap();
+        assert!(config.tracked_files.contains(&root.join(name)));
+        assert!(Filter::from_config(&config).matches(&root.join(name)));
+    }
+
+    #[test]
+    fn filter_include_then_exclude() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config =
+            parse_config(&args(&["--ext", "php", "--ext-exclude", "blade.php", root])).unwrap();
+        let filter = Filter::from_config(&config);
+
+        assert!(filter.matches(Path::new("app/User.php")));
+        assert!(!filter.matches(Path::new("views/home.blade.php")));
+        assert!(!filter.matches(Path::new("app.js")));
+    }
+
+    #[test]
+    fn filter_names_match_whole_filename() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            "--ext",
+            "php",
+            "--name",
+            "Dockerfile, Makefile",
+            "--ext-exclude",
+            "Make*",
+            root,
+        ]))
+        .unwrap();
+        assert_eq!(config.names, vec!["Dockerfile", "Makefile"]);
+        let filter = Filter::from_config(&config);
+
+        assert_eq!(
+            filter.classify(Path::new("docker/Dockerfile")),
+            Some((DEFAULT_TAG, Some("Dockerfile")))
+        );
+        // Extensions still match alongside names.
+        assert!(filter.matches(Path::new("app/User.php")));
+        assert!(!filter.matches(Path::new("Dockerfile.dev")));
+        assert!(!filter.matches(Path::new("prod.Dockerfile")));
+        // Excludes still win.
+        assert!(!filter.matches(Path::new("Makefile")));
+    }
+
+    #[test]
+    fn all_skips_binary_files_unless_listed() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let filter = Filter::from_config(&parse_config(&args(&["--all", root])).unwrap());
+        assert!(filter.matches(Path::new("notes.txt")));
+        assert!(!filter.matches(Path::new("public/logo.PNG")));
+        assert!(!filter.matches(Path::new("backups/dump.sql")));
+
+        let filter =
+            Filter::from_config(&parse_config(&args(&["--all", "--ext", "sql", root])).unwrap());
+        assert!(filter.matches(Path::new("database/schema.sql")));
+        assert!(!filter.matches(Path::new("release.zip")));
+
+        let filter = Filter::from_config(
+            &parse_config(&args(&["--all", "--no-binary-filter", root])).unwrap(),
+        );
+        assert!(filter.matches(Path::new("release.zip")));
+    }
+
+    #[test]
+    fn parse_all_matches_any_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        for flags in [&["--all"][..], &["--ext", "*"][..]] {
+            let mut a = args(flags);
+            a.push(root.to_string());
+            let filter = Filter::from_config(&parse_config(&a).unwrap());
+            assert!(filter.matches(Path::new("notes.txt")));
+            assert!(filter.matches(Path::new("Makefile")));
+        }
+    }
+
+    #[test]
+    fn parse_help_and_version_skip_validation() {
+        assert!(matches!(
+            parse_args_from(&args(&["--ext", "php", "--help"])),
+            Ok(Command::Help)
+        ));
+        assert!(matches!(
+            parse_args_from(&args(&["-V"])),
+            Ok(Command::Version)
+        ));
+    }
+
+    #[test]
+    fn parse_repeated_ext_accumulates() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let repeated = parse_config(&args(&["--ext", "php", "--ext", "js,php", root])).unwrap();
+        assert_eq!(repeated.extensions, vec![".php", ".js"]);
+        let joined = parse_config(&args(&["--ext", "php,js", root])).unwrap();
+        assert_eq!(joined.extensions, repeated.extensions);
+    }
+
+    #[test]
+    fn describe_lists_resolved_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&[
+            "--show-config",
+            "--ext",
+            "php,blade.php",
+            "--debounce",
+            "200ms,css=50ms",
+            "--batch-command",
+            "deploy --token=secret",
+            root,
+        ]))
+        .unwrap();
+        assert!(config.show_config);
+
+        let described = config.describe();
+        let canonical = fs::canonicalize(dir.path()).unwrap();
+        assert!(described.contains(&format!("path: {}\n", display_path(&canonical))));
+        assert!(described.contains("extensions: .php,.blade.php\n"));
+        assert!(described.contains("debounce: 200ms, .css=50ms\n"));
+        assert!(described.contains("batch-command: (set)\n"));
+        assert!(!described.contains("secret"));
+    }
+
+    #[test]
+    fn parse_color() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&["--color", "never", root])).unwrap();
+        assert_eq!(config.color, ColorChoice::Never);
+        assert_eq!(
+            parse_config(&args(&[root])).unwrap().color,
+            ColorChoice::Auto
+        );
+        assert!(parse_args_from(&args(&["--color", "sometimes", root])).is_err());
+    }
+
+    #[test]
+    fn filter_exclude_only_matches_everything_else() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_str().unwrap();
+        let config = parse_config(&args(&["--ext-exclude", "map,lock", root])).unwrap();
+        let filter = Filter::from_config(&config);
+
+        assert!(filter.matches(Path::new("app.js")));
+        assert!(filter.matches(Path::new("app/User.php")));
+        assert!(!filter.matches(Path::new("app.js.map")));
+        assert!(!filter.matches(Path::new("composer.lock")));
+    }
+
     #[test]
     fn is_ignored_dotfile() {
         assert!(is_ignored(Path::new(".env")));
@@ -473,6 +10776,123 @@ mod tests {
         assert!(is_ignored_path(Path::new("node_modules/express/index.js")));
     }
 
+    #[test]
+    fn is_ignored_below_watch_also_exception() {
+        let also = [PathBuf::from("/srv/app/vendor/acme/pkg")];
+        assert!(!is_ignored_below(
+            Path::new("/srv/app/vendor/acme/pkg/src/A.php"),
+            &also
+        ));
+        assert!(is_ignored_below(
+            Path::new("/srv/app/vendor/acme/other/B.php"),
+            &also
+        ));
+        assert!(is_ignored_below(
+            Path::new("/srv/app/vendor/acme/pkg/node_modules/x.js"),
+            &also
+        ));
+    }
+
+    #[test]
+    fn hidden_files_are_watched_but_not_hidden_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(root.join("app/.idea")).unwrap();
+        for file in ["app/.env.local", "app/.idea/workspace.xml", "app/a.php"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let found = |extra: &[&str]| {
+            let mut argv = args(&[root.to_str().unwrap(), "--all", "--relative"]);
+            argv.extend(args(extra));
+            let config = parse_config(&argv).unwrap();
+            let mut files: Vec<String> = scan(&config)
+                .files()
+                .map(|p| display_path(p.strip_prefix(&root).unwrap()))
+                .collect();
+            files.sort();
+            files
+        };
+        assert_eq!(found(&[]), ["app/a.php"]);
+        assert_eq!(found(&["--hidden-files"]), ["app/.env.local", "app/a.php"]);
+
+        let filter = |extra: &[&str]| {
+            let mut argv = args(&[root.to_str().unwrap(), "--all"]);
+            argv.extend(args(extra));
+            Filter::from_config(&parse_config(&argv).unwrap())
+        };
+        let hidden = filter(&["--hidden-files"]);
+        // Only names below the root count: the tempdir itself is `.tmp*`.
+        assert!(!filter(&[]).always_ignored(&root.join("app/a.php")));
+        assert!(filter(&[]).always_ignored(&root.join("app/.env.local")));
+        assert!(!hidden.always_ignored(&root.join("app/.env.local")));
+        assert!(hidden.always_ignored(&root.join("app/.idea")));
+        assert!(hidden.always_ignored(&root.join("app/.idea/workspace.xml")));
+        assert!(hidden.always_ignored(&root.join("vendor/.env")));
+    }
+
+    #[test]
+    fn scan_remembers_unreadable_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let gone = dir.path().join("gone");
+        let config = parse_config(&args(&[
+            dir.path().to_str().unwrap(),
+            "--poll",
+            "--warn-unreadable",
+        ]))
+        .unwrap();
+        let opts = ScanOptions::from_config(&config);
+
+        let mut first = ScanState::default();
+        scan_dir(&gone, &opts, &ScanState::default(), &mut first);
+        assert!(first.unreadable.contains(&gone));
+        // Still failing: carried into the next scan, which doesn't warn again.
+        let mut second = ScanState::default();
+        scan_dir(&gone, &opts, &first, &mut second);
+        assert!(second.unreadable.contains(&gone));
+
+        fs::create_dir(&gone).unwrap();
+        let mut third = ScanState::default();
+        scan_dir(&gone, &opts, &second, &mut third);
+        assert!(third.unreadable.is_empty());
+    }
+
+    #[test]
+    fn scan_descends_into_watch_also_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        for file in [
+            "app/a.php",
+            "vendor/acme/pkg/src/b.php",
+            "vendor/acme/pkg/.git/c.php",
+            "vendor/acme/other/d.php",
+            "vendor/e.php",
+        ] {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        let config = parse_config(&args(&[
+            dir.path().to_str().unwrap(),
+            "--watch-also",
+            "vendor/acme/pkg",
+        ]))
+        .unwrap();
+
+        let root = std::path::absolute(dir.path()).unwrap();
+        let mut files: Vec<PathBuf> = scan(&config)
+            .files()
+            .map(|f| f.strip_prefix(&root).unwrap().to_path_buf())
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("app/a.php"),
+                PathBuf::from("vendor/acme/pkg/src/b.php")
+            ]
+        );
+    }
+
     #[test]
     fn is_ignored_path_normal() {
         assert!(!is_ignored_path(Path::new("app/Models/User.php")));
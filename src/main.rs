@@ -1,23 +1,79 @@
-use std::io::{self, BufWriter};
+use std::io::{self, IsTerminal};
+use std::path::PathBuf;
 use std::process;
 
+use filewatcher::{ColorChoice, Command};
+
+/// What to do once the configuration is resolved.
+enum Task {
+    Watch,
+    Snapshot,
+    Diff(PathBuf),
+    Replay(PathBuf),
+}
+
 fn main() {
-    let config = match filewatcher::parse_args() {
-        Ok(c) => c,
+    let (mut config, task) = match filewatcher::parse_args() {
+        Ok(Command::Run(c)) => {
+            let task = match &c.replay {
+                Some(file) => Task::Replay(file.clone()),
+                None => Task::Watch,
+            };
+            (*c, task)
+        }
+        Ok(Command::Snapshot(c)) => (*c, Task::Snapshot),
+        Ok(Command::Diff(c, file)) => (*c, Task::Diff(file)),
+        Ok(Command::Help) => {
+            print!("{}", filewatcher::USAGE);
+            return;
+        }
+        Ok(Command::Version) => {
+            println!("filewatcher {}", filewatcher::VERSION);
+            return;
+        }
         Err(e) => {
             eprintln!("error: {}", e);
             process::exit(1);
         }
     };
 
-    filewatcher::install_signal_handlers();
+    if config.color == ColorChoice::Auto {
+        let tty = config.output.is_none() && io::stdout().is_terminal();
+        config.color = if tty {
+            ColorChoice::Always
+        } else {
+            ColorChoice::Never
+        };
+    }
 
-    let writer: Box<dyn io::Write + Send> = Box::new(BufWriter::new(io::stdout()));
+    if config.show_config {
+        eprint!("{}", config.describe());
+    }
+
+    let writer = match filewatcher::open_output(&config) {
+        Ok(w) => w,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    filewatcher::install_signal_handlers();
 
-    let result = if config.poll {
-        filewatcher::run_poller(&config, writer)
-    } else {
-        filewatcher::run_watcher(&config, writer)
+    let result = match task {
+        // Like diff(1): 0 unchanged, 1 changed, 2 on error.
+        Task::Diff(file) => match filewatcher::run_diff(&config, &file, writer) {
+            Ok(changed) => process::exit(i32::from(changed)),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                process::exit(2);
+            }
+        },
+        Task::Snapshot => filewatcher::run_snapshot(&config, writer),
+        Task::Replay(file) => filewatcher::run_replay(&config, &file, writer),
+        Task::Watch if config.list => filewatcher::run_list(&config, writer),
+        Task::Watch if config.poll => filewatcher::run_poller(&config, writer),
+        Task::Watch => filewatcher::run_watcher(&config, writer),
     };
 
     if let Err(e) = result {
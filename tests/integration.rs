@@ -38,6 +38,16 @@ fn send_sigterm(child: &std::process::Child) {
     }
 }
 
+#[cfg(windows)]
+fn send_ctrl_break(child: &std::process::Child) {
+    unsafe extern "system" {
+        fn GenerateConsoleCtrlEvent(event: u32, process_group_id: u32) -> i32;
+    }
+    unsafe {
+        GenerateConsoleCtrlEvent(1, child.id()); // CTRL_BREAK_EVENT
+    }
+}
+
 #[test]
 fn file_creation_detected() {
     let dir = tempfile::tempdir().unwrap();
@@ -54,46 +64,873 @@ fn file_creation_detected() {
 
     std::fs::write(dir.path().join("test.php"), "<?php echo 1;").unwrap();
 
-    let line = rx
-        .recv_timeout(Duration::from_secs(5))
-        .expect("timed out waiting for changed event");
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for changed event");
+
+    assert!(line.starts_with("changed: "), "unexpected: {}", line);
+    assert!(line.contains("test.php"), "missing filename: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn ready_marker_signals_when_changes_are_caught() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--debounce",
+            "100",
+            "--ready-marker",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    let ready = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for ready marker");
+    assert_eq!(ready, "ready");
+
+    // No sleep: once ready is printed, the watch is in place.
+    std::fs::write(dir.path().join("test.php"), "<?php echo 1;").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for changed event");
+    assert!(line.contains("test.php"), "missing filename: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn extension_filtering() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--ext",
+            "php",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("readme.txt"), "hello").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("index.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for php change");
+
+    assert!(line.contains("index.php"), "expected index.php: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn all_flag_reports_any_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--all", "--debounce", "100", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("notes.txt"), "hello").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for notes.txt");
+
+    assert!(line.contains("notes.txt"), "expected notes.txt: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn initial_delay_swallows_startup_changes() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--initial-delay",
+            "2s",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("early.php"), "<?php").unwrap();
+    thread::sleep(Duration::from_millis(2500));
+
+    std::fs::write(dir.path().join("late.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for late.php");
+
+    assert!(
+        line.contains("late.php"),
+        "expected late.php first: {}",
+        line
+    );
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+#[test]
+fn batch_command_receives_nul_separated_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let watched = dir.path().join("app");
+    std::fs::create_dir(&watched).unwrap();
+    let out = dir.path().join("batch.txt");
+
+    let mut child = Command::new(binary())
+        .args([
+            "--debounce",
+            "200",
+            "--relative",
+            "--batch-command",
+            &format!("cat >> '{}'", out.display()),
+            watched.to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(watched.join("a.php"), "<?php").unwrap();
+    std::fs::write(watched.join("b.php"), "<?php").unwrap();
+
+    for _ in 0..2 {
+        rx.recv_timeout(Duration::from_secs(5))
+            .expect("timed out waiting for changes");
+    }
+    send_sigterm(&child);
+    let status = child.wait().unwrap();
+    assert!(status.success());
+
+    let mut paths: Vec<String> = std::fs::read_to_string(&out)
+        .unwrap()
+        .split_terminator('\0')
+        .map(String::from)
+        .collect();
+    paths.sort();
+    assert_eq!(paths, ["a.php", "b.php"]);
+}
+
+#[test]
+#[cfg(feature = "native")]
+fn verbose_events_print_event_kind() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--verbose-events",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("test.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for event");
+
+    assert!(
+        line.starts_with("Create(") || line.starts_with("Modify("),
+        "expected an event kind: {}",
+        line
+    );
+    assert!(line.contains("test.php"), "missing filename: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+#[cfg(feature = "native")]
+fn debug_traces_events_to_stderr_only() {
+    use std::io::Read;
+
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--debug", "--debounce", "100", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("notes.txt"), "skip").unwrap();
+    std::fs::write(dir.path().join("test.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for event");
+    assert!(line.ends_with("test.php"), "unexpected stdout: {}", line);
+    assert!(
+        rx.recv_timeout(Duration::from_millis(300)).is_err(),
+        "debug output leaked to stdout"
+    );
+
+    child.kill().ok();
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    let _ = child.wait();
+
+    assert!(stderr.contains("debug: "), "no trace: {}", stderr);
+    assert!(
+        stderr.contains("notes.txt dropped: no matching extension"),
+        "no drop reason: {}",
+        stderr
+    );
+}
+
+#[test]
+fn log_level_gates_stderr_diagnostics() {
+    use std::io::Read;
+
+    let dir = tempfile::tempdir().unwrap();
+    let state = dir.path().join("state.json");
+    std::fs::write(&state, "not json").unwrap();
+
+    let stderr_at = |level: &str| {
+        let mut child = Command::new(binary())
+            .args(["--poll", "--poll-interval", "100ms", "--log-level", level])
+            .arg("--state-file")
+            .arg(&state)
+            .arg(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+        thread::sleep(Duration::from_millis(500));
+        child.kill().ok();
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        let _ = child.wait();
+        stderr
+    };
+
+    let info = stderr_at("info");
+    assert!(info.contains("warning: ignoring state file"), "{}", info);
+    assert!(info.contains("info: polling 1 paths"), "{}", info);
+
+    // The state file was rewritten by the first run, so break it again.
+    std::fs::write(&state, "not json").unwrap();
+    let error = stderr_at("error");
+    assert!(error.is_empty(), "{}", error);
+}
+
+#[cfg(unix)]
+#[test]
+fn exec_on_start_reports_its_exit_status() {
+    use std::io::Read;
+
+    let dir = tempfile::tempdir().unwrap();
+    let stderr_with = |command: &str, level: &str| {
+        let mut child = Command::new(binary())
+            .args(["--poll", "--poll-interval", "100ms", "--log-level", level])
+            .args(["--exec-on-start", "--batch-command", command])
+            .arg(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+        thread::sleep(Duration::from_millis(500));
+        child.kill().ok();
+        let mut stderr = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut stderr)
+            .unwrap();
+        let _ = child.wait();
+        stderr
+    };
+
+    let info = stderr_with("true", "info");
+    assert!(
+        info.contains("info: start command exited with exit status: 0"),
+        "{}",
+        info
+    );
+    let warn = stderr_with("true", "warn");
+    assert!(!warn.contains("start command"), "{}", warn);
+    let failed = stderr_with("exit 3", "warn");
+    assert!(
+        failed.contains("batch command exited with exit status: 3"),
+        "{}",
+        failed
+    );
+}
+
+#[test]
+fn wait_for_paths_watches_once_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let build = dir.path().join("build");
+
+    let mut child = Command::new(binary())
+        .args([
+            "--wait-for-paths",
+            "--poll-interval",
+            "100",
+            "--debounce",
+            "100",
+            build.to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(300));
+    std::fs::create_dir(&build).unwrap();
+    thread::sleep(Duration::from_millis(700));
+
+    std::fs::write(build.join("app.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for change");
+    assert!(line.contains("app.php"), "missing filename: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+#[cfg(feature = "native")]
+fn control_watches_new_path() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let first = dir.path().join("first");
+    let second = dir.path().join("second");
+    std::fs::create_dir(&first).unwrap();
+    std::fs::create_dir(&second).unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--control", "--debounce", "100", first.to_str().unwrap()])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    let mut stdin = child.stdin.take().unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    writeln!(stdin, r#"{{"cmd":"bogus","path":"x"}}"#).unwrap();
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for error");
+    assert!(
+        line.starts_with(r#"{"event":"error""#),
+        "unexpected: {}",
+        line
+    );
+
+    let cmd = format!(
+        r#"{{"cmd":"watch","path":"{}"}}"#,
+        second.display().to_string().replace('\\', "\\\\")
+    );
+    writeln!(stdin, "{}", cmd).unwrap();
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for ack");
+    assert!(
+        line.starts_with(r#"{"event":"watching""#),
+        "unexpected: {}",
+        line
+    );
+    thread::sleep(Duration::from_millis(300));
+
+    std::fs::write(second.join("new.php"), "<?php").unwrap();
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for change");
+    assert!(line.contains("new.php"), "missing filename: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn max_events_exits_after_limit() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--max-events",
+            "2",
+            "--debounce",
+            "200",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    for name in ["a.php", "b.php", "c.php"] {
+        std::fs::write(dir.path().join(name), "<?php").unwrap();
+    }
+
+    let mut lines = Vec::new();
+    while let Ok(line) = rx.recv_timeout(Duration::from_secs(5)) {
+        lines.push(line);
+    }
+    assert_eq!(lines.len(), 2, "unexpected output: {:?}", lines);
+
+    let status = child.wait().unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn process_existing_reports_files_at_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("existing.php"), "<?php").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "hi").unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--process-existing",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for existing file");
+    assert!(line.contains("existing.php"), "unexpected: {}", line);
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn no_recursive_ignores_subdirectories() {
+    for backend in [&[][..], &["--poll", "--poll-interval", "100ms"][..]] {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("Models")).unwrap();
+
+        let mut child = Command::new(binary())
+            .args(["--no-recursive", "--debounce", "100"])
+            .args(backend)
+            .arg(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+
+        let rx = spawn_line_reader(&mut child);
+        thread::sleep(Duration::from_millis(500));
+
+        std::fs::write(dir.path().join("Models/User.php"), "<?php").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        std::fs::write(dir.path().join("routes.php"), "<?php").unwrap();
+
+        let line = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("timed out waiting for top-level change");
+        assert!(line.contains("routes.php"), "{:?}: {}", backend, line);
+        assert!(
+            rx.recv_timeout(Duration::from_millis(500)).is_err(),
+            "{:?}: nested change reported",
+            backend
+        );
+
+        child.kill().ok();
+        let _ = child.wait();
+    }
+}
+
+#[test]
+fn recursive_reports_nested_changes() {
+    for backend in [&[][..], &["--poll", "--poll-interval", "100ms"][..]] {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("Models")).unwrap();
+
+        // The later flag wins.
+        let mut child = Command::new(binary())
+            .args(["--no-recursive", "--recursive", "--debounce", "100"])
+            .args(backend)
+            .arg(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+
+        let rx = spawn_line_reader(&mut child);
+        thread::sleep(Duration::from_millis(500));
+
+        std::fs::write(dir.path().join("Models/User.php"), "<?php").unwrap();
+
+        let line = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("timed out waiting for nested change");
+        assert!(line.contains("User.php"), "{:?}: {}", backend, line);
+
+        child.kill().ok();
+        let _ = child.wait();
+    }
+}
+
+#[test]
+fn no_initial_scan_reports_existing_files_on_first_poll() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("existing.php"), "<?php").unwrap();
+    std::fs::write(dir.path().join("notes.txt"), "hi").unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--poll",
+            "--poll-interval",
+            "100ms",
+            "--no-initial-scan",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for existing file");
+    assert!(line.contains("existing.php"), "unexpected: {}", line);
+    assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+#[cfg(unix)]
+fn poll_reports_repointed_symlink() {
+    use std::os::unix::fs::symlink;
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("releases/1")).unwrap();
+    std::fs::create_dir_all(dir.path().join("releases/2")).unwrap();
+    symlink("releases/1", dir.path().join("current")).unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--poll",
+            "--poll-interval",
+            "100ms",
+            "--debounce",
+            "100",
+            "--relative",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    // Swap the link atomically, as deploy tools do.
+    symlink("releases/2", dir.path().join("current.tmp")).unwrap();
+    std::fs::rename(dir.path().join("current.tmp"), dir.path().join("current")).unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for symlink change");
+    assert_eq!(line, "symlink-changed: current -> releases/2");
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn exits_when_reader_goes_away() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--debounce", "50", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    drop(child.stdout.take());
+    thread::sleep(Duration::from_millis(500));
+    std::fs::write(dir.path().join("a.php"), "<?php").unwrap();
+
+    for _ in 0..50 {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "exited with {}", status);
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    child.kill().ok();
+    let _ = child.wait();
+    panic!("still running after its reader went away");
+}
+
+#[test]
+fn snapshot_then_diff_reports_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("app");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("User.php"), "<?php").unwrap();
+    std::fs::write(root.join("Post.php"), "<?php").unwrap();
+    let state = dir.path().join("state.json");
+
+    let out = Command::new(binary())
+        .args(["snapshot", "--poll-compare", "hash"])
+        .arg(&root)
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    std::fs::write(&state, &out.stdout).unwrap();
+
+    let diff = |root: &std::path::Path| {
+        Command::new(binary())
+            .args(["diff", "--poll-compare", "hash"])
+            .arg(root)
+            .arg(&state)
+            .output()
+            .unwrap()
+    };
+    let unchanged = diff(&root);
+    assert_eq!(unchanged.status.code(), Some(0));
+    assert!(unchanged.stdout.is_empty());
 
-    assert!(line.starts_with("changed: "), "unexpected: {}", line);
-    assert!(line.contains("test.php"), "missing filename: {}", line);
+    std::fs::write(root.join("User.php"), "<?php echo 1;").unwrap();
+    std::fs::remove_file(root.join("Post.php")).unwrap();
+    let changed = diff(&root);
+    assert_eq!(changed.status.code(), Some(1));
+    let stdout = String::from_utf8(changed.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 2, "unexpected: {}", stdout);
+    assert!(lines[0].starts_with("removed: ") && lines[0].ends_with("Post.php"));
+    assert!(lines[1].starts_with("modified: ") && lines[1].ends_with("User.php"));
 
-    child.kill().ok();
-    let _ = child.wait();
+    std::fs::write(&state, "not json").unwrap();
+    assert_eq!(diff(&root).status.code(), Some(2));
 }
 
 #[test]
-fn extension_filtering() {
+fn record_then_replay_reemits_changes() {
     let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("app");
+    std::fs::create_dir(&root).unwrap();
+    let record = dir.path().join("events.jsonl");
 
     let mut child = Command::new(binary())
-        .args(["--ext", "php", "--debounce", "100", dir.path().to_str().unwrap()])
+        .args(["--poll", "--poll-interval", "100ms", "--debounce", "100"])
+        .arg("--record")
+        .arg(&record)
+        .arg(&root)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .expect("failed to start filewatcher");
 
     let rx = spawn_line_reader(&mut child);
-    thread::sleep(Duration::from_millis(500));
+    thread::sleep(Duration::from_millis(300));
+    std::fs::write(root.join("User.php"), "<?php").unwrap();
+    let recorded = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for event");
+    child.kill().ok();
+    let _ = child.wait();
 
-    std::fs::write(dir.path().join("readme.txt"), "hello").unwrap();
-    thread::sleep(Duration::from_millis(500));
+    let contents = std::fs::read_to_string(&record).unwrap();
+    assert_eq!(contents.lines().count(), 1, "unexpected: {}", contents);
+    assert!(contents.contains("\"kind\":\"changed\""), "{}", contents);
 
-    std::fs::write(dir.path().join("index.php"), "<?php").unwrap();
+    // No paths: replay touches nothing and exits once the file is done.
+    let replayed = Command::new(binary())
+        .arg("--replay")
+        .arg(&record)
+        .output()
+        .unwrap();
+    assert!(replayed.status.success());
+    assert_eq!(String::from_utf8(replayed.stdout).unwrap(), recorded + "\n");
 
-    let line = rx
-        .recv_timeout(Duration::from_secs(5))
-        .expect("timed out waiting for php change");
+    std::fs::write(&record, "not json\n").unwrap();
+    let bad = Command::new(binary())
+        .arg("--replay")
+        .arg(&record)
+        .output()
+        .unwrap();
+    assert_eq!(bad.status.code(), Some(1));
+}
 
-    assert!(line.contains("index.php"), "expected index.php: {}", line);
+#[test]
+fn state_file_reports_changes_made_while_stopped() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().join("app");
+    std::fs::create_dir(&root).unwrap();
+    std::fs::write(root.join("User.php"), "<?php").unwrap();
+    std::fs::write(root.join("Post.php"), "<?php").unwrap();
+    let state = dir.path().join("state.json");
+
+    let start = || {
+        let mut child = Command::new(binary())
+            .args([
+                "--poll",
+                "--poll-interval",
+                "100ms",
+                "--poll-compare",
+                "hash",
+            ])
+            .args(["--debounce", "100", "--relative", "--state-file"])
+            .arg(&state)
+            .arg(&root)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+        let rx = spawn_line_reader(&mut child);
+        (child, rx)
+    };
+
+    // First run: nothing saved yet, so existing files are not reported.
+    let (mut child, rx) = start();
+    thread::sleep(Duration::from_millis(500));
+    assert!(rx.try_recv().is_err());
+    assert!(state.exists());
+    child.kill().ok();
+    let _ = child.wait();
 
+    std::fs::write(root.join("User.php"), "<?php echo 1;").unwrap();
+    std::fs::remove_file(root.join("Post.php")).unwrap();
+
+    let (mut child, rx) = start();
+    let mut lines: Vec<String> = (0..2)
+        .map(|_| {
+            rx.recv_timeout(Duration::from_secs(5))
+                .expect("timed out waiting for offline change")
+        })
+        .collect();
+    lines.sort();
+    assert_eq!(lines, vec!["changed: Post.php", "changed: User.php"]);
     child.kill().ok();
     let _ = child.wait();
 }
 
+#[test]
+fn no_removals_skips_deleted_files() {
+    for backend in [&[][..], &["--poll", "--poll-interval", "100ms"][..]] {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("User.php");
+        std::fs::write(&file, "<?php").unwrap();
+
+        let mut child = Command::new(binary())
+            .args(["--no-removals", "--debounce", "100"])
+            .args(backend)
+            .arg(dir.path())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start filewatcher");
+
+        let rx = spawn_line_reader(&mut child);
+        thread::sleep(Duration::from_millis(500));
+
+        std::fs::remove_file(&file).unwrap();
+        assert!(
+            rx.recv_timeout(Duration::from_secs(1)).is_err(),
+            "{:?}: removal reported",
+            backend
+        );
+
+        // Still running and reporting other changes.
+        std::fs::write(dir.path().join("Post.php"), "<?php").unwrap();
+        let line = rx
+            .recv_timeout(Duration::from_secs(5))
+            .expect("timed out waiting for Post.php");
+        assert!(line.contains("Post.php"), "{:?}: {}", backend, line);
+
+        child.kill().ok();
+        let _ = child.wait();
+    }
+}
+
 #[test]
 fn ignored_directories() {
     let dir = tempfile::tempdir().unwrap();
@@ -164,6 +1001,47 @@ fn debouncing() {
     let _ = child.wait();
 }
 
+#[test]
+fn min_size_defers_empty_files() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--min-size",
+            "1",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    let file = dir.path().join("growing.php");
+    std::fs::write(&file, "").unwrap();
+    assert!(
+        rx.recv_timeout(Duration::from_millis(700)).is_err(),
+        "empty file reported"
+    );
+
+    std::fs::write(&file, "<?php").unwrap();
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for growing.php");
+    assert!(
+        line.contains("growing.php"),
+        "expected growing.php: {}",
+        line
+    );
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
 #[test]
 fn polling_mode() {
     let dir = tempfile::tempdir().unwrap();
@@ -222,6 +1100,37 @@ fn clean_shutdown() {
     );
 }
 
+#[cfg(windows)]
+#[test]
+fn clean_shutdown_windows() {
+    use std::os::windows::process::CommandExt;
+
+    // Ctrl+C cannot be delivered to a separate process group, Ctrl+Break can.
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .creation_flags(CREATE_NEW_PROCESS_GROUP)
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    thread::sleep(Duration::from_millis(300));
+
+    send_ctrl_break(&child);
+
+    let status = child.wait().expect("failed to wait for child");
+
+    assert!(
+        status.success(),
+        "expected exit code 0, got {:?}",
+        status.code()
+    );
+}
+
 #[test]
 fn no_paths_exits_with_error() {
     let output = Command::new(binary())
@@ -240,6 +1149,257 @@ fn no_paths_exits_with_error() {
     );
 }
 
+#[test]
+fn help_and_version_exit_zero() {
+    let output = Command::new(binary())
+        .arg("--help")
+        .output()
+        .expect("failed to run filewatcher");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("--poll-interval"), "usage: {}", stdout);
+
+    let output = Command::new(binary())
+        .arg("-V")
+        .output()
+        .expect("failed to run filewatcher");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        format!("filewatcher {}", env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn sigusr1_triggers_rescan() {
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: i32 = 10;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: i32 = 30;
+
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--poll",
+            "--poll-interval",
+            "30s",
+            "--debounce",
+            "100",
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(dir.path().join("test.php"), "<?php").unwrap();
+    unsafe {
+        kill(child.id() as i32, SIGUSR1);
+    }
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for rescan");
+    assert!(line.contains("test.php"), "missing filename: {}", line);
+
+    send_sigterm(&child);
+    let _ = child.wait();
+}
+
+#[cfg(unix)]
+#[test]
+#[cfg(feature = "native")]
+fn stats_on_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let child = Command::new(binary())
+        .args(["--stats", "--debounce", "100", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    thread::sleep(Duration::from_millis(500));
+    std::fs::write(dir.path().join("counted.php"), "<?php").unwrap();
+    std::fs::write(dir.path().join("skipped.txt"), "x").unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    send_sigterm(&child);
+    let output = child.wait_with_output().expect("failed to wait for child");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let line = stderr
+        .lines()
+        .find(|l| l.starts_with("stats: "))
+        .unwrap_or_else(|| panic!("missing stats line: {}", stderr));
+    assert!(line.contains("flushed=1"), "unexpected stats: {}", line);
+}
+
+#[test]
+fn paths_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let watched = dir.path().join("watched");
+    std::fs::create_dir_all(&watched).unwrap();
+
+    let list = dir.path().join("paths.txt");
+    std::fs::write(&list, format!("# watch list\n\n{}\n", watched.display())).unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--debounce", "100", "--paths-from", list.to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let rx = spawn_line_reader(&mut child);
+    thread::sleep(Duration::from_millis(500));
+
+    std::fs::write(watched.join("listed.php"), "<?php").unwrap();
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for listed.php");
+
+    assert!(line.contains("listed.php"), "expected listed.php: {}", line);
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn list_mode_prints_matches_and_exits() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(dir.path().join("app")).unwrap();
+    std::fs::create_dir_all(dir.path().join("vendor")).unwrap();
+    std::fs::write(dir.path().join("app/User.php"), "<?php").unwrap();
+    std::fs::write(dir.path().join("app/notes.txt"), "x").unwrap();
+    std::fs::write(dir.path().join("vendor/autoload.php"), "<?php").unwrap();
+
+    let output = Command::new(binary())
+        .args(["--list", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("failed to run filewatcher");
+
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(lines.len(), 1, "unexpected listing: {}", stdout);
+    assert!(
+        lines[0].ends_with("User.php"),
+        "unexpected listing: {}",
+        stdout
+    );
+}
+
+#[test]
+fn output_to_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let watched = dir.path().join("watched");
+    std::fs::create_dir_all(&watched).unwrap();
+    let out = dir.path().join("changes.log");
+    std::fs::write(&out, "previous run\n").unwrap();
+
+    let mut child = Command::new(binary())
+        .args([
+            "--debounce",
+            "100",
+            "--output",
+            out.to_str().unwrap(),
+            "--output-append",
+            watched.to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    thread::sleep(Duration::from_millis(500));
+    std::fs::write(watched.join("logged.php"), "<?php").unwrap();
+
+    let mut contents = String::new();
+    for _ in 0..50 {
+        thread::sleep(Duration::from_millis(100));
+        contents = std::fs::read_to_string(&out).unwrap();
+        if contents.contains("logged.php") {
+            break;
+        }
+    }
+
+    assert!(
+        contents.starts_with("previous run\n"),
+        "truncated: {}",
+        contents
+    );
+    assert!(
+        contents.contains("logged.php"),
+        "missing change: {}",
+        contents
+    );
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
+#[test]
+fn unwritable_output_exits_with_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("missing/changes.log");
+
+    let output = Command::new(binary())
+        .args([
+            "--output",
+            out.to_str().unwrap(),
+            dir.path().to_str().unwrap(),
+        ])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .expect("failed to run filewatcher");
+
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn heartbeat_when_idle() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut child = Command::new(binary())
+        .args(["--heartbeat", "200ms", dir.path().to_str().unwrap()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start filewatcher");
+
+    let stderr = child.stderr.take().expect("child has no stderr");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let line = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("timed out waiting for heartbeat");
+    assert_eq!(line, "heartbeat");
+
+    child.kill().ok();
+    let _ = child.wait();
+}
+
 #[test]
 fn invalid_path_exits_with_error() {
     let output = Command::new(binary())